@@ -1,51 +1,275 @@
+use std::{fmt, time::Duration};
+
 use cpal::{
     traits::{DeviceTrait, HostTrait},
-    Device, Sample, SampleFormat, Stream, StreamConfig, SupportedOutputConfigs,
-    SupportedStreamConfigRange,
+    BufferSize, BuildStreamError, Device, DevicesError, Host, PauseStreamError, PlayStreamError,
+    Sample, SampleFormat, SampleRate, Stream, StreamConfig, StreamError, StreamInstant,
+    SupportedBufferSize, SupportedStreamConfigRange, SupportedStreamConfigsError,
 };
 
+use crossbeam::channel::Sender;
+
+use crate::chain::ProcessContext;
+
+#[derive(Debug, Clone, Default)]
+pub struct AudioOptions {
+    pub host: Option<String>,
+    pub device: Option<String>,
+    pub sample_rate: Option<u32>,
+    pub buffer_size: Option<u32>,
+}
+
+#[derive(Debug)]
+pub enum AudioError {
+    HostNotFound(String),
+    NoDefaultDevice,
+    DeviceNotFound(String),
+    UnsupportedConfig {
+        sample_rate: Option<u32>,
+        buffer_size: Option<u32>,
+    },
+    Devices(DevicesError),
+    SupportedConfigs(SupportedStreamConfigsError),
+    BuildStream(BuildStreamError),
+    PlayStream(PlayStreamError),
+    PauseStream(PauseStreamError),
+}
+
+impl fmt::Display for AudioError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AudioError::HostNotFound(name) => write!(f, "no audio host named '{}'", name),
+            AudioError::NoDefaultDevice => write!(f, "no default output device"),
+            AudioError::DeviceNotFound(name) => write!(f, "no output device named '{}'", name),
+            AudioError::UnsupportedConfig {
+                sample_rate,
+                buffer_size,
+            } => write!(
+                f,
+                "device does not support sample rate {:?} with buffer size {:?}",
+                sample_rate, buffer_size
+            ),
+            AudioError::Devices(err) => write!(f, "{}", err),
+            AudioError::SupportedConfigs(err) => write!(f, "{}", err),
+            AudioError::BuildStream(err) => write!(f, "{}", err),
+            AudioError::PlayStream(err) => write!(f, "{}", err),
+            AudioError::PauseStream(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl std::error::Error for AudioError {}
+
+pub enum StreamEvent {
+    Xrun,
+    Error(StreamError),
+}
+
+pub struct DeviceInfo {
+    pub host: String,
+    pub name: String,
+    pub configs: Vec<SupportedStreamConfigRange>,
+}
+
+/// Lists every host's output devices. A host that can't list its devices is
+/// left out, and returned with its error instead.
+pub fn list_output_devices() -> (Vec<DeviceInfo>, Vec<(String, AudioError)>) {
+    let mut devices = Vec::new();
+    let mut failed = Vec::new();
+    for host_id in cpal::available_hosts() {
+        let host = match cpal::host_from_id(host_id) {
+            Ok(host) => host,
+            Err(_) => continue,
+        };
+
+        let outputs = match host.output_devices() {
+            Ok(outputs) => outputs,
+            Err(err) => {
+                failed.push((host_id.name().to_string(), AudioError::Devices(err)));
+                continue;
+            }
+        };
+        for device in outputs {
+            devices.push(DeviceInfo {
+                host: host_id.name().to_string(),
+                name: device.name().unwrap_or_default(),
+                configs: device
+                    .supported_output_configs()
+                    .map(|configs| configs.collect())
+                    .unwrap_or_default(),
+            });
+        }
+    }
+
+    (devices, failed)
+}
+
 pub struct Audio {
     device: Device,
     config: StreamConfig,
     format: SampleFormat,
+    options: AudioOptions,
 }
 
 impl Audio {
-    pub fn new() -> Self {
-        let host = cpal::default_host();
-        let device = host.default_output_device().unwrap();
-        let config = device.supported_output_configs().unwrap().next().unwrap();
-        let format = config.sample_format();
-        let config = config.with_max_sample_rate().config();
-
-        Audio {
+    pub fn new() -> Result<Self, AudioError> {
+        Self::with_options(&AudioOptions::default())
+    }
+
+    pub fn with_options(options: &AudioOptions) -> Result<Self, AudioError> {
+        let host = find_host(options.host.as_deref())?;
+        let device = find_device(&host, options.device.as_deref())?;
+
+        let supported = device
+            .supported_output_configs()
+            .map_err(AudioError::SupportedConfigs)?
+            .find(|config| supports(config, options))
+            .ok_or(AudioError::UnsupportedConfig {
+                sample_rate: options.sample_rate,
+                buffer_size: options.buffer_size,
+            })?;
+        let format = supported.sample_format();
+        let supported = match options.sample_rate {
+            Some(rate) => supported.with_sample_rate(SampleRate(rate)),
+            None => supported.with_max_sample_rate(),
+        };
+
+        let mut config = supported.config();
+        if let Some(frames) = options.buffer_size {
+            config.buffer_size = BufferSize::Fixed(frames);
+        }
+
+        Ok(Audio {
+            options: AudioOptions {
+                device: device.name().ok(),
+                ..options.clone()
+            },
             device,
             config,
             format,
+        })
+    }
+
+    pub fn reopen(&self) -> Result<Self, AudioError> {
+        Self::with_options(&self.options)
+            .or_else(|_| {
+                Self::with_options(&AudioOptions {
+                    device: None,
+                    ..self.options.clone()
+                })
+            })
+            .or_else(|_| Self::new())
+    }
+
+    pub fn context(&self) -> ProcessContext {
+        ProcessContext {
+            sample_rate: self.config.sample_rate.0 as f32,
+            block_size: match self.config.buffer_size {
+                BufferSize::Fixed(frames) => frames as usize,
+                BufferSize::Default => ProcessContext::default().block_size,
+            },
+            channels: self.config.channels as usize,
         }
     }
-    pub fn stream_with<F>(&self, f: F) -> Stream
+
+    pub fn stream_with<F>(&self, f: F, events: Sender<StreamEvent>) -> Result<Stream, AudioError>
     where
         F: FnMut(&mut [f32]) + Send + 'static,
     {
         match self.format {
-            SampleFormat::F32 => return self.stream_audio_with::<F, f32>(f),
-            _ => panic!(""),
-        };
+            SampleFormat::F32 => self.stream_audio_with::<F, f32>(f, events),
+            SampleFormat::I16 => self.stream_audio_with(convert_with::<F, i16>(f), events),
+            SampleFormat::U16 => self.stream_audio_with(convert_with::<F, u16>(f), events),
+        }
     }
 
-    fn stream_audio_with<F, T>(&self, mut f: F) -> Stream
+    fn stream_audio_with<F, T>(
+        &self,
+        mut f: F,
+        events: Sender<StreamEvent>,
+    ) -> Result<Stream, AudioError>
     where
         F: FnMut(&mut [T]) + Send + 'static,
         T: Sample,
     {
-        let data_callback = move |data: &mut [T], _: &cpal::OutputCallbackInfo| {
+        let xrun_events = events.clone();
+        let channels = self.config.channels as usize;
+        let sample_rate = self.config.sample_rate.0 as f64;
+        let mut expected_playback: Option<StreamInstant> = None;
+
+        let data_callback = move |data: &mut [T], info: &cpal::OutputCallbackInfo| {
+            // A playback instant later than the end of the previous buffer means the
+            // device ran dry in between.
+            let playback = info.timestamp().playback;
+            let frames = Duration::from_secs_f64((data.len() / channels) as f64 / sample_rate);
+            if let Some(late) = expected_playback.and_then(|e| playback.duration_since(&e)) {
+                if late > frames / 2 {
+                    let _ = xrun_events.try_send(StreamEvent::Xrun);
+                }
+            }
+            expected_playback = playback.add(frames);
+
             f(data);
         };
-        let error_callback = move |_err| {};
+        let error_callback = move |err| {
+            let _ = events.try_send(StreamEvent::Error(err));
+        };
 
         self.device
             .build_output_stream(&self.config, data_callback, error_callback)
-            .unwrap()
+            .map_err(AudioError::BuildStream)
+    }
+}
+
+fn find_host(name: Option<&str>) -> Result<Host, AudioError> {
+    match name {
+        None => Ok(cpal::default_host()),
+        Some(name) => cpal::available_hosts()
+            .into_iter()
+            .find(|id| id.name().eq_ignore_ascii_case(name))
+            .and_then(|id| cpal::host_from_id(id).ok())
+            .ok_or_else(|| AudioError::HostNotFound(name.to_string())),
+    }
+}
+
+fn find_device(host: &Host, name: Option<&str>) -> Result<Device, AudioError> {
+    match name {
+        None => host
+            .default_output_device()
+            .ok_or(AudioError::NoDefaultDevice),
+        Some(name) => host
+            .output_devices()
+            .map_err(AudioError::Devices)?
+            .find(|device| device.name().map(|n| n == name).unwrap_or(false))
+            .ok_or_else(|| AudioError::DeviceNotFound(name.to_string())),
+    }
+}
+
+fn supports(config: &SupportedStreamConfigRange, options: &AudioOptions) -> bool {
+    let rate_ok = options.sample_rate.is_none_or(|rate| {
+        config.min_sample_rate().0 <= rate && rate <= config.max_sample_rate().0
+    });
+    let buffer_ok = options
+        .buffer_size
+        .is_none_or(|frames| match config.buffer_size() {
+            SupportedBufferSize::Range { min, max } => *min <= frames && frames <= *max,
+            SupportedBufferSize::Unknown => true,
+        });
+
+    rate_ok && buffer_ok
+}
+
+fn convert_with<F, T>(mut f: F) -> impl FnMut(&mut [T]) + Send + 'static
+where
+    F: FnMut(&mut [f32]) + Send + 'static,
+    T: Sample,
+{
+    let mut buffer = Vec::new();
+    move |data: &mut [T]| {
+        buffer.resize(data.len(), 0.0);
+        f(&mut buffer);
+        for (slot, sample) in data.iter_mut().zip(buffer.iter()) {
+            *slot = T::from(sample);
+        }
     }
 }
@@ -1,11 +1,122 @@
 use crate::config::{Config, ConfigReceiver};
 
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ProcessContext {
+    pub sample_rate: f32,
+    pub block_size: usize,
+    pub channels: usize,
+}
+
+impl Default for ProcessContext {
+    fn default() -> Self {
+        Self {
+            sample_rate: 44100.0,
+            block_size: 512,
+            channels: 1,
+        }
+    }
+}
+
 pub trait Effect<Signal>: ConfigReceiver {
     fn process(&mut self, signal: Signal) -> Signal;
+
+    fn process_block(&mut self, block: &mut [Signal])
+    where
+        Signal: Copy,
+    {
+        for slot in block.iter_mut() {
+            *slot = self.process(*slot);
+        }
+    }
+
+    fn set_context(&mut self, _context: ProcessContext) {}
+
+    fn note_on(&mut self, _velocity: f32) {}
+
+    fn note_off(&mut self) {}
+
+    /// Whether the effect is still producing a tail after `note_off`.
+    fn is_releasing(&self) -> bool {
+        false
+    }
 }
 
 pub trait Voice<Signal>: ConfigReceiver {
     fn generate(&mut self) -> Signal;
+
+    fn generate_block(&mut self, block: &mut [Signal]) {
+        for slot in block.iter_mut() {
+            *slot = self.generate();
+        }
+    }
+
+    fn set_context(&mut self, _context: ProcessContext) {}
+
+    fn note_on(&mut self, _velocity: f32) {}
+
+    fn note_off(&mut self) {}
+
+    /// Whether the voice should keep sounding after `note_off`, e.g. while an
+    /// envelope releases. Controllers stop generating a voice once this is
+    /// false.
+    fn is_releasing(&self) -> bool {
+        false
+    }
+}
+
+impl<S, E: Effect<S> + ?Sized> Effect<S> for Box<E> {
+    fn process(&mut self, signal: S) -> S {
+        (**self).process(signal)
+    }
+
+    fn process_block(&mut self, block: &mut [S])
+    where
+        S: Copy,
+    {
+        (**self).process_block(block)
+    }
+
+    fn set_context(&mut self, context: ProcessContext) {
+        (**self).set_context(context)
+    }
+
+    fn note_on(&mut self, velocity: f32) {
+        (**self).note_on(velocity)
+    }
+
+    fn note_off(&mut self) {
+        (**self).note_off()
+    }
+
+    fn is_releasing(&self) -> bool {
+        (**self).is_releasing()
+    }
+}
+
+impl<S, V: Voice<S> + ?Sized> Voice<S> for Box<V> {
+    fn generate(&mut self) -> S {
+        (**self).generate()
+    }
+
+    fn generate_block(&mut self, block: &mut [S]) {
+        (**self).generate_block(block)
+    }
+
+    fn set_context(&mut self, context: ProcessContext) {
+        (**self).set_context(context)
+    }
+
+    fn note_on(&mut self, velocity: f32) {
+        (**self).note_on(velocity)
+    }
+
+    fn note_off(&mut self) {
+        (**self).note_off()
+    }
+
+    fn is_releasing(&self) -> bool {
+        (**self).is_releasing()
+    }
 }
 
 pub struct Chain<Signal> {
@@ -38,4 +149,35 @@ impl<Signal> Effect<Signal> for Chain<Signal> {
         }
         output
     }
+
+    fn process_block(&mut self, block: &mut [Signal])
+    where
+        Signal: Copy,
+    {
+        for effect in self.chain.iter_mut() {
+            effect.process_block(block);
+        }
+    }
+
+    fn set_context(&mut self, context: ProcessContext) {
+        for effect in self.chain.iter_mut() {
+            effect.set_context(context);
+        }
+    }
+
+    fn note_on(&mut self, velocity: f32) {
+        for effect in self.chain.iter_mut() {
+            effect.note_on(velocity);
+        }
+    }
+
+    fn note_off(&mut self) {
+        for effect in self.chain.iter_mut() {
+            effect.note_off();
+        }
+    }
+
+    fn is_releasing(&self) -> bool {
+        self.chain.iter().any(|effect| effect.is_releasing())
+    }
 }
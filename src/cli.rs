@@ -0,0 +1,97 @@
+use crate::{audio::AudioOptions, offline::WavFormat};
+
+pub const USAGE: &str = "usage: rsynth [render [PATH] [16|24|32f]] [--list-devices]
+              [--patch FILE] [--presets DIR] [--midi FILE]
+              [--tuning EDO|just|FILE.scl] [--kbm FILE] [--a4 HZ]
+              [--host NAME] [--device NAME] [--sample-rate HZ] [--buffer-size FRAMES]";
+
+pub enum Command {
+    Play,
+    ListDevices,
+    Render { path: String, format: WavFormat },
+}
+
+pub struct Cli {
+    pub command: Command,
+    pub audio: AudioOptions,
+    pub patch: Option<String>,
+    pub presets: Option<String>,
+    pub midi: Option<String>,
+    pub tuning: Option<String>,
+    pub kbm: Option<String>,
+    pub a4: Option<f64>,
+}
+
+pub fn parse_args<I: Iterator<Item = String>>(mut args: I) -> Result<Cli, String> {
+    let mut list_devices = false;
+    let mut audio = AudioOptions::default();
+    let mut patch = None;
+    let mut presets = None;
+    let mut midi = None;
+    let mut tuning = None;
+    let mut kbm = None;
+    let mut a4 = None;
+    let mut positional = Vec::new();
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--list-devices" => list_devices = true,
+            "--patch" => patch = Some(value(&mut args, &arg)?),
+            "--presets" => presets = Some(value(&mut args, &arg)?),
+            "--midi" => midi = Some(value(&mut args, &arg)?),
+            "--tuning" => tuning = Some(value(&mut args, &arg)?),
+            "--kbm" => kbm = Some(value(&mut args, &arg)?),
+            "--a4" => a4 = Some(number(&mut args, &arg)?),
+            "--host" => audio.host = Some(value(&mut args, &arg)?),
+            "--device" => audio.device = Some(value(&mut args, &arg)?),
+            "--sample-rate" => audio.sample_rate = Some(number(&mut args, &arg)?),
+            "--buffer-size" => audio.buffer_size = Some(number(&mut args, &arg)?),
+            flag if flag.starts_with("--") => return Err(format!("unknown flag '{}'", flag)),
+            _ => positional.push(arg),
+        }
+    }
+
+    let command = match positional.first().map(String::as_str) {
+        _ if list_devices => Command::ListDevices,
+        None => Command::Play,
+        Some("render") => Command::Render {
+            path: positional
+                .get(1)
+                .cloned()
+                .unwrap_or_else(|| "out.wav".to_string()),
+            format: match positional.get(2).map(String::as_str) {
+                None | Some("16") => WavFormat::Int16,
+                Some("24") => WavFormat::Int24,
+                Some("32f") => WavFormat::Float32,
+                Some(other) => return Err(format!("unknown wav format '{}'", other)),
+            },
+        },
+        Some(other) => return Err(format!("unknown command '{}'", other)),
+    };
+
+    Ok(Cli {
+        command,
+        audio,
+        patch,
+        presets,
+        midi,
+        tuning,
+        kbm,
+        a4,
+    })
+}
+
+fn value<I: Iterator<Item = String>>(args: &mut I, flag: &str) -> Result<String, String> {
+    args.next()
+        .ok_or_else(|| format!("missing value for '{}'", flag))
+}
+
+fn number<I: Iterator<Item = String>, N: std::str::FromStr>(
+    args: &mut I,
+    flag: &str,
+) -> Result<N, String> {
+    let value = value(args, flag)?;
+    value
+        .parse()
+        .map_err(|_| format!("invalid value '{}' for '{}'", value, flag))
+}
@@ -3,22 +3,43 @@ use std::{
     ops::{Add, Mul},
 };
 
+use serde::{Deserialize, Serialize};
+
 use crate::{
-    chain::Voice,
+    chain::{ProcessContext, Voice},
     config::{
         ComposeConfig, ComposeConfigClient, Config, ConfigReceiver, HasConfig, ValidatedConfig,
         ValidatedConfigClient,
     },
+    preset::{indexed, Persistent},
 };
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
 pub struct TwoChannelConfig {
     pub a_mix: f32,
     pub b_mix: f32,
 }
 
+impl Persistent for TwoChannelConfig {
+    fn restore(&self, saved: Self) -> Result<Self, String> {
+        if validate_two_channel_config(&saved) {
+            Ok(saved)
+        } else {
+            Err("mix levels must be between 0 and 1".to_string())
+        }
+    }
+
+    fn parameter(&mut self, path: &str) -> Option<&mut f32> {
+        match path {
+            "a_mix" => Some(&mut self.a_mix),
+            "b_mix" => Some(&mut self.b_mix),
+            _ => None,
+        }
+    }
+}
+
 fn validate_two_channel_config(config: &TwoChannelConfig) -> bool {
-    let within_range = |vol| vol >= 0.0 && vol <= 1.0;
+    let within_range = |vol| (0.0..=1.0).contains(&vol);
     within_range(config.a_mix) && within_range(config.b_mix)
 }
 
@@ -33,14 +54,15 @@ pub type TwoChannelClient = ValidatedConfigClient<TwoChannelConfig>;
 
 impl<S, Va: Voice<S>, Vb: Voice<S>> TwoChannel<S, Va, Vb> {
     pub fn new(a: Va, b: Vb) -> Self {
+        Self::with_mix(a, b, 0.5, 0.5)
+    }
+
+    pub fn with_mix(a: Va, b: Vb, a_mix: f32, b_mix: f32) -> Self {
         Self {
             a,
             b,
             config: ValidatedConfig::new_validated(
-                TwoChannelConfig {
-                    a_mix: 0.5,
-                    b_mix: 0.5,
-                },
+                TwoChannelConfig { a_mix, b_mix },
                 validate_two_channel_config,
             ),
             _phantom: PhantomData,
@@ -64,12 +86,32 @@ impl<S: Add<Output = S> + Mul<Output = S> + From<f32>, Va: Voice<S>, Vb: Voice<S
         let signal_b = self.b.generate();
         signal_a * self.config.get().a_mix.into() + signal_b * self.config.get().b_mix.into()
     }
+
+    fn set_context(&mut self, context: ProcessContext) {
+        self.a.set_context(context);
+        self.b.set_context(context);
+    }
+
+    fn note_on(&mut self, velocity: f32) {
+        self.a.note_on(velocity);
+        self.b.note_on(velocity);
+    }
+
+    fn note_off(&mut self) {
+        self.a.note_off();
+        self.b.note_off();
+    }
+
+    fn is_releasing(&self) -> bool {
+        self.a.is_releasing() || self.b.is_releasing()
+    }
 }
 
 pub struct Mixer<V: Voice<f32>> {
     pub config:
         ComposeConfig<MixerConfig, MixerAction, fn(MixerConfig, MixerAction) -> MixerConfig>,
     pub voices: Vec<V>,
+    scratch: Vec<f32>,
 }
 
 pub type MixerClient =
@@ -81,7 +123,9 @@ fn reduce_mixer_action(mut config: MixerConfig, action: MixerAction) -> MixerCon
             channel,
             volume_change,
         } => {
-            config.channels[channel] += volume_change;
+            if let Some(volume) = config.channels.get_mut(channel) {
+                *volume += volume_change;
+            }
         }
     }
 
@@ -90,14 +134,15 @@ fn reduce_mixer_action(mut config: MixerConfig, action: MixerAction) -> MixerCon
 
 impl<V: Voice<f32>> Mixer<V> {
     pub fn new(voices: Vec<V>) -> Self {
+        let channels = vec![0.5; voices.len()];
+        Self::with_channels(voices, channels)
+    }
+
+    pub fn with_channels(voices: Vec<V>, channels: Vec<f32>) -> Self {
         Self {
-            config: ComposeConfig::new(
-                MixerConfig {
-                    channels: vec![0.5; voices.len()],
-                },
-                reduce_mixer_action,
-            ),
+            config: ComposeConfig::new(MixerConfig { channels }, reduce_mixer_action),
             voices,
+            scratch: Vec::new(),
         }
     }
 }
@@ -114,18 +159,75 @@ impl<V: Voice<f32>> Voice<f32> for Mixer<V> {
         let output = self
             .voices
             .iter_mut()
-            .enumerate()
-            .map(|(channel, voice)| voice.generate() * channels[channel])
+            .zip(channels.iter())
+            .map(|(voice, volume)| voice.generate() * volume)
             .sum();
         output
     }
+
+    fn generate_block(&mut self, block: &mut [f32]) {
+        let Self {
+            config,
+            voices,
+            scratch,
+        } = self;
+        scratch.resize(block.len(), 0.0);
+        block.iter_mut().for_each(|slot| *slot = 0.0);
+
+        for (voice, volume) in voices.iter_mut().zip(config.get().channels.iter()) {
+            voice.generate_block(scratch);
+            for (slot, sample) in block.iter_mut().zip(scratch.iter()) {
+                *slot += sample * volume;
+            }
+        }
+    }
+
+    fn set_context(&mut self, context: ProcessContext) {
+        self.scratch.resize(context.block_size, 0.0);
+        for voice in self.voices.iter_mut() {
+            voice.set_context(context);
+        }
+    }
+
+    fn note_on(&mut self, velocity: f32) {
+        for voice in self.voices.iter_mut() {
+            voice.note_on(velocity);
+        }
+    }
+
+    fn note_off(&mut self) {
+        for voice in self.voices.iter_mut() {
+            voice.note_off();
+        }
+    }
+
+    fn is_releasing(&self) -> bool {
+        self.voices.iter().any(|voice| voice.is_releasing())
+    }
 }
 
-#[derive(Clone)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct MixerConfig {
     pub channels: Vec<f32>,
 }
 
+impl Persistent for MixerConfig {
+    fn restore(&self, saved: Self) -> Result<Self, String> {
+        if saved.channels.len() != self.channels.len() {
+            return Err(format!(
+                "expected {} channels, found {}",
+                self.channels.len(),
+                saved.channels.len()
+            ));
+        }
+        Ok(saved)
+    }
+
+    fn parameter(&mut self, path: &str) -> Option<&mut f32> {
+        indexed(&mut self.channels, path, "channels")
+    }
+}
+
 #[derive(Clone, Copy)]
 pub enum MixerAction {
     Change { channel: usize, volume_change: f32 },
@@ -11,21 +11,23 @@ pub struct Config<C> {
 }
 
 pub struct ConfigClient<C> {
-    sender: Sender<C>,
+    senders: Vec<Sender<C>>,
     current: C,
 }
 
 impl<C: Clone> ConfigClient<C> {
     pub fn new(sender: Sender<C>, config: C) -> Self {
         Self {
-            sender,
+            senders: vec![sender],
             current: config,
         }
     }
 
     pub fn update<F: FnOnce(&mut C)>(&mut self, f: F) {
         f(&mut self.current);
-        self.sender.send(self.current.clone()).unwrap();
+        for sender in self.senders.iter() {
+            sender.send(self.current.clone()).unwrap();
+        }
     }
 
     pub fn get(&self) -> C {
@@ -54,10 +56,37 @@ impl<C: Clone> Config<C> {
     }
 }
 
+/// Makes one client also drive another's config, so copies of a voice can be
+/// controlled as one. The other config's current value is replaced with ours.
+pub trait JoinClient {
+    fn join(&mut self, other: Self);
+}
+
+impl<C: Clone> JoinClient for ConfigClient<C> {
+    fn join(&mut self, other: Self) {
+        for sender in other.senders.iter() {
+            sender.send(self.current.clone()).unwrap();
+        }
+        self.senders.extend(other.senders);
+    }
+}
+
+impl<C: Clone, D, F: Fn(C, D) -> C> JoinClient for ComposeConfigClient<C, D, F> {
+    fn join(&mut self, other: Self) {
+        self.client.join(other.client);
+    }
+}
+
 pub trait ConfigReceiver {
     fn try_update_configs(&mut self);
 }
 
+impl<R: ConfigReceiver + ?Sized> ConfigReceiver for Box<R> {
+    fn try_update_configs(&mut self) {
+        (**self).try_update_configs()
+    }
+}
+
 pub trait HasConfig<C> {
     fn get<'a>(&'a self) -> &'a C;
 }
@@ -120,6 +149,10 @@ impl<C: Clone, D, F: Fn(C, D) -> C> ComposeConfigClient<C, D, F> {
         self.client.update(|old| *old = c);
     }
 
+    pub fn set(&mut self, config: C) {
+        self.client.update(|old| *old = config);
+    }
+
     pub fn get(&self) -> C {
         self.client.get()
     }
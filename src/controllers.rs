@@ -7,21 +7,56 @@ use std::{
     },
 };
 
+use crossbeam::{atomic::AtomicCell, queue::ArrayQueue};
+use serde::{Deserialize, Serialize};
+
 use crate::{
-    chain::{Effect, Voice},
+    chain::{Effect, ProcessContext, Voice},
     config::{ComposeConfig, ComposeConfigClient, Config, ConfigReceiver, HasConfig},
+    preset::Persistent,
     voices::{HasFreq, Waveform},
 };
 
 #[derive(Clone, Copy)]
 pub enum KBConfigAction {
     Play(f32),
-    Stop,
+    Stop(f32),
+    StopAll,
+    /// Transposes every note by a frequency ratio.
     ChangeBase(f32),
+    /// Velocity for the notes played after it, from 0.0 to 1.0.
+    Velocity(f32),
+    /// Pitch bend as a frequency ratio applied to every note.
+    Bend(f32),
+    /// While the sustain pedal is down, stopped notes keep playing until it
+    /// is released.
+    Sustain(bool),
+}
+
+/// Actions a sequencer on the audio thread hands straight to a controller,
+/// so it never waits on a client the UI holds. They play alongside the
+/// client's notes rather than through its config, which the client replaces
+/// whole on every update.
+pub type KBActionQueue = Arc<ArrayQueue<KBConfigAction>>;
+
+const QUEUED_ACTIONS: usize = 1024;
+const SEQUENCED_NOTES: usize = 128;
+// Sequenced notes are numbered apart from the client's, so the two never
+// share an id.
+const SEQUENCED_IDS: u64 = 1 << 63;
+
+fn apply_queued(queue: &ArrayQueue<KBConfigAction>, sequenced: &mut KBCConfig) {
+    while let Some(action) = queue.pop() {
+        *sequenced = reduce_kb_config_action(std::mem::take(sequenced), action);
+    }
 }
 
 pub struct KeyboardController<V: Waveform<f32>> {
     voice: V,
+    note: Option<HeldNote>,
+    gate: bool,
+    queue: KBActionQueue,
+    sequenced: KBCConfig,
     pub config:
         ComposeConfig<KBCConfig, KBConfigAction, fn(KBCConfig, KBConfigAction) -> KBCConfig>,
 }
@@ -31,9 +66,32 @@ pub type KeyboardControllerClient =
 
 fn reduce_kb_config_action(mut config: KBCConfig, action: KBConfigAction) -> KBCConfig {
     match action {
-        KBConfigAction::Play(hz) => config.playing_note = Some(hz),
-        KBConfigAction::Stop => config.playing_note = None,
-        KBConfigAction::ChangeBase(hz) => config.base_hz = hz,
+        KBConfigAction::Play(hz) => {
+            config.held.retain(|note| note.hz != hz);
+            config.held.push(HeldNote {
+                hz,
+                velocity: config.velocity,
+                sustained: false,
+                id: config.next_id,
+            });
+            config.next_id += 1;
+        }
+        KBConfigAction::Stop(hz) if config.sustain => config
+            .held
+            .iter_mut()
+            .filter(|note| note.hz == hz)
+            .for_each(|note| note.sustained = true),
+        KBConfigAction::Stop(hz) => config.held.retain(|note| note.hz != hz),
+        KBConfigAction::StopAll => config.held.clear(),
+        KBConfigAction::ChangeBase(ratio) => config.transpose = ratio,
+        KBConfigAction::Velocity(velocity) => config.velocity = velocity,
+        KBConfigAction::Bend(ratio) => config.bend = ratio,
+        KBConfigAction::Sustain(sustain) => {
+            config.sustain = sustain;
+            if !sustain {
+                config.held.retain(|note| !note.sustained);
+            }
+        }
     }
 
     config
@@ -41,24 +99,138 @@ fn reduce_kb_config_action(mut config: KBCConfig, action: KBConfigAction) -> KBC
 
 impl<V: Waveform<f32>> KeyboardController<V> {
     pub fn new(voice: V) -> Self {
+        Self::with_transpose(voice, KBCConfig::default().transpose)
+    }
+
+    pub fn with_transpose(voice: V, transpose: f32) -> Self {
         Self {
             voice,
-            config: ComposeConfig::new(KBCConfig::default(), reduce_kb_config_action),
+            note: None,
+            gate: false,
+            queue: Arc::new(ArrayQueue::new(QUEUED_ACTIONS)),
+            sequenced: KBCConfig::sequenced(),
+            config: ComposeConfig::new(
+                KBCConfig {
+                    transpose,
+                    ..KBCConfig::default()
+                },
+                reduce_kb_config_action,
+            ),
         }
     }
 }
 
-#[derive(Clone, Copy, Debug)]
+/// A pressed key. Every press gets a fresh `id`, so a controller comparing
+/// two snapshots can tell a repeated press of the same note from a held one.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct HeldNote {
+    pub hz: f32,
+    pub velocity: f32,
+    /// Released while the sustain pedal was down.
+    sustained: bool,
+    id: u64,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct KBCConfig {
-    base_hz: f32,
-    playing_note: Option<f32>,
+    #[serde(default = "no_transpose")]
+    transpose: f32,
+    #[serde(skip)]
+    held: Vec<HeldNote>,
+    #[serde(skip)]
+    next_id: u64,
+    #[serde(skip)]
+    velocity: f32,
+    #[serde(skip)]
+    bend: f32,
+    #[serde(skip)]
+    sustain: bool,
+}
+
+fn no_transpose() -> f32 {
+    1.0
+}
+
+/// Only the transposition is part of a preset, loading one leaves the held
+/// notes and performance state alone.
+impl Persistent for KBCConfig {
+    fn restore(&self, saved: Self) -> Result<Self, String> {
+        Ok(Self {
+            transpose: saved.transpose,
+            ..self.clone()
+        })
+    }
+
+    fn parameter(&mut self, path: &str) -> Option<&mut f32> {
+        match path {
+            "transpose" => Some(&mut self.transpose),
+            _ => None,
+        }
+    }
+}
+
+impl KBCConfig {
+    // Room for a sequencer's notes, so playing them doesn't allocate.
+    fn sequenced() -> Self {
+        Self {
+            held: Vec::with_capacity(SEQUENCED_NOTES),
+            next_id: SEQUENCED_IDS,
+            ..Self::default()
+        }
+    }
+
+    fn note_hz(&self, note: HeldNote) -> f32 {
+        note.hz * self.transpose * self.bend
+    }
 }
 
 impl Default for KBCConfig {
     fn default() -> Self {
         Self {
-            base_hz: 0.0,
-            playing_note: None,
+            transpose: 1.0,
+            held: Vec::new(),
+            next_id: 0,
+            velocity: 1.0,
+            bend: 1.0,
+            sustain: false,
+        }
+    }
+}
+
+impl<V: Waveform<f32>> KeyboardController<V> {
+    pub fn action_queue(&self) -> KBActionQueue {
+        Arc::clone(&self.queue)
+    }
+
+    // Plays the most recent held note, keeping the last one around while the
+    // voice releases. A sequenced note takes priority over the client's.
+    fn update_note(&mut self) {
+        let latest = self
+            .sequenced
+            .held
+            .last()
+            .or_else(|| self.config.get().held.last())
+            .copied();
+        match latest {
+            Some(note) if self.note.map(|playing| playing.id) != Some(note.id) || !self.gate => {
+                self.note = Some(note);
+                self.gate = true;
+                self.voice.note_on(note.velocity);
+            }
+            None if self.gate => {
+                self.gate = false;
+                self.voice.note_off();
+            }
+            _ => {}
+        }
+    }
+
+    fn sounding_hz(&self) -> Option<f32> {
+        match self.note {
+            Some(note) if self.gate || self.voice.is_releasing() => {
+                Some(self.config.get().note_hz(note) * self.sequenced.bend)
+            }
+            _ => None,
         }
     }
 }
@@ -66,17 +238,412 @@ impl Default for KBCConfig {
 impl<V: Waveform<f32>> ConfigReceiver for KeyboardController<V> {
     fn try_update_configs(&mut self) {
         self.config.try_update();
+        apply_queued(&self.queue, &mut self.sequenced);
+        self.update_note();
         self.voice.try_update_configs()
     }
 }
 
 impl<V: Waveform<f32>> Voice<f32> for KeyboardController<V> {
     fn generate(&mut self) -> f32 {
-        if let Some(hz) = self.config.get().playing_note {
-            self.voice.set_freq(hz + self.config.get().base_hz);
+        if let Some(hz) = self.sounding_hz() {
+            self.voice.set_freq(hz);
             self.voice.generate()
         } else {
             0.0
         }
     }
+
+    fn generate_block(&mut self, block: &mut [f32]) {
+        if let Some(hz) = self.sounding_hz() {
+            self.voice.set_freq(hz);
+            self.voice.generate_block(block);
+        } else {
+            block.iter_mut().for_each(|slot| *slot = 0.0);
+        }
+    }
+
+    fn set_context(&mut self, context: ProcessContext) {
+        self.voice.set_context(context);
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum StealPolicy {
+    Oldest,
+    Quietest,
+    SameNoteRetrigger,
+}
+
+struct Slot<V> {
+    voice: V,
+    note: Option<HeldNote>,
+    gate: bool,
+    level: f32,
+    started: u64,
+}
+
+impl<V: Waveform<f32>> Slot<V> {
+    fn is_busy(&self) -> bool {
+        self.note.is_some() && (self.gate || self.voice.is_releasing())
+    }
+
+    fn start(&mut self, note: HeldNote, started: u64) {
+        self.note = Some(note);
+        self.gate = true;
+        self.started = started;
+        self.voice.note_on(note.velocity);
+    }
+}
+
+/// Plays every held note on its own voice, taking one from `policy` when all
+/// of them are busy.
+pub struct PolyKeyboardController<V: Waveform<f32>> {
+    slots: Vec<Slot<V>>,
+    policy: StealPolicy,
+    next_unseen: u64,
+    next_unseen_sequenced: u64,
+    // Counts note starts from either source, so stealing can go by age.
+    starts: u64,
+    queue: KBActionQueue,
+    sequenced: KBCConfig,
+    scratch: Vec<f32>,
+    active: Arc<AtomicCell<usize>>,
+    pub config:
+        ComposeConfig<KBCConfig, KBConfigAction, fn(KBCConfig, KBConfigAction) -> KBCConfig>,
+}
+
+impl<V: Waveform<f32>> PolyKeyboardController<V> {
+    pub fn new(voices: Vec<V>, policy: StealPolicy) -> Self {
+        Self::with_transpose(voices, policy, KBCConfig::default().transpose)
+    }
+
+    pub fn with_transpose(voices: Vec<V>, policy: StealPolicy, transpose: f32) -> Self {
+        Self {
+            slots: voices
+                .into_iter()
+                .map(|voice| Slot {
+                    voice,
+                    note: None,
+                    gate: false,
+                    level: 0.0,
+                    started: 0,
+                })
+                .collect(),
+            policy,
+            next_unseen: 0,
+            next_unseen_sequenced: SEQUENCED_IDS,
+            starts: 0,
+            queue: Arc::new(ArrayQueue::new(QUEUED_ACTIONS)),
+            sequenced: KBCConfig::sequenced(),
+            scratch: vec![0.0; ProcessContext::default().block_size],
+            active: Arc::new(AtomicCell::new(0)),
+            config: ComposeConfig::new(
+                KBCConfig {
+                    transpose,
+                    ..KBCConfig::default()
+                },
+                reduce_kb_config_action,
+            ),
+        }
+    }
+
+    pub fn active_voices(&self) -> Arc<AtomicCell<usize>> {
+        Arc::clone(&self.active)
+    }
+
+    pub fn action_queue(&self) -> KBActionQueue {
+        Arc::clone(&self.queue)
+    }
+
+    fn update_notes(&mut self) {
+        let client = &self.config.get().held;
+        let sequenced = &self.sequenced.held;
+        let held = || client.iter().chain(sequenced.iter());
+        let (next_unseen, next_unseen_sequenced) = (self.next_unseen, self.next_unseen_sequenced);
+        let is_new = |note: &HeldNote| {
+            if note.id >= SEQUENCED_IDS {
+                note.id >= next_unseen_sequenced
+            } else {
+                note.id >= next_unseen
+            }
+        };
+
+        for slot in self.slots.iter_mut() {
+            let playing = match slot.note {
+                Some(playing) if slot.gate => playing,
+                _ => continue,
+            };
+            if held().any(|note| note.id == playing.id) {
+                continue;
+            }
+
+            let retrigger = match self.policy {
+                StealPolicy::SameNoteRetrigger => held()
+                    .find(|note| is_new(note) && note.hz == playing.hz)
+                    .copied(),
+                _ => None,
+            };
+            match retrigger {
+                Some(note) => {
+                    self.starts += 1;
+                    slot.start(note, self.starts);
+                }
+                None => {
+                    slot.gate = false;
+                    slot.voice.note_off();
+                }
+            }
+        }
+
+        for note in held().filter(|note| is_new(note)) {
+            if self
+                .slots
+                .iter()
+                .any(|slot| slot.note.map(|playing| playing.id) == Some(note.id))
+            {
+                continue;
+            }
+            if let Some(index) = self.allocate(note.hz) {
+                self.starts += 1;
+                self.slots[index].start(*note, self.starts);
+            }
+        }
+
+        if let Some(last) = client.last() {
+            self.next_unseen = self.next_unseen.max(last.id + 1);
+        }
+        if let Some(last) = sequenced.last() {
+            self.next_unseen_sequenced = self.next_unseen_sequenced.max(last.id + 1);
+        }
+        self.update_active();
+    }
+
+    fn update_active(&self) {
+        self.active
+            .store(self.slots.iter().filter(|slot| slot.is_busy()).count());
+    }
+
+    fn allocate(&self, hz: f32) -> Option<usize> {
+        let busy_with = |slot: &Slot<V>| slot.note.filter(|_| slot.is_busy()).map(|note| note.hz);
+        if self.policy == StealPolicy::SameNoteRetrigger {
+            if let Some(index) = self
+                .slots
+                .iter()
+                .position(|slot| busy_with(slot) == Some(hz))
+            {
+                return Some(index);
+            }
+        }
+        if let Some(index) = self.slots.iter().position(|slot| !slot.is_busy()) {
+            return Some(index);
+        }
+
+        // Voices that are only ringing out go before ones whose key is held.
+        let any_releasing = self.slots.iter().any(|slot| !slot.gate);
+        let candidates =
+            (0..self.slots.len()).filter(|&index| !any_releasing || !self.slots[index].gate);
+
+        match self.policy {
+            StealPolicy::Oldest | StealPolicy::SameNoteRetrigger => {
+                candidates.min_by_key(|&index| self.slots[index].started)
+            }
+            StealPolicy::Quietest => candidates.min_by(|&a, &b| {
+                self.slots[a]
+                    .level
+                    .partial_cmp(&self.slots[b].level)
+                    .unwrap_or(std::cmp::Ordering::Equal)
+            }),
+        }
+    }
+}
+
+impl<V: Waveform<f32>> ConfigReceiver for PolyKeyboardController<V> {
+    fn try_update_configs(&mut self) {
+        self.config.try_update();
+        apply_queued(&self.queue, &mut self.sequenced);
+        self.update_notes();
+        for slot in self.slots.iter_mut() {
+            slot.voice.try_update_configs();
+        }
+    }
+}
+
+impl<V: Waveform<f32>> Voice<f32> for PolyKeyboardController<V> {
+    fn generate(&mut self) -> f32 {
+        let config = self.config.get();
+        let bend = self.sequenced.bend;
+        let mut signal = 0.0;
+        for slot in self.slots.iter_mut().filter(|slot| slot.is_busy()) {
+            if let Some(note) = slot.note {
+                slot.voice.set_freq(config.note_hz(note) * bend);
+                let sample = slot.voice.generate();
+                slot.level = sample.abs().max(slot.level * 0.999);
+                signal += sample;
+            }
+        }
+        signal
+    }
+
+    fn generate_block(&mut self, block: &mut [f32]) {
+        let config = self.config.get();
+        let bend = self.sequenced.bend;
+        block.iter_mut().for_each(|slot| *slot = 0.0);
+
+        // Blocks longer than the context promised are split rather than
+        // growing the scratch buffer here.
+        let chunk_len = self.scratch.len();
+        for (chunk_index, chunk) in block.chunks_mut(chunk_len).enumerate() {
+            let scratch = &mut self.scratch[..chunk.len()];
+            for slot in self.slots.iter_mut().filter(|slot| slot.is_busy()) {
+                if let Some(note) = slot.note {
+                    slot.voice.set_freq(config.note_hz(note) * bend);
+                    slot.voice.generate_block(scratch);
+                    let peak = scratch.iter().fold(0.0, |peak: f32, s| s.abs().max(peak));
+                    slot.level = if chunk_index == 0 {
+                        peak
+                    } else {
+                        slot.level.max(peak)
+                    };
+                    for (out, sample) in chunk.iter_mut().zip(scratch.iter()) {
+                        *out += sample;
+                    }
+                }
+            }
+        }
+    }
+
+    fn set_context(&mut self, context: ProcessContext) {
+        self.scratch.resize(context.block_size.max(1), 0.0);
+        for slot in self.slots.iter_mut() {
+            slot.voice.set_context(context);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{preset::Persist, voices::Sine};
+
+    #[test]
+    fn restore_keeps_held_notes() {
+        let mut controller =
+            PolyKeyboardController::new(vec![Sine::new(440.0)], StealPolicy::Oldest);
+        let active = controller.active_voices();
+        let mut client = controller.config.get_client().unwrap();
+
+        client.update(|_| KBConfigAction::Play(440.0));
+        controller.try_update_configs();
+        assert_eq!(active.load(), 1);
+
+        let mut saved = client.save().unwrap();
+        saved
+            .as_table_mut()
+            .unwrap()
+            .insert("transpose".to_string(), toml::Value::Float(2.0));
+        client.restore(saved).unwrap();
+        controller.try_update_configs();
+
+        assert_eq!(active.load(), 1);
+        assert_eq!(controller.config.get().held.len(), 1);
+        assert_eq!(controller.config.get().transpose, 2.0);
+    }
+
+    #[test]
+    fn queued_notes_survive_client_updates() {
+        let mut controller = PolyKeyboardController::new(
+            vec![Sine::new(440.0), Sine::new(440.0)],
+            StealPolicy::Oldest,
+        );
+        let active = controller.active_voices();
+        let queue = controller.action_queue();
+        let mut client = controller.config.get_client().unwrap();
+
+        assert!(queue.push(KBConfigAction::Play(220.0)).is_ok());
+        controller.try_update_configs();
+        client.update(|_| KBConfigAction::Play(440.0));
+        controller.try_update_configs();
+        assert_eq!(active.load(), 2);
+
+        client.update(|_| KBConfigAction::Stop(440.0));
+        controller.try_update_configs();
+        assert_eq!(active.load(), 1);
+        assert!(controller
+            .slots
+            .iter()
+            .any(|slot| slot.gate && slot.note.map(|note| note.hz) == Some(220.0)));
+    }
+
+    #[test]
+    fn steals_oldest_voice() {
+        let mut controller = PolyKeyboardController::new(
+            vec![Sine::new(440.0), Sine::new(440.0)],
+            StealPolicy::Oldest,
+        );
+        let mut client = controller.config.get_client().unwrap();
+
+        for hz in [220.0, 330.0, 440.0].iter() {
+            client.update(|_| KBConfigAction::Play(*hz));
+            controller.try_update_configs();
+        }
+
+        let mut playing: Vec<f32> = controller
+            .slots
+            .iter()
+            .filter_map(|slot| slot.note.map(|note| note.hz))
+            .collect();
+        playing.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        assert_eq!(playing, vec![330.0, 440.0]);
+    }
+
+    #[test]
+    fn steals_oldest_across_sources() {
+        let mut controller = PolyKeyboardController::new(
+            vec![Sine::new(440.0), Sine::new(440.0)],
+            StealPolicy::Oldest,
+        );
+        let queue = controller.action_queue();
+        let mut client = controller.config.get_client().unwrap();
+
+        assert!(queue.push(KBConfigAction::Play(220.0)).is_ok());
+        controller.try_update_configs();
+        client.update(|_| KBConfigAction::Play(330.0));
+        controller.try_update_configs();
+        client.update(|_| KBConfigAction::Play(440.0));
+        controller.try_update_configs();
+
+        let mut playing: Vec<f32> = controller
+            .slots
+            .iter()
+            .filter_map(|slot| slot.note.map(|note| note.hz))
+            .collect();
+        playing.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        assert_eq!(playing, vec![330.0, 440.0]);
+    }
+
+    #[test]
+    fn splits_blocks_longer_than_the_context() {
+        let make = || {
+            let mut controller =
+                PolyKeyboardController::new(vec![Sine::new(440.0)], StealPolicy::Oldest);
+            let mut client = controller.config.get_client().unwrap();
+            client.update(|_| KBConfigAction::Play(440.0));
+            controller.try_update_configs();
+            controller
+        };
+        let mut blocks = make();
+        blocks.set_context(ProcessContext {
+            block_size: 4,
+            ..ProcessContext::default()
+        });
+        let mut samples = make();
+
+        let mut block = [0.0; 10];
+        blocks.generate_block(&mut block);
+        assert_eq!(blocks.scratch.len(), 4);
+        for sample in block.iter() {
+            assert!((sample - samples.generate()).abs() < 1e-6);
+        }
+    }
 }
@@ -8,7 +8,7 @@ use crossbeam::atomic::AtomicCell;
 use num::{Num, NumCast, Signed};
 
 use crate::{
-    chain::{Effect, Voice},
+    chain::{Effect, ProcessContext, Voice},
     config::{Config, ConfigReceiver},
     voices::{HasFreq, Waveform},
 };
@@ -17,6 +17,7 @@ pub struct LowPassFilter<Signal> {
     pub hz: f32,
     pub velocity: Arc<AtomicCell<f32>>,
     output: Signal,
+    sample_rate: f32,
 }
 
 impl LowPassFilter<f32> {
@@ -25,11 +26,16 @@ impl LowPassFilter<f32> {
             hz,
             velocity: Arc::new(AtomicCell::new(1.0)),
             output: 0.0,
+            sample_rate: ProcessContext::default().sample_rate,
         }
     }
 
+    pub fn set_context(&mut self, context: ProcessContext) {
+        self.sample_rate = context.sample_rate;
+    }
+
     pub fn delta(&mut self) -> Duration {
-        Duration::from_secs_f32(1.0 / 44100.0)
+        Duration::from_secs_f32(1.0 / self.sample_rate)
     }
 }
 
@@ -106,4 +112,23 @@ impl<S: NumCast, M: Waveform<S>, V: Waveform<S>> Voice<S> for FM<S, M, V> {
             .set_freq(self.modulator.generate().to_f32().unwrap());
         self.voice.generate()
     }
+
+    fn set_context(&mut self, context: ProcessContext) {
+        self.modulator.set_context(context);
+        self.voice.set_context(context);
+    }
+
+    fn note_on(&mut self, velocity: f32) {
+        self.modulator.note_on(velocity);
+        self.voice.note_on(velocity);
+    }
+
+    fn note_off(&mut self) {
+        self.modulator.note_off();
+        self.voice.note_off();
+    }
+
+    fn is_releasing(&self) -> bool {
+        self.modulator.is_releasing() || self.voice.is_releasing()
+    }
 }
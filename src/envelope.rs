@@ -0,0 +1,305 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    chain::{Effect, ProcessContext, Voice},
+    config::{Config, ConfigClient, ConfigReceiver},
+    preset::Persistent,
+};
+
+/// Stage lengths are in seconds. `curve` bends every stage: 0.0 is linear,
+/// positive values move quickly at first and settle slowly, negative values
+/// do the opposite.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct EnvelopeConfig {
+    pub attack: f32,
+    pub hold: f32,
+    pub decay: f32,
+    pub sustain: f32,
+    pub release: f32,
+    pub curve: f32,
+}
+
+impl Persistent for EnvelopeConfig {
+    fn parameter(&mut self, path: &str) -> Option<&mut f32> {
+        match path {
+            "attack" => Some(&mut self.attack),
+            "hold" => Some(&mut self.hold),
+            "decay" => Some(&mut self.decay),
+            "sustain" => Some(&mut self.sustain),
+            "release" => Some(&mut self.release),
+            "curve" => Some(&mut self.curve),
+            _ => None,
+        }
+    }
+}
+
+impl Default for EnvelopeConfig {
+    fn default() -> Self {
+        Self {
+            attack: 0.01,
+            hold: 0.0,
+            decay: 0.1,
+            sustain: 0.7,
+            release: 0.3,
+            curve: 0.0,
+        }
+    }
+}
+
+pub type EnvelopeClient = ConfigClient<EnvelopeConfig>;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Stage {
+    Idle,
+    Attack,
+    Hold,
+    Decay,
+    Sustain,
+    Release,
+}
+
+/// An ADSR envelope with an optional hold stage. As an `Effect` it scales its
+/// input, as a `Voice` it outputs the envelope itself for use as a
+/// modulation source.
+pub struct Envelope {
+    pub config: Config<EnvelopeConfig>,
+    stage: Stage,
+    elapsed: f32,
+    level: f32,
+    from: f32,
+    velocity: f32,
+    sample_rate: f32,
+}
+
+impl Envelope {
+    pub fn new(config: EnvelopeConfig) -> Self {
+        Self {
+            config: Config::new(config),
+            stage: Stage::Idle,
+            elapsed: 0.0,
+            level: 0.0,
+            from: 0.0,
+            velocity: 1.0,
+            sample_rate: ProcessContext::default().sample_rate,
+        }
+    }
+
+    pub fn note_on(&mut self, velocity: f32) {
+        self.velocity = velocity;
+        self.enter(Stage::Attack);
+    }
+
+    pub fn note_off(&mut self) {
+        if self.stage != Stage::Idle {
+            self.enter(Stage::Release);
+        }
+    }
+
+    pub fn is_releasing(&self) -> bool {
+        self.stage == Stage::Release
+    }
+
+    // Stages start from the current level, velocity included, so retriggers
+    // and early releases don't jump.
+    fn enter(&mut self, stage: Stage) {
+        self.stage = stage;
+        self.elapsed = 0.0;
+        self.from = self.level;
+    }
+
+    fn shape(&self, progress: f32) -> f32 {
+        let curve = self.config.config.curve;
+        if curve.abs() < 1e-3 {
+            progress
+        } else {
+            (1.0 - (-curve * progress).exp()) / (1.0 - (-curve).exp())
+        }
+    }
+
+    fn next(&mut self) -> f32 {
+        let config = self.config.config;
+        let length = match self.stage {
+            Stage::Attack => config.attack,
+            Stage::Hold => config.hold,
+            Stage::Decay => config.decay,
+            Stage::Release => config.release,
+            Stage::Idle | Stage::Sustain => f32::INFINITY,
+        };
+        let progress = if length > 0.0 {
+            (self.elapsed / length).min(1.0)
+        } else {
+            1.0
+        };
+        let shaped = self.shape(progress);
+        let (peak, sustain) = (self.velocity, config.sustain * self.velocity);
+
+        self.level = match self.stage {
+            Stage::Idle => 0.0,
+            Stage::Attack => self.from + (peak - self.from) * shaped,
+            Stage::Hold => peak,
+            Stage::Decay => self.from + (sustain - self.from) * shaped,
+            Stage::Sustain => sustain,
+            Stage::Release => self.from * (1.0 - shaped),
+        };
+
+        self.elapsed += 1.0 / self.sample_rate;
+        if progress >= 1.0 {
+            match self.stage {
+                Stage::Attack => self.enter(Stage::Hold),
+                Stage::Hold => self.enter(Stage::Decay),
+                Stage::Decay => self.enter(Stage::Sustain),
+                Stage::Release => self.enter(Stage::Idle),
+                Stage::Idle | Stage::Sustain => {}
+            }
+        }
+
+        self.level
+    }
+}
+
+impl ConfigReceiver for Envelope {
+    fn try_update_configs(&mut self) {
+        self.config.try_update();
+    }
+}
+
+impl Effect<f32> for Envelope {
+    fn process(&mut self, signal: f32) -> f32 {
+        signal * self.next()
+    }
+
+    fn set_context(&mut self, context: ProcessContext) {
+        self.sample_rate = context.sample_rate;
+    }
+
+    fn note_on(&mut self, velocity: f32) {
+        Envelope::note_on(self, velocity)
+    }
+
+    fn note_off(&mut self) {
+        Envelope::note_off(self)
+    }
+
+    fn is_releasing(&self) -> bool {
+        Envelope::is_releasing(self)
+    }
+}
+
+impl Voice<f32> for Envelope {
+    fn generate(&mut self) -> f32 {
+        self.next()
+    }
+
+    fn set_context(&mut self, context: ProcessContext) {
+        self.sample_rate = context.sample_rate;
+    }
+
+    fn note_on(&mut self, velocity: f32) {
+        Envelope::note_on(self, velocity)
+    }
+
+    fn note_off(&mut self) {
+        Envelope::note_off(self)
+    }
+
+    fn is_releasing(&self) -> bool {
+        Envelope::is_releasing(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // At 1kHz a stage of 0.01s lasts 10 samples.
+    fn at_1khz(config: EnvelopeConfig) -> Envelope {
+        let mut envelope = Envelope::new(config);
+        Voice::set_context(
+            &mut envelope,
+            ProcessContext {
+                sample_rate: 1000.0,
+                ..ProcessContext::default()
+            },
+        );
+        envelope
+    }
+
+    fn run(envelope: &mut Envelope, samples: usize) -> Vec<f32> {
+        (0..samples).map(|_| envelope.generate()).collect()
+    }
+
+    const LINEAR: EnvelopeConfig = EnvelopeConfig {
+        attack: 0.01,
+        hold: 0.01,
+        decay: 0.01,
+        sustain: 0.5,
+        release: 0.01,
+        curve: 0.0,
+    };
+
+    #[test]
+    fn holds_at_the_peak_before_decaying() {
+        let mut envelope = at_1khz(LINEAR);
+        envelope.note_on(1.0);
+        let levels = run(&mut envelope, 40);
+
+        assert_eq!(levels[0], 0.0);
+        assert!((levels[5] - 0.5).abs() < 1e-6);
+        assert!(levels[10..23].iter().all(|level| *level == 1.0));
+        assert!(levels[23] < 1.0);
+        assert!(levels[33..].iter().all(|level| *level == 0.5));
+
+        let mut envelope = at_1khz(EnvelopeConfig {
+            hold: 0.0,
+            ..LINEAR
+        });
+        envelope.note_on(1.0);
+        assert!(run(&mut envelope, 14)[13] < 1.0);
+    }
+
+    #[test]
+    fn curve_bends_the_stages() {
+        let halfway = |curve| {
+            let mut envelope = at_1khz(EnvelopeConfig { curve, ..LINEAR });
+            envelope.note_on(1.0);
+            run(&mut envelope, 6)[5]
+        };
+        assert!(halfway(4.0) > 0.5);
+        assert!((halfway(0.0) - 0.5).abs() < 1e-6);
+        assert!(halfway(-4.0) < 0.5);
+    }
+
+    #[test]
+    fn releases_from_any_stage_without_jumping() {
+        for &played in [5, 15, 25, 40].iter() {
+            let mut envelope = at_1khz(LINEAR);
+            envelope.note_on(1.0);
+            let before = *run(&mut envelope, played).last().unwrap();
+            envelope.note_off();
+            assert!(envelope.is_releasing());
+
+            let release = run(&mut envelope, 12);
+            assert!((release[0] - before).abs() < 1e-6, "{}", played);
+            assert!(release.windows(2).all(|pair| pair[1] <= pair[0]));
+            assert_eq!(*release.last().unwrap(), 0.0);
+            assert!(!envelope.is_releasing());
+        }
+    }
+
+    #[test]
+    fn retriggers_from_the_current_level() {
+        let mut envelope = at_1khz(LINEAR);
+        envelope.note_on(1.0);
+        let sustained = *run(&mut envelope, 40).last().unwrap();
+
+        // A softer note starts where the louder one was and attacks to its
+        // own peak.
+        envelope.note_on(0.2);
+        let retriggered = run(&mut envelope, 40);
+        assert!((retriggered[0] - sustained).abs() < 1e-6);
+        assert!(retriggered
+            .windows(2)
+            .all(|pair| (pair[1] - pair[0]).abs() <= 0.05));
+        assert!((retriggered[39] - 0.1).abs() < 1e-6);
+    }
+}
@@ -0,0 +1,600 @@
+use std::{collections::HashMap, fmt};
+
+use crossbeam::channel::{Receiver, Sender};
+
+use petgraph::{
+    algo::toposort,
+    stable_graph::{NodeIndex, StableGraph},
+    visit::{EdgeFiltered, EdgeRef, IntoEdgeReferences},
+    Direction,
+};
+
+use crate::{
+    chain::{Effect, ProcessContext, Voice},
+    config::{Config, ConfigReceiver},
+    effects::Gate,
+    envelope::{Envelope, EnvelopeConfig},
+    noise::{BrownNoise, PinkNoise, WhiteNoise},
+    oscillators::{Saw, Square, Triangle},
+    voices::{HasFreq, Sine, Waveform},
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct NodeId(pub usize);
+
+/// Oscillators play at the graph's frequency times `ratio`, offset by the
+/// sum of their modulation inputs. Delays pass their input on one sample
+/// later, which is the only way a cycle is allowed.
+pub enum Node {
+    Oscillator {
+        voice: Box<dyn Waveform<f32> + Send>,
+        ratio: f32,
+    },
+    Voice(Box<dyn Voice<f32> + Send>),
+    Effect(Box<dyn Effect<f32> + Send>),
+    Mixer(f32),
+    Delay,
+    Output,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum NodeKind {
+    Oscillator,
+    Voice,
+    Effect,
+    Mixer,
+    Delay,
+    Output,
+}
+
+impl Node {
+    pub fn kind(&self) -> NodeKind {
+        match self {
+            Node::Oscillator { .. } => NodeKind::Oscillator,
+            Node::Voice(_) => NodeKind::Voice,
+            Node::Effect(_) => NodeKind::Effect,
+            Node::Mixer(_) => NodeKind::Mixer,
+            Node::Delay => NodeKind::Delay,
+            Node::Output => NodeKind::Output,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Edge {
+    Audio(f32),
+    Modulation(f32),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum GraphError {
+    UnknownNode(NodeId),
+    Cycle(NodeId),
+    NotModulatable(NodeId),
+    RemoveOutput,
+    Full,
+    Busy,
+}
+
+impl fmt::Display for GraphError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GraphError::UnknownNode(id) => write!(f, "no node {}", id.0),
+            GraphError::Cycle(id) => write!(f, "cycle through node {} without a delay", id.0),
+            GraphError::NotModulatable(id) => write!(f, "node {} has no frequency", id.0),
+            GraphError::RemoveOutput => write!(f, "the output node cannot be removed"),
+            GraphError::Full => write!(f, "the graph has no room for more nodes or edges"),
+            GraphError::Busy => write!(f, "the audio thread hasn't caught up with earlier edits"),
+        }
+    }
+}
+
+/// Room for nodes and edges is reserved up front, so the audio thread never
+/// grows the graph.
+const MAX_NODES: usize = 64;
+const MAX_EDGES: usize = 256;
+/// Edits that can wait for the audio thread at once.
+const QUEUED_EDITS: usize = 64;
+
+// Nodes are addressed by index. The client's copy of the graph sees the same
+// additions and removals in the same order, so its indices are the graph's.
+enum Edit {
+    Add(NodeIndex, Node),
+    Remove(NodeIndex),
+    Connect(NodeIndex, NodeIndex, Edge),
+    Disconnect(NodeIndex, NodeIndex),
+}
+
+// Each edit comes with the processing order after it, worked out by the
+// client.
+struct GraphCommand {
+    edit: Edit,
+    order: Vec<NodeIndex>,
+}
+
+// What an edit replaced, sent back so it's freed off the audio thread.
+struct Retired {
+    _node: Option<Node>,
+    _order: Vec<NodeIndex>,
+}
+
+fn schedule<N>(
+    graph: &StableGraph<N, Edge>,
+    is_delay: impl Fn(&N) -> bool,
+) -> Result<Vec<NodeIndex>, NodeIndex> {
+    // Edges leaving a delay carry last sample's value, so they don't constrain the order.
+    let filtered = EdgeFiltered::from_fn(graph, |edge| !is_delay(&graph[edge.source()]));
+    toposort(&filtered, None).map_err(|cycle| cycle.node_id())
+}
+
+struct NodeState {
+    node: Node,
+    output: f32,
+    delayed: f32,
+}
+
+pub struct Graph {
+    graph: StableGraph<NodeState, Edge>,
+    order: Vec<NodeIndex>,
+    output: NodeIndex,
+    hz: f32,
+    context: ProcessContext,
+    commands: Receiver<GraphCommand>,
+    retired: Sender<Retired>,
+    client: Option<GraphClient>,
+}
+
+impl Graph {
+    pub fn new(hz: f32) -> Self {
+        let (sender, commands) = crossbeam::channel::bounded(QUEUED_EDITS);
+        let (retired, retired_receiver) = crossbeam::channel::bounded(QUEUED_EDITS);
+        let mut graph = StableGraph::with_capacity(MAX_NODES, MAX_EDGES);
+        let output = graph.add_node(NodeState {
+            node: Node::Output,
+            output: 0.0,
+            delayed: 0.0,
+        });
+
+        Self {
+            graph,
+            order: vec![output],
+            output,
+            hz,
+            context: ProcessContext::default(),
+            commands,
+            retired,
+            client: Some(GraphClient::new(sender, retired_receiver)),
+        }
+    }
+
+    pub fn get_client(&mut self) -> Option<GraphClient> {
+        std::mem::take(&mut self.client)
+    }
+
+    fn apply(&mut self, command: GraphCommand) {
+        let mut removed = None;
+        match command.edit {
+            Edit::Add(index, mut node) => {
+                match &mut node {
+                    Node::Oscillator { voice, .. } => voice.set_context(self.context),
+                    Node::Voice(voice) => voice.set_context(self.context),
+                    Node::Effect(effect) => effect.set_context(self.context),
+                    _ => {}
+                }
+                let added = self.graph.add_node(NodeState {
+                    node,
+                    output: 0.0,
+                    delayed: 0.0,
+                });
+                debug_assert_eq!(added, index);
+            }
+            Edit::Remove(index) => removed = self.graph.remove_node(index).map(|state| state.node),
+            Edit::Connect(a, b, edge) => {
+                self.graph.update_edge(a, b, edge);
+            }
+            Edit::Disconnect(a, b) => {
+                while let Some(edge) = self.graph.find_edge(a, b) {
+                    self.graph.remove_edge(edge);
+                }
+            }
+        }
+
+        let order = std::mem::replace(&mut self.order, command.order);
+        // The client leaves room for everything it has edits in flight for.
+        let _ = self.retired.try_send(Retired {
+            _node: removed,
+            _order: order,
+        });
+    }
+
+    fn inputs(&self, index: NodeIndex) -> (f32, f32) {
+        let mut audio = 0.0;
+        let mut modulation = 0.0;
+        for edge in self.graph.edges_directed(index, Direction::Incoming) {
+            let source = &self.graph[edge.source()];
+            let signal = match source.node {
+                Node::Delay => source.delayed,
+                _ => source.output,
+            };
+            match edge.weight() {
+                Edge::Audio(gain) => audio += signal * gain,
+                Edge::Modulation(depth) => modulation += signal * depth,
+            }
+        }
+        (audio, modulation)
+    }
+}
+
+impl ConfigReceiver for Graph {
+    fn try_update_configs(&mut self) {
+        while let Ok(command) = self.commands.try_recv() {
+            self.apply(command);
+        }
+
+        for state in self.graph.node_weights_mut() {
+            match &mut state.node {
+                Node::Oscillator { voice, .. } => voice.try_update_configs(),
+                Node::Voice(voice) => voice.try_update_configs(),
+                Node::Effect(effect) => effect.try_update_configs(),
+                _ => {}
+            }
+        }
+    }
+}
+
+impl Voice<f32> for Graph {
+    fn generate(&mut self) -> f32 {
+        for i in 0..self.order.len() {
+            let index = self.order[i];
+            let (audio, modulation) = self.inputs(index);
+            let hz = self.hz;
+            let state = &mut self.graph[index];
+            state.output = match &mut state.node {
+                Node::Oscillator { voice, ratio } => {
+                    voice.set_freq(hz * *ratio + modulation);
+                    voice.generate()
+                }
+                Node::Voice(voice) => voice.generate(),
+                Node::Effect(effect) => effect.process(audio),
+                Node::Mixer(gain) => audio * *gain,
+                Node::Delay | Node::Output => audio,
+            };
+        }
+
+        for state in self.graph.node_weights_mut() {
+            if let Node::Delay = state.node {
+                state.delayed = state.output;
+            }
+        }
+
+        self.graph[self.output].output
+    }
+
+    fn set_context(&mut self, context: ProcessContext) {
+        self.context = context;
+        for state in self.graph.node_weights_mut() {
+            match &mut state.node {
+                Node::Oscillator { voice, .. } => voice.set_context(context),
+                Node::Voice(voice) => voice.set_context(context),
+                Node::Effect(effect) => effect.set_context(context),
+                _ => {}
+            }
+        }
+    }
+
+    fn note_on(&mut self, velocity: f32) {
+        for state in self.graph.node_weights_mut() {
+            match &mut state.node {
+                Node::Oscillator { voice, .. } => voice.note_on(velocity),
+                Node::Voice(voice) => voice.note_on(velocity),
+                Node::Effect(effect) => effect.note_on(velocity),
+                _ => {}
+            }
+        }
+    }
+
+    fn note_off(&mut self) {
+        for state in self.graph.node_weights_mut() {
+            match &mut state.node {
+                Node::Oscillator { voice, .. } => voice.note_off(),
+                Node::Voice(voice) => voice.note_off(),
+                Node::Effect(effect) => effect.note_off(),
+                _ => {}
+            }
+        }
+    }
+
+    fn is_releasing(&self) -> bool {
+        self.graph
+            .node_indices()
+            .any(|index| match &self.graph[index].node {
+                Node::Oscillator { voice, .. } => voice.is_releasing(),
+                Node::Voice(voice) => voice.is_releasing(),
+                Node::Effect(effect) => effect.is_releasing(),
+                _ => false,
+            })
+    }
+}
+
+impl HasFreq for Graph {
+    fn set_freq(&mut self, hz: f32) {
+        self.hz = hz;
+    }
+
+    fn get_freq(&self) -> f32 {
+        self.hz
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct NodeInfo {
+    pub id: NodeId,
+    pub kind: NodeKind,
+}
+
+/// Edits a running `Graph`. Keeps its own copy of the topology so changes can
+/// be validated and scheduled before they reach the audio thread, which also
+/// hands back anything they replaced to be dropped here.
+pub struct GraphClient {
+    sender: Sender<GraphCommand>,
+    retired: Receiver<Retired>,
+    graph: StableGraph<NodeInfo, Edge>,
+    indices: HashMap<NodeId, NodeIndex>,
+    next_id: usize,
+}
+
+impl GraphClient {
+    fn new(sender: Sender<GraphCommand>, retired: Receiver<Retired>) -> Self {
+        let mut graph = StableGraph::new();
+        let mut indices = HashMap::new();
+        indices.insert(
+            NodeId(0),
+            graph.add_node(NodeInfo {
+                id: NodeId(0),
+                kind: NodeKind::Output,
+            }),
+        );
+
+        Self {
+            sender,
+            retired,
+            graph,
+            indices,
+            next_id: 1,
+        }
+    }
+
+    // Frees what earlier edits replaced, and checks there's room for
+    // another, and for what it will replace.
+    fn reserve(&mut self) -> Result<(), GraphError> {
+        self.retired.try_iter().for_each(drop);
+        if self.sender.len() + self.retired.len() >= QUEUED_EDITS {
+            return Err(GraphError::Busy);
+        }
+        Ok(())
+    }
+
+    fn send(&self, edit: Edit) {
+        let order = schedule(&self.graph, |info| info.kind == NodeKind::Delay)
+            .expect("the client only keeps edits that schedule");
+        let _ = self.sender.try_send(GraphCommand { edit, order });
+    }
+
+    pub fn output(&self) -> NodeId {
+        NodeId(0)
+    }
+
+    pub fn add(&mut self, node: Node) -> Result<NodeId, GraphError> {
+        if self.graph.node_count() >= MAX_NODES {
+            return Err(GraphError::Full);
+        }
+        self.reserve()?;
+        let id = NodeId(self.next_id);
+        self.next_id += 1;
+
+        let index = self.graph.add_node(NodeInfo {
+            id,
+            kind: node.kind(),
+        });
+        self.indices.insert(id, index);
+        self.send(Edit::Add(index, node));
+        Ok(id)
+    }
+
+    pub fn remove(&mut self, id: NodeId) -> Result<(), GraphError> {
+        if id == self.output() {
+            return Err(GraphError::RemoveOutput);
+        }
+        let index = self.index(id)?;
+        self.reserve()?;
+
+        self.graph.remove_node(index);
+        self.indices.remove(&id);
+        self.send(Edit::Remove(index));
+        Ok(())
+    }
+
+    pub fn connect(&mut self, from: NodeId, to: NodeId, edge: Edge) -> Result<(), GraphError> {
+        let a = self.index(from)?;
+        let b = self.index(to)?;
+        if let Edge::Modulation(_) = edge {
+            if self.graph[b].kind != NodeKind::Oscillator {
+                return Err(GraphError::NotModulatable(to));
+            }
+        }
+        let previous = self.graph.find_edge(a, b).map(|e| self.graph[e]);
+        if previous.is_none() && self.graph.edge_count() >= MAX_EDGES {
+            return Err(GraphError::Full);
+        }
+        self.reserve()?;
+        let added = self.graph.update_edge(a, b, edge);
+        if let Err(index) = schedule(&self.graph, |info| info.kind == NodeKind::Delay) {
+            match previous {
+                Some(previous) => self.graph[added] = previous,
+                None => {
+                    self.graph.remove_edge(added);
+                }
+            }
+            return Err(GraphError::Cycle(self.graph[index].id));
+        }
+
+        self.send(Edit::Connect(a, b, edge));
+        Ok(())
+    }
+
+    pub fn disconnect(&mut self, from: NodeId, to: NodeId) -> Result<(), GraphError> {
+        let a = self.index(from)?;
+        let b = self.index(to)?;
+        self.reserve()?;
+        while let Some(edge) = self.graph.find_edge(a, b) {
+            self.graph.remove_edge(edge);
+        }
+
+        self.send(Edit::Disconnect(a, b));
+        Ok(())
+    }
+
+    pub fn nodes(&self) -> Vec<NodeInfo> {
+        let mut nodes: Vec<NodeInfo> = self
+            .graph
+            .node_indices()
+            .map(|index| self.graph[index])
+            .collect();
+        nodes.sort_by_key(|info| info.id);
+        nodes
+    }
+
+    pub fn edges(&self) -> Vec<(NodeId, NodeId, Edge)> {
+        self.graph
+            .edge_references()
+            .map(|edge| {
+                (
+                    self.graph[edge.source()].id,
+                    self.graph[edge.target()].id,
+                    *edge.weight(),
+                )
+            })
+            .collect()
+    }
+
+    fn index(&self, id: NodeId) -> Result<NodeIndex, GraphError> {
+        self.indices
+            .get(&id)
+            .copied()
+            .ok_or(GraphError::UnknownNode(id))
+    }
+}
+
+/// Makes a node for the palette. The argument seeds anything random in it,
+/// and is different for every node added, so two noise nodes don't play the
+/// same noise.
+pub type MakeNode = fn(u64) -> Node;
+
+pub fn default_palette() -> Vec<(&'static str, MakeNode)> {
+    vec![
+        ("sine", |_| Node::Oscillator {
+            voice: Box::new(Sine::new(440.0)),
+            ratio: 1.0,
+        }),
+        ("sine x2", |_| Node::Oscillator {
+            voice: Box::new(Sine::new(440.0)),
+            ratio: 2.0,
+        }),
+        ("saw", |_| Node::Oscillator {
+            voice: Box::new(Saw::new(440.0)),
+            ratio: 1.0,
+        }),
+        ("square", |_| Node::Oscillator {
+            voice: Box::new(Square::new(440.0)),
+            ratio: 1.0,
+        }),
+        ("triangle", |_| Node::Oscillator {
+            voice: Box::new(Triangle::new(440.0)),
+            ratio: 1.0,
+        }),
+        ("white noise", |seed| {
+            Node::Voice(Box::new(WhiteNoise::new(seed)))
+        }),
+        ("pink noise", |seed| {
+            Node::Voice(Box::new(PinkNoise::new(seed)))
+        }),
+        ("brown noise", |seed| {
+            Node::Voice(Box::new(BrownNoise::new(seed)))
+        }),
+        ("gate", |_| {
+            Node::Effect(Box::new(Gate {
+                cutoff_config: Config::new(0.5),
+            }))
+        }),
+        ("envelope", |_| {
+            Node::Voice(Box::new(Envelope::new(EnvelopeConfig::default())))
+        }),
+        ("amp envelope", |_| {
+            Node::Effect(Box::new(Envelope::new(EnvelopeConfig::default())))
+        }),
+        ("mixer", |_| Node::Mixer(0.5)),
+        ("delay", |_| Node::Delay),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sine() -> Node {
+        Node::Oscillator {
+            voice: Box::new(Sine::new(440.0)),
+            ratio: 1.0,
+        }
+    }
+
+    #[test]
+    fn follows_the_clients_edits() {
+        let mut graph = Graph::new(440.0);
+        let mut client = graph.get_client().unwrap();
+        let first = client.add(sine()).unwrap();
+        let second = client.add(sine()).unwrap();
+        client.remove(first).unwrap();
+        // Takes the slot the first sine left.
+        let third = client.add(Node::Mixer(0.5)).unwrap();
+        client.connect(second, third, Edge::Audio(1.0)).unwrap();
+        client
+            .connect(third, client.output(), Edge::Audio(1.0))
+            .unwrap();
+        graph.try_update_configs();
+
+        assert_eq!(graph.order.len(), 3);
+        assert_eq!(graph.graph[graph.order[1]].node.kind(), NodeKind::Mixer);
+        let signal: f32 = (0..100).map(|_| graph.generate().abs()).sum();
+        assert!(signal > 0.0);
+
+        // Everything the edits replaced comes back to be dropped.
+        assert_eq!(client.retired.len(), 6);
+        client.disconnect(second, third).unwrap();
+        assert_eq!(client.retired.len(), 0);
+    }
+
+    #[test]
+    fn refuses_cycles_and_overflow() {
+        let mut graph = Graph::new(440.0);
+        let mut client = graph.get_client().unwrap();
+        let a = client.add(Node::Mixer(1.0)).unwrap();
+        let b = client.add(Node::Mixer(1.0)).unwrap();
+        client.connect(a, b, Edge::Audio(1.0)).unwrap();
+        assert!(matches!(
+            client.connect(b, a, Edge::Audio(1.0)),
+            Err(GraphError::Cycle(_))
+        ));
+
+        // Until the audio thread runs, the edits wait in the queue.
+        let error = (0..QUEUED_EDITS).find_map(|_| client.disconnect(a, b).err());
+        assert_eq!(error, Some(GraphError::Busy));
+        graph.try_update_configs();
+        let error = (0..MAX_NODES).find_map(|_| {
+            let added = client.add(Node::Delay);
+            graph.try_update_configs();
+            added.err()
+        });
+        assert_eq!(error, Some(GraphError::Full));
+    }
+}
@@ -1,19 +1,28 @@
 use std::{
     rc::Rc,
     sync::{Arc, LockResult, Mutex, MutexGuard, RwLock},
-    time::Duration,
+    time::{Duration, Instant},
 };
 
-use chain::Chain;
-use combinators::{TwoChannel, TwoChannelConfig};
-use config::Config;
+use audio::{Audio, AudioOptions};
+use chain::{Chain, Voice};
+use cli::Command;
+use combinators::{MixerClient, TwoChannel, TwoChannelConfig};
+use config::{Config, JoinClient};
 use cpal::{traits::StreamTrait, Sample};
 use crossterm::{
     event::{self, read, EventStream},
     terminal::{disable_raw_mode, enable_raw_mode},
 };
 use effects::{Gate, LowPassFilter, FM};
+use envelope::{Envelope, EnvelopeClient, EnvelopeConfig};
+use graph::{Edge, Graph, Node};
+use midi::{CcMap, MidiFile, MidiPlayer};
+use patch::Patch;
+use preset::{PresetBank, SharedPersist};
+use stereo::Panned;
 use synth::Synth;
+use tuning::Tuning;
 
 use tui::layout::Direction;
 use ui::{
@@ -25,66 +34,400 @@ use voices::{Chained, Sine};
 
 use crate::{
     config::HasConfig,
-    controllers::{KBCConfig, KeyboardController},
+    controllers::{
+        KBCConfig, KBConfigAction, KeyboardController, PolyKeyboardController, StealPolicy,
+    },
+    offline::{Offline, Script, WavFormat},
     ui::{
         components::{
-            AdditiveComponent, KeyboardInputComponent, MixerComponent, NavigationContainer,
+            AdditiveComponent, EnvelopeComponent, GraphComponent, KeyboardInputComponent,
+            MixerComponent, NavigationContainer, PresetComponent, TransportComponent,
         },
-        input::parse_input_event,
+        input::KeyTracker,
     },
-    voices::Additive,
+    voices::{Additive, AdditiveClient},
 };
 
 mod audio;
 mod chain;
+mod cli;
 mod combinators;
 mod config;
 mod controllers;
 mod effects;
+mod envelope;
+mod graph;
+mod midi;
+#[cfg(target_os = "linux")]
+mod midi_input;
+mod noise;
+mod offline;
+mod oscillators;
+mod patch;
+mod preset;
+mod sampler;
+mod sf2;
+mod sfz;
+mod stereo;
 mod synth;
+mod tuning;
 mod ui;
 mod voices;
+mod wavetable;
 
 fn main() {
-    let mut additive = Additive::new(440.0, vec![2.0, 4.0, 6.0, 8.0]);
-    let additive_client = additive.config.get_client().unwrap();
-    let mixer_client = additive.mixer.config.get_client().unwrap();
-    let fm = FM::new(additive, Sine::new(440.0));
-    let mut ctrl = KeyboardController::new(fm);
-    let ctrl_client = ctrl.config.get_client().unwrap();
+    let cli = match cli::parse_args(std::env::args().skip(1)) {
+        Ok(cli) => cli,
+        Err(err) => {
+            eprintln!("{}\n{}", err, cli::USAGE);
+            std::process::exit(2);
+        }
+    };
+
+    let tuning = load_tuning_or_exit(&cli);
+    match cli.command {
+        Command::ListDevices => list_devices(),
+        Command::Render { path, format } => render(
+            &path,
+            format,
+            cli.audio.sample_rate.unwrap_or(44100),
+            cli.patch.as_deref(),
+            cli.midi.as_deref(),
+            tuning,
+        ),
+        Command::Play => play(
+            &cli.audio,
+            cli.patch.as_deref(),
+            cli.presets.as_deref().unwrap_or("presets"),
+            cli.midi.as_deref(),
+            tuning,
+        ),
+    }
+}
+
+fn load_tuning_or_exit(cli: &cli::Cli) -> Tuning {
+    let a4 = cli.a4.unwrap_or(440.0);
+    let name = cli.tuning.as_deref().unwrap_or("12");
+    let mut tuning = match name.trim_end_matches("edo").parse::<usize>() {
+        Ok(divisions) if divisions > 0 => Tuning::equal(divisions, a4),
+        _ if name == "just" => Tuning::just(a4),
+        _ => Tuning::load_scala(name, a4).unwrap_or_else(|err| {
+            eprintln!("{}: {}", name, err);
+            std::process::exit(1);
+        }),
+    };
+
+    if let Some(kbm) = cli.kbm.as_deref() {
+        if let Err(err) = tuning.load_keyboard_mapping(kbm) {
+            eprintln!("{}: {}", kbm, err);
+            std::process::exit(1);
+        }
+        // The mapping's reference may not be A4, so an explicit A4 moves it
+        // rather than replacing it.
+        if let Some(a4) = cli.a4 {
+            if let Err(err) = tuning.set_a4(a4) {
+                eprintln!("--a4: {}", err);
+                std::process::exit(1);
+            }
+        }
+    }
+    tuning
+}
+
+fn play(
+    options: &AudioOptions,
+    patch: Option<&str>,
+    presets: &str,
+    midi: Option<&str>,
+    tuning: Tuning,
+) {
+    let audio = Audio::with_options(options)
+        .or_else(|err| {
+            eprintln!("{}, falling back to the default output device", err);
+            Audio::new()
+        })
+        .unwrap_or_else(|err| {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        });
+
+    let mut patch = match patch {
+        Some(path) => load_patch_or_exit(path, &tuning),
+        None => built_in_patch(),
+    };
+
+    let mut synth = Synth::new(audio);
+    let cc_map = Arc::new(patch.cc_map);
 
-    let mut synth = Synth::new();
-    synth.play(ctrl);
+    #[cfg(target_os = "linux")]
+    match midi_input::spawn(
+        patch.keyboard_clients.clone(),
+        Arc::clone(&cc_map),
+        tuning.clone(),
+        Arc::clone(&synth.status),
+    ) {
+        Ok(address) => eprintln!("midi input on {}", address),
+        Err(err) => eprintln!("no midi input: {}", err),
+    }
+
+    let voice = match midi {
+        Some(path) => {
+            let mut player = MidiPlayer::new(
+                patch.voice,
+                load_midi_or_exit(path, &tuning),
+                patch.keyboard_queues,
+                midi::spawn_controls(cc_map, Arc::clone(&synth.status)),
+            );
+            patch.components.push(Box::new(TransportComponent::new(
+                Arc::new(Mutex::new(player.config.get_client().unwrap())),
+                player.position(),
+                player.length(),
+            )));
+            Box::new(player)
+        }
+        None => patch.voice,
+    };
+
+    let output = Chained::new(Panned::new(voice, patch.pan), patch.stereo);
+    if let Err(err) = synth.play(output) {
+        eprintln!("{}", err);
+        std::process::exit(1);
+    }
 
+    patch
+        .components
+        .push(Box::new(PresetComponent::new(PresetBank::new(
+            presets,
+            patch.configs,
+        ))));
     let ui_model = UIModel::new(
-        KeyboardInputComponent {
-            controller_clients: vec![ctrl_client],
-        },
-        NavigationContainer::new(
-            vec![
-                Box::new(MixerComponent {
-                    client: mixer_client,
-                }) as Box<dyn UIComponent + Send + 'static>,
-                Box::new(AdditiveComponent {
-                    client: additive_client,
-                }) as Box<dyn UIComponent + Send + 'static>,
-            ],
-            Direction::Horizontal,
-        ),
+        KeyboardInputComponent::new(patch.keyboard_clients, patch.active_voices, tuning),
+        NavigationContainer::new(patch.components, Direction::Horizontal),
+        Arc::clone(&synth.status),
     );
     start(ui_model);
+
+    loop {
+        synth.poll_events();
+        std::thread::sleep(Duration::from_millis(100));
+    }
+}
+
+fn load_patch_or_exit(path: &str, tuning: &Tuning) -> Patch {
+    patch::load_patch(path, tuning).unwrap_or_else(|err| {
+        eprintln!("{}: {}", path, err);
+        std::process::exit(1);
+    })
+}
+
+fn load_midi_or_exit(path: &str, tuning: &Tuning) -> MidiFile {
+    midi::load_midi(path, tuning).unwrap_or_else(|err| {
+        eprintln!("{}: {}", path, err);
+        std::process::exit(1);
+    })
+}
+
+fn built_in_patch() -> Patch {
+    let mut voices = Vec::new();
+    let mut clients: Option<(AdditiveClient, MixerClient, EnvelopeClient)> = None;
+    for _ in 0..8 {
+        let mut additive = Additive::new(440.0, vec![2.0, 4.0, 6.0, 8.0]);
+        let additive_client = additive.config.get_client().unwrap();
+        let mixer_client = additive.mixer.config.get_client().unwrap();
+        let mut envelope = Envelope::new(EnvelopeConfig::default());
+        let envelope_client = envelope.config.get_client().unwrap();
+        match clients.as_mut() {
+            Some((first_additive, first_mixer, first_envelope)) => {
+                first_additive.join(additive_client);
+                first_mixer.join(mixer_client);
+                first_envelope.join(envelope_client);
+            }
+            None => clients = Some((additive_client, mixer_client, envelope_client)),
+        }
+        voices.push(Chained::new(FM::new(additive, Sine::new(440.0)), envelope));
+    }
+    let (additive_client, mixer_client, envelope_client) = clients.unwrap();
+    let additive_client = Arc::new(Mutex::new(additive_client));
+    let mixer_client = Arc::new(Mutex::new(mixer_client));
+    let envelope_client = Arc::new(Mutex::new(envelope_client));
+    let mut ctrl = PolyKeyboardController::new(voices, StealPolicy::Oldest);
+    let ctrl_client = Arc::new(Mutex::new(ctrl.config.get_client().unwrap()));
+    let active_voices = ctrl.active_voices();
+    let ctrl_queue = ctrl.action_queue();
+
+    let mut graph = Graph::new(440.0);
+    let mut graph_client = graph.get_client().unwrap();
+    let sine = graph_client
+        .add(Node::Oscillator {
+            voice: Box::new(Sine::new(440.0)),
+            ratio: 1.0,
+        })
+        .unwrap();
+    graph_client
+        .connect(sine, graph_client.output(), Edge::Audio(1.0))
+        .unwrap();
+    let mut graph_ctrl = KeyboardController::new(graph);
+    let graph_ctrl_client = Arc::new(Mutex::new(graph_ctrl.config.get_client().unwrap()));
+    let graph_ctrl_queue = graph_ctrl.action_queue();
+
+    let mut mix = TwoChannel::new(ctrl, graph_ctrl);
+    let mix_client = Arc::new(Mutex::new(mix.config.get_client().unwrap()));
+
+    let configs = vec![
+        ("keys".to_string(), ctrl_client.clone() as SharedPersist),
+        (
+            "graph_keys".to_string(),
+            graph_ctrl_client.clone() as SharedPersist,
+        ),
+        ("mix".to_string(), mix_client.clone() as SharedPersist),
+        (
+            "additive".to_string(),
+            additive_client.clone() as SharedPersist,
+        ),
+        (
+            "additive.mixer".to_string(),
+            mixer_client.clone() as SharedPersist,
+        ),
+        (
+            "envelope".to_string(),
+            envelope_client.clone() as SharedPersist,
+        ),
+    ];
+    // The mod wheel brings in the first overtone.
+    let mut cc_map = CcMap::new();
+    cc_map
+        .add(1, "additive.mixer.channels.1", 0.0, 1.0, &configs)
+        .unwrap();
+
+    Patch {
+        voice: Box::new(mix),
+        pan: 0.0,
+        stereo: Chain::new(),
+        components: vec![
+            Box::new(TwoChannelComponent {
+                client: mix_client.clone(),
+                selected_channel: 0,
+            }) as Box<dyn UIComponent + Send + 'static>,
+            Box::new(MixerComponent {
+                client: mixer_client.clone(),
+            }) as Box<dyn UIComponent + Send + 'static>,
+            Box::new(AdditiveComponent {
+                client: additive_client.clone(),
+            }) as Box<dyn UIComponent + Send + 'static>,
+            Box::new(EnvelopeComponent::new(envelope_client.clone()))
+                as Box<dyn UIComponent + Send + 'static>,
+            Box::new(GraphComponent::new(graph_client, graph::default_palette()))
+                as Box<dyn UIComponent + Send + 'static>,
+        ],
+        keyboard_clients: vec![ctrl_client.clone(), graph_ctrl_client.clone()],
+        keyboard_queues: vec![ctrl_queue, graph_ctrl_queue],
+        configs,
+        active_voices: vec![active_voices],
+        cc_map,
+    }
+}
+
+fn list_devices() {
+    let (devices, failed) = audio::list_output_devices();
+    for (host, err) in failed {
+        eprintln!("{}: {}", host, err);
+    }
+
+    for device in devices {
+        println!("{}: {}", device.host, device.name);
+        for config in device.configs {
+            println!(
+                "    {:?} {}ch {}-{}Hz buffer {:?}",
+                config.sample_format(),
+                config.channels(),
+                config.min_sample_rate().0,
+                config.max_sample_rate().0,
+                config.buffer_size()
+            );
+        }
+    }
+}
+
+fn render(
+    path: &str,
+    format: WavFormat,
+    sample_rate: u32,
+    patch: Option<&str>,
+    midi: Option<&str>,
+    tuning: Tuning,
+) {
+    let mut offline = Offline::new(sample_rate);
+    let mut voice: Box<dyn Voice<f32> + Send> = match patch {
+        Some(patch) => {
+            let patch = load_patch_or_exit(patch, &tuning);
+            patch
+                .keyboard_clients
+                .into_iter()
+                .for_each(|client| offline.add_client(client));
+            offline.set_cc_map(patch.cc_map);
+            patch.voice
+        }
+        None => {
+            let additive = Additive::new(440.0, vec![2.0, 4.0, 6.0, 8.0]);
+            let fm = FM::new(additive, Sine::new(440.0));
+            let mut ctrl = KeyboardController::new(fm);
+            offline.add_client(Arc::new(Mutex::new(ctrl.config.get_client().unwrap())));
+            Box::new(ctrl)
+        }
+    };
+
+    let mut script = Script::new();
+    let seconds = match midi {
+        Some(midi) => {
+            let midi = load_midi_or_exit(midi, &tuning);
+            for (time, action) in midi.events {
+                script.add(time, action);
+            }
+            for (time, cc, value) in midi.controls {
+                script.add_control(time, cc, value);
+            }
+            // Leave room for the last notes to release.
+            midi.length + 2.0
+        }
+        None => {
+            let notes = [60, 62, 64, 65, 67]
+                .iter()
+                .filter_map(|note| tuning.hz(*note));
+            for (i, hz) in notes.enumerate() {
+                script.add(i as f32 * 0.5, KBConfigAction::Play(hz));
+                script.add(i as f32 * 0.5 + 0.45, KBConfigAction::Stop(hz));
+            }
+            3.0
+        }
+    };
+
+    let frames = (seconds * sample_rate as f32) as usize;
+    offline
+        .render_to_wav(&mut voice, &script, frames, path, format)
+        .unwrap();
 }
 
 fn start<C: UIComponent + Send + 'static>(ui_model: UIModel<C>) {
     let ui_model = Arc::new(Mutex::new(ui_model));
     let ui_model_arc_copy = Arc::clone(&ui_model);
     let (sender, receiver) = std::sync::mpsc::channel();
-    std::thread::spawn(move || loop {
-        let event = read();
-        event
-            .ok()
-            .and_then(parse_input_event)
-            .and_then(|e| sender.send(e).ok());
+    input::request_key_releases();
+    std::thread::spawn(move || {
+        let mut keys = KeyTracker::new(KeyboardInputComponent::plays);
+        loop {
+            let timeout = keys
+                .next_deadline()
+                .map(|deadline| deadline.saturating_duration_since(Instant::now()))
+                .unwrap_or(Duration::from_millis(100));
+            if event::poll(timeout).unwrap_or(false) {
+                if let Some(e) = read().ok().and_then(|e| keys.track(e, Instant::now())) {
+                    sender.send(e).ok();
+                }
+            }
+            for e in keys.expire(Instant::now()) {
+                sender.send(e).ok();
+            }
+        }
     });
 
     std::thread::spawn(move || {
@@ -101,8 +444,4 @@ fn start<C: UIComponent + Send + 'static>(ui_model: UIModel<C>) {
             std::thread::sleep(Duration::from_millis(17));
         }
     });
-
-    loop {
-        std::thread::sleep(Duration::from_secs(1));
-    }
 }
@@ -0,0 +1,526 @@
+use std::{
+    fmt, fs, io,
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+use crossbeam::{
+    atomic::AtomicCell,
+    channel::{self, Sender},
+};
+use midly::{Format, MetaMessage, MidiMessage, Smf, Timing, TrackEventKind};
+
+use crate::{
+    chain::{ProcessContext, Voice},
+    config::{ComposeConfig, ComposeConfigClient, ConfigReceiver, HasConfig},
+    controllers::{KBActionQueue, KBConfigAction},
+    preset::SharedPersist,
+    synth::StreamStatus,
+    tuning::Tuning,
+};
+
+/// Pitch bend range in semitones either way.
+const BEND_RANGE: f32 = 2.0;
+
+#[derive(Debug)]
+pub enum MidiError {
+    Io(io::Error),
+    Parse(midly::Error),
+    Unsupported(&'static str),
+}
+
+impl fmt::Display for MidiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MidiError::Io(err) => write!(f, "{}", err),
+            MidiError::Parse(err) => write!(f, "{}", err),
+            MidiError::Unsupported(message) => write!(f, "unsupported midi file: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for MidiError {}
+
+/// The controller actions of a Standard MIDI File, timed in seconds. All
+/// channels and tracks are merged onto the keyboard. `controls` has every
+/// control change as `(seconds, cc, value)`, for a `CcMap`.
+pub struct MidiFile {
+    pub events: Vec<(f32, KBConfigAction)>,
+    pub controls: Vec<(f32, u8, u8)>,
+    pub length: f32,
+}
+
+pub fn load_midi<P: AsRef<Path>>(path: P, tuning: &Tuning) -> Result<MidiFile, MidiError> {
+    let bytes = fs::read(path).map_err(MidiError::Io)?;
+    parse_midi(&bytes, tuning)
+}
+
+pub fn parse_midi(bytes: &[u8], tuning: &Tuning) -> Result<MidiFile, MidiError> {
+    let smf = Smf::parse(bytes).map_err(MidiError::Parse)?;
+    if smf.header.format == Format::Sequential {
+        return Err(MidiError::Unsupported("format 2 files are not supported"));
+    }
+
+    // Tempo changes can come from any track, so every event is put on one
+    // timeline in ticks before converting to seconds.
+    let mut timeline = Vec::new();
+    for track in smf.tracks.iter() {
+        let mut tick = 0u64;
+        for event in track.iter() {
+            tick += event.delta.as_int() as u64;
+            timeline.push((tick, event.kind));
+        }
+    }
+    timeline.sort_by_key(|(tick, _)| *tick);
+
+    let mut events = Vec::new();
+    let mut controls = Vec::new();
+    let mut tempo = 500_000.0;
+    let mut last_tick = 0;
+    let mut seconds = 0.0f64;
+    for (tick, kind) in timeline {
+        seconds += match smf.header.timing {
+            Timing::Metrical(ticks_per_beat) => {
+                (tick - last_tick) as f64 * tempo / 1_000_000.0 / ticks_per_beat.as_int() as f64
+            }
+            Timing::Timecode(fps, subframes) => {
+                (tick - last_tick) as f64 / (fps.as_f32() as f64 * subframes as f64)
+            }
+        };
+        last_tick = tick;
+
+        let time = seconds as f32;
+        match kind {
+            TrackEventKind::Meta(MetaMessage::Tempo(micros)) => tempo = micros.as_int() as f64,
+            TrackEventKind::Midi { message, .. } => {
+                events.extend(
+                    message_actions(message, tuning)
+                        .into_iter()
+                        .map(|action| (time, action)),
+                );
+                if let MidiMessage::Controller { controller, value } = message {
+                    controls.push((time, controller.as_int(), value.as_int()));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    Ok(MidiFile {
+        events,
+        controls,
+        length: seconds as f32,
+    })
+}
+
+/// The keyboard actions for a channel message. Control changes other than
+/// sustain and the channel mode messages are left to a `CcMap`. Notes the
+/// tuning leaves unmapped are dropped.
+pub fn message_actions(message: MidiMessage, tuning: &Tuning) -> Vec<KBConfigAction> {
+    match message {
+        MidiMessage::NoteOn { key, vel } if vel.as_int() > 0 => match tuning.hz(key.as_int()) {
+            Some(hz) => vec![
+                KBConfigAction::Velocity(vel.as_int() as f32 / 127.0),
+                KBConfigAction::Play(hz),
+            ],
+            None => Vec::new(),
+        },
+        MidiMessage::NoteOn { key, .. } | MidiMessage::NoteOff { key, .. } => tuning
+            .hz(key.as_int())
+            .map(KBConfigAction::Stop)
+            .into_iter()
+            .collect(),
+        MidiMessage::PitchBend { bend } => {
+            let semitones = bend.as_f32() * BEND_RANGE;
+            vec![KBConfigAction::Bend(2.0f32.powf(semitones / 12.0))]
+        }
+        MidiMessage::Controller { controller, value } => match controller.as_int() {
+            64 => vec![KBConfigAction::Sustain(value.as_int() >= 64)],
+            121 => vec![KBConfigAction::Sustain(false), KBConfigAction::Bend(1.0)],
+            120 | 123 => vec![KBConfigAction::StopAll],
+            _ => Vec::new(),
+        },
+        _ => Vec::new(),
+    }
+}
+
+struct CcTarget {
+    cc: u8,
+    client: SharedPersist,
+    path: String,
+    min: f32,
+    max: f32,
+}
+
+/// Routes MIDI control changes to config parameters. A target is the name a
+/// config was registered under followed by one of its parameters, like
+/// `envelope.attack` or `additive.mixer.channels.0`.
+pub struct CcMap {
+    targets: Vec<CcTarget>,
+}
+
+impl CcMap {
+    pub fn new() -> Self {
+        Self {
+            targets: Vec::new(),
+        }
+    }
+
+    /// Maps `cc` onto `target`, scaling its 0-127 range to `min..max`.
+    pub fn add(
+        &mut self,
+        cc: u8,
+        target: &str,
+        min: f32,
+        max: f32,
+        configs: &[(String, SharedPersist)],
+    ) -> Result<(), String> {
+        // Config names can contain dots themselves, so take the longest match.
+        let (name, client) = configs
+            .iter()
+            .filter(|(name, _)| target.starts_with(&format!("{}.", name)))
+            .max_by_key(|(name, _)| name.len())
+            .ok_or_else(|| format!("no config for '{}'", target))?;
+        let path = target[name.len() + 1..].to_string();
+        if client.lock().unwrap().parameter(&path).is_none() {
+            return Err(format!("'{}' has no parameter '{}'", name, path));
+        }
+
+        self.targets.push(CcTarget {
+            cc,
+            client: Arc::clone(client),
+            path,
+            min,
+            max,
+        });
+        Ok(())
+    }
+
+    /// Sets every target of `cc`, going on past any that refuse the value.
+    /// The first refusal is returned.
+    pub fn apply(&self, cc: u8, value: u8) -> Result<(), String> {
+        let amount = value as f32 / 127.0;
+        let mut result = Ok(());
+        for target in self.targets.iter().filter(|target| target.cc == cc) {
+            let value = target.min + (target.max - target.min) * amount;
+            let set = target
+                .client
+                .lock()
+                .unwrap()
+                .set_parameter(&target.path, value);
+            if let Err(message) = set {
+                if result.is_ok() {
+                    result = Err(format!("cc {}: {}", cc, message));
+                }
+            }
+        }
+        result
+    }
+}
+
+/// Applies control changes sent from the audio thread, which mustn't wait on
+/// the clients the UI shares, on a thread of their own. Errors are shown in
+/// the status line.
+pub fn spawn_controls(cc_map: Arc<CcMap>, status: Arc<Mutex<StreamStatus>>) -> Sender<(u8, u8)> {
+    let (sender, receiver) = channel::bounded(256);
+    std::thread::spawn(move || {
+        for (cc, value) in receiver.iter() {
+            if let Err(err) = cc_map.apply(cc, value) {
+                status.lock().unwrap().last_error = Some(err);
+            }
+        }
+    });
+    sender
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct TransportConfig {
+    pub playing: bool,
+    pub looping: bool,
+    /// Counts rewinds, so one is never lost between two config updates.
+    rewinds: u64,
+}
+
+#[derive(Clone, Copy)]
+pub enum TransportAction {
+    TogglePlay,
+    ToggleLoop,
+    Rewind,
+}
+
+fn reduce_transport_action(
+    mut config: TransportConfig,
+    action: TransportAction,
+) -> TransportConfig {
+    match action {
+        TransportAction::TogglePlay => config.playing = !config.playing,
+        TransportAction::ToggleLoop => config.looping = !config.looping,
+        TransportAction::Rewind => config.rewinds += 1,
+    }
+    config
+}
+
+pub type TransportClient = ComposeConfigClient<
+    TransportConfig,
+    TransportAction,
+    fn(TransportConfig, TransportAction) -> TransportConfig,
+>;
+
+/// Plays a `MidiFile` into keyboard controllers' action queues from the
+/// audio thread. Blocks are split at event frames so every action lands on the
+/// sample it's scheduled for.
+pub struct MidiPlayer<V: Voice<f32>> {
+    voice: V,
+    events: Vec<(f32, KBConfigAction)>,
+    controls: Vec<(f32, u8, u8)>,
+    length: f32,
+    queues: Vec<KBActionQueue>,
+    cc_sender: Sender<(u8, u8)>,
+    next_event: usize,
+    next_control: usize,
+    frame: usize,
+    sample_rate: f32,
+    playing: bool,
+    rewinds: u64,
+    position: Arc<AtomicCell<f32>>,
+    pub config: ComposeConfig<
+        TransportConfig,
+        TransportAction,
+        fn(TransportConfig, TransportAction) -> TransportConfig,
+    >,
+}
+
+impl<V: Voice<f32>> MidiPlayer<V> {
+    pub fn new(
+        voice: V,
+        midi: MidiFile,
+        queues: Vec<KBActionQueue>,
+        cc_sender: Sender<(u8, u8)>,
+    ) -> Self {
+        let mut events = midi.events;
+        events.sort_by(|(a, _), (b, _)| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+        let mut controls = midi.controls;
+        controls.sort_by(|(a, ..), (b, ..)| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+
+        Self {
+            voice,
+            events,
+            controls,
+            length: midi.length,
+            queues,
+            cc_sender,
+            next_event: 0,
+            next_control: 0,
+            frame: 0,
+            sample_rate: ProcessContext::default().sample_rate,
+            playing: true,
+            rewinds: 0,
+            position: Arc::new(AtomicCell::new(0.0)),
+            config: ComposeConfig::new(
+                TransportConfig {
+                    playing: true,
+                    looping: false,
+                    rewinds: 0,
+                },
+                reduce_transport_action,
+            ),
+        }
+    }
+
+    /// Playback position in seconds, updated from the audio thread.
+    pub fn position(&self) -> Arc<AtomicCell<f32>> {
+        Arc::clone(&self.position)
+    }
+
+    pub fn length(&self) -> f32 {
+        self.length
+    }
+
+    fn send(&self, action: KBConfigAction) {
+        for queue in self.queues.iter() {
+            // The voice drains the queues every time it's sent something, so
+            // one only fills up if its controller isn't being played, and
+            // then there's nobody to hear the action anyway.
+            let _ = queue.push(action);
+        }
+    }
+
+    fn to_frame(&self, seconds: f32) -> usize {
+        (seconds * self.sample_rate).round() as usize
+    }
+
+    fn rewind(&mut self) {
+        self.next_event = 0;
+        self.next_control = 0;
+        self.frame = 0;
+        self.send(KBConfigAction::StopAll);
+        self.send(KBConfigAction::Sustain(false));
+        self.send(KBConfigAction::Bend(1.0));
+        self.voice.try_update_configs();
+    }
+
+    // Returns whether anything was sent, so the voice only rereads its
+    // configs when it has to.
+    fn send_due(&mut self) -> bool {
+        let start = self.next_event;
+        while let Some((seconds, action)) = self.events.get(self.next_event) {
+            if self.to_frame(*seconds) > self.frame {
+                break;
+            }
+            self.send(*action);
+            self.next_event += 1;
+        }
+        self.next_event > start
+    }
+
+    // Control changes land within a block of their time, since the CcMap
+    // applies them on another thread anyway.
+    fn send_controls(&mut self, until_frame: usize) {
+        while let Some((seconds, cc, value)) = self.controls.get(self.next_control) {
+            if self.to_frame(*seconds) > until_frame {
+                break;
+            }
+            // A full channel means the control thread is behind, and a
+            // dropped change is better than a stalled audio thread.
+            let _ = self.cc_sender.try_send((*cc, *value));
+            self.next_control += 1;
+        }
+    }
+}
+
+impl<V: Voice<f32>> ConfigReceiver for MidiPlayer<V> {
+    fn try_update_configs(&mut self) {
+        self.config.try_update();
+        let transport = *self.config.get();
+        if transport.rewinds != self.rewinds {
+            self.rewinds = transport.rewinds;
+            self.rewind();
+        }
+        if self.playing && !transport.playing {
+            self.send(KBConfigAction::StopAll);
+        }
+        self.playing = transport.playing;
+        self.voice.try_update_configs();
+    }
+}
+
+impl<V: Voice<f32>> Voice<f32> for MidiPlayer<V> {
+    fn generate(&mut self) -> f32 {
+        let mut sample = [0.0];
+        self.generate_block(&mut sample);
+        sample[0]
+    }
+
+    fn generate_block(&mut self, block: &mut [f32]) {
+        let looping = self.config.get().looping;
+        let end_frame = self.to_frame(self.length);
+        let mut start = 0;
+
+        while start < block.len() {
+            if !self.playing {
+                self.voice.generate_block(&mut block[start..]);
+                break;
+            }
+
+            if self.send_due() {
+                self.voice.try_update_configs();
+            }
+            self.send_controls(self.frame + block.len() - start);
+            let mut end = block.len();
+            if let Some((seconds, _)) = self.events.get(self.next_event) {
+                end = end.min(start + self.to_frame(*seconds) - self.frame);
+            }
+            if looping && end_frame > self.frame {
+                end = end.min(start + end_frame - self.frame);
+            }
+
+            self.voice.generate_block(&mut block[start..end]);
+            self.frame += end - start;
+            start = end;
+
+            if looping && end_frame > 0 && self.frame >= end_frame {
+                self.rewind();
+            }
+        }
+
+        self.position
+            .store((self.frame as f32 / self.sample_rate).min(self.length));
+    }
+
+    fn set_context(&mut self, context: ProcessContext) {
+        self.sample_rate = context.sample_rate;
+        self.voice.set_context(context);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{combinators::MixerConfig, config::Config, preset::SharedPersist};
+
+    // One track at 96 ticks a beat: A4 on with the mod wheel at 64, then off
+    // a beat later.
+    fn smf() -> Vec<u8> {
+        let track = [
+            0x00, 0x90, 0x45, 0x64, // note on
+            0x00, 0xb0, 0x01, 0x40, // cc 1
+            0x60, 0x80, 0x45, 0x00, // note off
+            0x00, 0xff, 0x2f, 0x00, // end of track
+        ];
+        let mut bytes = b"MThd\0\0\0\x06\0\0\0\x01\0\x60MTrk".to_vec();
+        bytes.extend_from_slice(&(track.len() as u32).to_be_bytes());
+        bytes.extend_from_slice(&track);
+        bytes
+    }
+
+    #[test]
+    fn parses_notes_and_controls() {
+        let midi = parse_midi(&smf(), &Tuning::default()).unwrap();
+        assert_eq!(midi.length, 0.5);
+        assert_eq!(midi.controls, vec![(0.0, 1, 64)]);
+        assert!(matches!(
+            midi.events.as_slice(),
+            [
+                (_, KBConfigAction::Velocity(_)),
+                (_, KBConfigAction::Play(hz)),
+                (time, KBConfigAction::Stop(_)),
+            ] if *hz == 440.0 && *time == 0.5
+        ));
+    }
+
+    #[test]
+    fn rejects_sequential_files() {
+        let mut bytes = smf();
+        bytes[9] = 2;
+        assert!(matches!(
+            parse_midi(&bytes, &Tuning::default()),
+            Err(MidiError::Unsupported(_))
+        ));
+        assert!(matches!(
+            parse_midi(b"MThd", &Tuning::default()),
+            Err(MidiError::Parse(_))
+        ));
+    }
+
+    #[test]
+    fn cc_sets_parameter() {
+        let mut config = Config::new(MixerConfig {
+            channels: vec![0.5, 0.5],
+        });
+        let client: SharedPersist = Arc::new(Mutex::new(config.get_client().unwrap()));
+        let configs = vec![("partials.mixer".to_string(), Arc::clone(&client))];
+
+        let mut cc_map = CcMap::new();
+        assert!(cc_map
+            .add(1, "partials.mixer.channels.2", 0.0, 1.0, &configs)
+            .is_err());
+        cc_map
+            .add(1, "partials.mixer.channels.1", 0.0, 2.0, &configs)
+            .unwrap();
+
+        cc_map.apply(1, 127).unwrap();
+        config.try_update();
+        assert_eq!(config.config.channels, vec![0.5, 2.0]);
+        assert_eq!(client.lock().unwrap().parameter("channels.1"), Some(2.0));
+    }
+}
@@ -0,0 +1,101 @@
+use std::{
+    ffi::CString,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use alsa::seq::{EvCtrl, EvNote, EventType, PortCap, PortType, Seq};
+use midly::{num::u7, MidiMessage, PitchBend};
+
+use crate::{
+    controllers::KeyboardControllerClient,
+    midi::{message_actions, CcMap},
+    synth::StreamStatus,
+    tuning::Tuning,
+};
+
+// What ALSA reports when events were dropped because we fell behind.
+const ENOSPC: i32 = 28;
+const MAX_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Opens a virtual ALSA sequencer input port for other clients to connect
+/// to, e.g. with `aconnect`, and plays everything sent to it. Returns the
+/// port's address. Errors reading or applying events are shown in the status
+/// line.
+pub fn spawn(
+    clients: Vec<Arc<Mutex<KeyboardControllerClient>>>,
+    cc_map: Arc<CcMap>,
+    tuning: Tuning,
+    status: Arc<Mutex<StreamStatus>>,
+) -> Result<String, alsa::Error> {
+    let name = CString::new("rsynth").unwrap();
+    let seq = Seq::open(None, Some(alsa::Direction::Capture), false)?;
+    seq.set_client_name(&name)?;
+    let port = seq.create_simple_port(
+        &name,
+        PortCap::WRITE | PortCap::SUBS_WRITE,
+        PortType::MIDI_GENERIC | PortType::APPLICATION,
+    )?;
+    let address = format!("{}:{}", seq.client_id()?, port);
+
+    std::thread::spawn(move || {
+        let mut input = seq.input();
+        let mut backoff = Duration::from_millis(10);
+        loop {
+            let message = match input.event_input() {
+                Ok(event) => {
+                    backoff = Duration::from_millis(10);
+                    match to_message(&event) {
+                        Some(message) => message,
+                        None => continue,
+                    }
+                }
+                // An overrun only loses events, the next read is fine.
+                Err(err) if err.errno().map(|errno| errno as i32) == Some(ENOSPC) => continue,
+                // Anything else would most likely fail again straight away.
+                Err(err) => {
+                    status.lock().unwrap().last_error = Some(format!("midi input: {}", err));
+                    std::thread::sleep(backoff);
+                    backoff = (backoff * 2).min(MAX_BACKOFF);
+                    continue;
+                }
+            };
+
+            for action in message_actions(message, &tuning) {
+                for client in clients.iter() {
+                    client.lock().unwrap().update(|_| action);
+                }
+            }
+            if let MidiMessage::Controller { controller, value } = message {
+                if let Err(err) = cc_map.apply(controller.as_int(), value.as_int()) {
+                    status.lock().unwrap().last_error = Some(err);
+                }
+            }
+        }
+    });
+
+    Ok(address)
+}
+
+fn to_message(event: &alsa::seq::Event) -> Option<MidiMessage> {
+    match event.get_type() {
+        EventType::Noteon => event.get_data().map(|note: EvNote| MidiMessage::NoteOn {
+            key: u7::from(note.note),
+            vel: u7::from(note.velocity),
+        }),
+        EventType::Noteoff => event.get_data().map(|note: EvNote| MidiMessage::NoteOff {
+            key: u7::from(note.note),
+            vel: u7::from(note.velocity),
+        }),
+        EventType::Controller => event
+            .get_data()
+            .map(|ctrl: EvCtrl| MidiMessage::Controller {
+                controller: u7::from(ctrl.param as u8),
+                value: u7::from(ctrl.value as u8),
+            }),
+        EventType::Pitchbend => event.get_data().map(|ctrl: EvCtrl| MidiMessage::PitchBend {
+            bend: PitchBend::from_int(ctrl.value as i16),
+        }),
+        _ => None,
+    }
+}
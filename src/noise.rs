@@ -0,0 +1,275 @@
+use crate::{
+    chain::{ProcessContext, Voice},
+    config::ConfigReceiver,
+    oscillators::Phase,
+    voices::HasFreq,
+};
+
+/// A small seedable generator, so renders with the same seed come out the
+/// same. A 64-bit LCG, using the top bits which are the most random.
+#[derive(Clone, Copy, Debug)]
+pub struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        let mut rng = Self { state: seed };
+        // Mixes the seed in, so nearby seeds don't start out alike.
+        rng.next_u32();
+        rng
+    }
+
+    pub fn next_u32(&mut self) -> u32 {
+        self.state = self
+            .state
+            .wrapping_mul(6364136223846793005)
+            .wrapping_add(1442695040888963407);
+        (self.state >> 32) as u32
+    }
+
+    /// Uniform in [-1, 1).
+    pub fn next_signal(&mut self) -> f32 {
+        (self.next_u32() >> 8) as f32 / (1 << 23) as f32 - 1.0
+    }
+}
+
+/// Noise has no pitch, but keeps a frequency so it can sit under a keyboard
+/// controller like any other waveform, e.g. for percussion.
+pub struct WhiteNoise {
+    rng: Rng,
+    hz: f32,
+}
+
+impl WhiteNoise {
+    pub fn new(seed: u64) -> Self {
+        Self {
+            rng: Rng::new(seed),
+            hz: 440.0,
+        }
+    }
+}
+
+impl ConfigReceiver for WhiteNoise {
+    fn try_update_configs(&mut self) {}
+}
+
+impl HasFreq for WhiteNoise {
+    fn set_freq(&mut self, hz: f32) {
+        self.hz = hz;
+    }
+
+    fn get_freq(&self) -> f32 {
+        self.hz
+    }
+}
+
+impl Voice<f32> for WhiteNoise {
+    fn generate(&mut self) -> f32 {
+        self.rng.next_signal()
+    }
+}
+
+const PINK_ROWS: usize = 12;
+
+/// Pink noise by the Voss-McCartney method: rows of white noise, each updated
+/// half as often as the one before, summed.
+pub struct PinkNoise {
+    rng: Rng,
+    rows: [f32; PINK_ROWS],
+    sum: f32,
+    counter: u32,
+    hz: f32,
+}
+
+impl PinkNoise {
+    pub fn new(seed: u64) -> Self {
+        let mut rng = Rng::new(seed);
+        let mut rows = [0.0; PINK_ROWS];
+        for row in rows.iter_mut() {
+            *row = rng.next_signal();
+        }
+        Self {
+            rng,
+            sum: rows.iter().sum(),
+            rows,
+            counter: 0,
+            hz: 440.0,
+        }
+    }
+}
+
+impl ConfigReceiver for PinkNoise {
+    fn try_update_configs(&mut self) {}
+}
+
+impl HasFreq for PinkNoise {
+    fn set_freq(&mut self, hz: f32) {
+        self.hz = hz;
+    }
+
+    fn get_freq(&self) -> f32 {
+        self.hz
+    }
+}
+
+impl Voice<f32> for PinkNoise {
+    fn generate(&mut self) -> f32 {
+        self.counter = self.counter.wrapping_add(1);
+        // Row n changes every 2^n samples, and never two rows at once.
+        let row = self.counter.trailing_zeros() as usize;
+        if row < PINK_ROWS {
+            let new = self.rng.next_signal();
+            self.sum += new - self.rows[row];
+            self.rows[row] = new;
+        }
+        // The extra white row fills in the top octave. Scaled by the peak, when
+        // every row lines up, so it stays within [-1, 1] like white noise.
+        (self.sum + self.rng.next_signal()) / (PINK_ROWS + 1) as f32
+    }
+}
+
+/// Brown noise: white noise through a leaky integrator, so it wanders rather
+/// than jumps.
+pub struct BrownNoise {
+    rng: Rng,
+    level: f32,
+    hz: f32,
+}
+
+impl BrownNoise {
+    pub fn new(seed: u64) -> Self {
+        Self {
+            rng: Rng::new(seed),
+            level: 0.0,
+            hz: 440.0,
+        }
+    }
+}
+
+impl ConfigReceiver for BrownNoise {
+    fn try_update_configs(&mut self) {}
+}
+
+impl HasFreq for BrownNoise {
+    fn set_freq(&mut self, hz: f32) {
+        self.hz = hz;
+    }
+
+    fn get_freq(&self) -> f32 {
+        self.hz
+    }
+}
+
+impl Voice<f32> for BrownNoise {
+    fn generate(&mut self) -> f32 {
+        self.level = (self.level + 0.02 * self.rng.next_signal()) / 1.02;
+        self.level * 3.5
+    }
+}
+
+/// Holds its input's value, taking a new one `hz` times a second. Over noise
+/// this gives stepped random modulation.
+pub struct SampleAndHold<V> {
+    pub input: V,
+    phase: Phase,
+    held: f32,
+}
+
+impl<V: Voice<f32>> SampleAndHold<V> {
+    pub fn new(input: V, hz: f32) -> Self {
+        Self {
+            input,
+            phase: Phase::new(hz),
+            held: 0.0,
+        }
+    }
+}
+
+impl<V: ConfigReceiver> ConfigReceiver for SampleAndHold<V> {
+    fn try_update_configs(&mut self) {
+        self.input.try_update_configs();
+    }
+}
+
+impl<V> HasFreq for SampleAndHold<V> {
+    fn set_freq(&mut self, hz: f32) {
+        self.phase.hz = hz;
+    }
+
+    fn get_freq(&self) -> f32 {
+        self.phase.hz
+    }
+}
+
+impl<V: Voice<f32>> Voice<f32> for SampleAndHold<V> {
+    fn generate(&mut self) -> f32 {
+        // The input keeps running between samples, so it sounds the same
+        // however often it's sampled.
+        let signal = self.input.generate();
+        let (phase, dt) = self.phase.advance();
+        if phase < dt {
+            self.held = signal;
+        }
+        self.held
+    }
+
+    fn set_context(&mut self, context: ProcessContext) {
+        self.phase.sample_rate = context.sample_rate;
+        self.input.set_context(context);
+    }
+
+    fn note_on(&mut self, velocity: f32) {
+        self.input.note_on(velocity);
+    }
+
+    fn note_off(&mut self) {
+        self.input.note_off();
+    }
+
+    fn is_releasing(&self) -> bool {
+        self.input.is_releasing()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run<V: Voice<f32>>(voice: &mut V, samples: usize) -> Vec<f32> {
+        (0..samples).map(|_| voice.generate()).collect()
+    }
+
+    #[test]
+    fn seeds_repeat() {
+        let white = |seed| run(&mut WhiteNoise::new(seed), 1000);
+        let pink = |seed| run(&mut PinkNoise::new(seed), 1000);
+        let brown = |seed| run(&mut BrownNoise::new(seed), 1000);
+
+        assert_eq!(white(7), white(7));
+        assert_eq!(pink(7), pink(7));
+        assert_eq!(brown(7), brown(7));
+        assert_ne!(white(7), white(8));
+        assert_ne!(pink(7), pink(8));
+        assert_ne!(brown(7), brown(8));
+    }
+
+    #[test]
+    fn sample_and_hold_updates_at_its_rate() {
+        let mut held = SampleAndHold::new(WhiteNoise::new(1), 8.0);
+        held.set_context(ProcessContext {
+            sample_rate: 64.0,
+            ..ProcessContext::default()
+        });
+        let output = run(&mut held, 64);
+
+        // Eight steps of eight samples each, a fresh value every time.
+        let steps: Vec<&[f32]> = output.chunks(8).collect();
+        for step in steps.iter() {
+            assert!(step.iter().all(|sample| *sample == step[0]));
+        }
+        for pair in steps.windows(2) {
+            assert_ne!(pair[0][0], pair[1][0]);
+        }
+    }
+}
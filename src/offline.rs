@@ -0,0 +1,199 @@
+use std::{
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+use hound::{SampleFormat, WavReader, WavSpec, WavWriter};
+
+use crate::{
+    chain::{ProcessContext, Voice},
+    controllers::{KBConfigAction, KeyboardControllerClient},
+    midi::CcMap,
+    stereo::Frame,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WavFormat {
+    Int16,
+    Int24,
+    Float32,
+}
+
+impl WavFormat {
+    fn spec(self, sample_rate: u32, channels: u16) -> WavSpec {
+        let (bits_per_sample, sample_format) = match self {
+            WavFormat::Int16 => (16, SampleFormat::Int),
+            WavFormat::Int24 => (24, SampleFormat::Int),
+            WavFormat::Float32 => (32, SampleFormat::Float),
+        };
+
+        WavSpec {
+            channels,
+            sample_rate,
+            bits_per_sample,
+            sample_format,
+        }
+    }
+}
+
+/// A list of controller actions and MIDI control changes, each scheduled at
+/// a time in seconds from the start of the render.
+pub struct Script {
+    events: Vec<(f32, KBConfigAction)>,
+    controls: Vec<(f32, (u8, u8))>,
+}
+
+impl Script {
+    pub fn new() -> Self {
+        Script {
+            events: Vec::new(),
+            controls: Vec::new(),
+        }
+    }
+
+    pub fn add(&mut self, seconds: f32, action: KBConfigAction) {
+        self.events.push((seconds, action));
+    }
+
+    pub fn add_control(&mut self, seconds: f32, cc: u8, value: u8) {
+        self.controls.push((seconds, (cc, value)));
+    }
+}
+
+fn to_frames<T: Copy>(events: &[(f32, T)], sample_rate: u32) -> Vec<(usize, T)> {
+    let mut events: Vec<(usize, T)> = events
+        .iter()
+        .map(|(seconds, event)| ((seconds * sample_rate as f32).round() as usize, *event))
+        .collect();
+    events.sort_by_key(|(frame, _)| *frame);
+    events
+}
+
+/// Renders a voice without an audio device, pulling samples at a fixed rate
+/// and feeding scripted actions to the controller clients, and control
+/// changes to the `CcMap`, on exact frames.
+pub struct Offline {
+    pub sample_rate: u32,
+    pub block_size: usize,
+    clients: Vec<Arc<Mutex<KeyboardControllerClient>>>,
+    cc_map: CcMap,
+}
+
+impl Offline {
+    pub fn new(sample_rate: u32) -> Self {
+        Self {
+            sample_rate,
+            block_size: 512,
+            clients: Vec::new(),
+            cc_map: CcMap::new(),
+        }
+    }
+
+    pub fn add_client(&mut self, client: Arc<Mutex<KeyboardControllerClient>>) {
+        self.clients.push(client);
+    }
+
+    pub fn set_cc_map(&mut self, cc_map: CcMap) {
+        self.cc_map = cc_map;
+    }
+
+    pub fn render<S: Frame, V: Voice<S>>(
+        &mut self,
+        voice: &mut V,
+        script: &Script,
+        frames: usize,
+    ) -> Vec<S> {
+        voice.set_context(ProcessContext {
+            sample_rate: self.sample_rate as f32,
+            block_size: self.block_size,
+            channels: S::CHANNELS,
+        });
+
+        let events = to_frames(&script.events, self.sample_rate);
+        let controls = to_frames(&script.controls, self.sample_rate);
+        let mut next_event = 0;
+        let mut next_control = 0;
+        let mut output = vec![S::default(); frames];
+        let mut frame = 0;
+
+        while frame < frames {
+            while next_event < events.len() && events[next_event].0 <= frame {
+                let action = events[next_event].1;
+                for client in self.clients.iter() {
+                    client.lock().unwrap().update(|_| action);
+                }
+                next_event += 1;
+            }
+            while next_control < controls.len() && controls[next_control].0 <= frame {
+                let (cc, value) = controls[next_control].1;
+                if let Err(err) = self.cc_map.apply(cc, value) {
+                    eprintln!("{}", err);
+                }
+                next_control += 1;
+            }
+            voice.try_update_configs();
+
+            let mut end = (frame + self.block_size).min(frames);
+            if let Some((event_frame, _)) = events.get(next_event) {
+                end = end.min(*event_frame);
+            }
+            if let Some((control_frame, _)) = controls.get(next_control) {
+                end = end.min(*control_frame);
+            }
+            voice.generate_block(&mut output[frame..end]);
+            frame = end;
+        }
+
+        output
+    }
+
+    pub fn render_to_wav<S: Frame, V: Voice<S>, P: AsRef<Path>>(
+        &mut self,
+        voice: &mut V,
+        script: &Script,
+        frames: usize,
+        path: P,
+        format: WavFormat,
+    ) -> hound::Result<()> {
+        let samples = self.render(voice, script, frames);
+        write_wav(path, &samples, self.sample_rate, format)
+    }
+}
+
+/// Reads every sample of a WAV file, interleaved and scaled to [-1, 1].
+pub fn read_wav<P: AsRef<Path>>(path: P) -> hound::Result<(WavSpec, Vec<f32>)> {
+    let mut reader = WavReader::open(path)?;
+    let spec = reader.spec();
+    let samples = match spec.sample_format {
+        SampleFormat::Float => reader.samples::<f32>().collect::<Result<_, _>>(),
+        SampleFormat::Int => {
+            let scale = 1.0 / (1u32 << (spec.bits_per_sample - 1)) as f32;
+            reader
+                .samples::<i32>()
+                .map(|sample| sample.map(|sample| sample as f32 * scale))
+                .collect::<Result<_, _>>()
+        }
+    }?;
+    Ok((spec, samples))
+}
+
+pub fn write_wav<S: Frame, P: AsRef<Path>>(
+    path: P,
+    frames: &[S],
+    sample_rate: u32,
+    format: WavFormat,
+) -> hound::Result<()> {
+    let mut writer = WavWriter::create(path, format.spec(sample_rate, S::CHANNELS as u16))?;
+    let mut samples = vec![0.0; S::CHANNELS];
+    for frame in frames {
+        frame.write(&mut samples);
+        for sample in samples.iter().map(|s| s.clamp(-1.0, 1.0)) {
+            match format {
+                WavFormat::Int16 => writer.write_sample((sample * i16::MAX as f32) as i16)?,
+                WavFormat::Int24 => writer.write_sample((sample * 8_388_607.0) as i32)?,
+                WavFormat::Float32 => writer.write_sample(sample)?,
+            }
+        }
+    }
+    writer.finalize()
+}
@@ -0,0 +1,326 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    chain::{ProcessContext, Voice},
+    config::{Config, ConfigClient, ConfigReceiver},
+    preset::Persistent,
+    voices::HasFreq,
+};
+
+// PolyBLEP: a two-sample polynomial correction around a step, `t` being the
+// phase and `dt` the phase increment, both in cycles.
+fn poly_blep(t: f32, dt: f32) -> f32 {
+    if t < dt {
+        let x = t / dt;
+        x + x - x * x - 1.0
+    } else if t > 1.0 - dt {
+        let x = (t - 1.0) / dt;
+        x * x + x + x + 1.0
+    } else {
+        0.0
+    }
+}
+
+// The integral of `poly_blep`, for corners rather than steps.
+fn poly_blamp(t: f32, dt: f32) -> f32 {
+    if t < dt {
+        let x = t / dt - 1.0;
+        -x * x * x / 3.0
+    } else if t > 1.0 - dt {
+        let x = (t - 1.0) / dt + 1.0;
+        x * x * x / 3.0
+    } else {
+        0.0
+    }
+}
+
+/// A phase in cycles that wraps at 1.0, so it keeps full precision however
+/// long it runs and a new frequency takes effect on the next sample.
+pub(crate) struct Phase {
+    phase: f32,
+    pub hz: f32,
+    pub sample_rate: f32,
+}
+
+impl Phase {
+    pub fn new(hz: f32) -> Self {
+        Self {
+            phase: 0.0,
+            hz,
+            sample_rate: ProcessContext::default().sample_rate,
+        }
+    }
+
+    fn increment(&self) -> f32 {
+        (self.hz / self.sample_rate).abs().min(0.5)
+    }
+
+    /// Returns the current phase and moves on to the next sample.
+    pub fn advance(&mut self) -> (f32, f32) {
+        let (phase, dt) = (self.phase, self.increment());
+        self.phase = (self.phase + dt).fract();
+        (phase, dt)
+    }
+
+    /// Like `advance`, but runs backwards for negative frequencies, as
+    /// through-zero FM needs.
+    pub fn turn(&mut self) -> f32 {
+        let phase = self.phase;
+        self.phase = (self.phase + self.hz / self.sample_rate).rem_euclid(1.0);
+        phase
+    }
+}
+
+fn pulse(phase: f32, dt: f32, width: f32) -> f32 {
+    let naive = if phase < width { 1.0 } else { -1.0 };
+    naive + poly_blep(phase, dt) - poly_blep((phase + 1.0 - width).fract(), dt)
+}
+
+/// A rising sawtooth.
+pub struct Saw {
+    phase: Phase,
+}
+
+impl Saw {
+    pub fn new(hz: f32) -> Self {
+        Self {
+            phase: Phase::new(hz),
+        }
+    }
+}
+
+impl ConfigReceiver for Saw {
+    fn try_update_configs(&mut self) {}
+}
+
+impl HasFreq for Saw {
+    fn set_freq(&mut self, hz: f32) {
+        self.phase.hz = hz;
+    }
+
+    fn get_freq(&self) -> f32 {
+        self.phase.hz
+    }
+}
+
+impl Voice<f32> for Saw {
+    fn generate(&mut self) -> f32 {
+        let (phase, dt) = self.phase.advance();
+        2.0 * phase - 1.0 - poly_blep(phase, dt)
+    }
+
+    fn set_context(&mut self, context: ProcessContext) {
+        self.phase.sample_rate = context.sample_rate;
+    }
+}
+
+/// A square wave, i.e. a `Pulse` fixed at half width.
+pub struct Square {
+    phase: Phase,
+}
+
+impl Square {
+    pub fn new(hz: f32) -> Self {
+        Self {
+            phase: Phase::new(hz),
+        }
+    }
+}
+
+impl ConfigReceiver for Square {
+    fn try_update_configs(&mut self) {}
+}
+
+impl HasFreq for Square {
+    fn set_freq(&mut self, hz: f32) {
+        self.phase.hz = hz;
+    }
+
+    fn get_freq(&self) -> f32 {
+        self.phase.hz
+    }
+}
+
+impl Voice<f32> for Square {
+    fn generate(&mut self) -> f32 {
+        let (phase, dt) = self.phase.advance();
+        pulse(phase, dt, 0.5)
+    }
+
+    fn set_context(&mut self, context: ProcessContext) {
+        self.phase.sample_rate = context.sample_rate;
+    }
+}
+
+/// A triangle wave.
+pub struct Triangle {
+    phase: Phase,
+}
+
+impl Triangle {
+    pub fn new(hz: f32) -> Self {
+        Self {
+            phase: Phase::new(hz),
+        }
+    }
+}
+
+impl ConfigReceiver for Triangle {
+    fn try_update_configs(&mut self) {}
+}
+
+impl HasFreq for Triangle {
+    fn set_freq(&mut self, hz: f32) {
+        self.phase.hz = hz;
+    }
+
+    fn get_freq(&self) -> f32 {
+        self.phase.hz
+    }
+}
+
+impl Voice<f32> for Triangle {
+    fn generate(&mut self) -> f32 {
+        let (phase, dt) = self.phase.advance();
+        let naive = 1.0 - (4.0 * phase - 2.0).abs();
+        naive + 4.0 * dt * (poly_blamp(phase, dt) - poly_blamp((phase + 0.5).fract(), dt))
+    }
+
+    fn set_context(&mut self, context: ProcessContext) {
+        self.phase.sample_rate = context.sample_rate;
+    }
+}
+
+/// `width` is the fraction of each cycle spent high.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct PulseConfig {
+    pub width: f32,
+}
+
+impl Persistent for PulseConfig {
+    fn parameter(&mut self, path: &str) -> Option<&mut f32> {
+        match path {
+            "width" => Some(&mut self.width),
+            _ => None,
+        }
+    }
+}
+
+pub type PulseClient = ConfigClient<PulseConfig>;
+
+pub struct Pulse {
+    pub config: Config<PulseConfig>,
+    phase: Phase,
+}
+
+impl Pulse {
+    pub fn new(hz: f32, width: f32) -> Self {
+        Self {
+            config: Config::new(PulseConfig { width }),
+            phase: Phase::new(hz),
+        }
+    }
+}
+
+impl ConfigReceiver for Pulse {
+    fn try_update_configs(&mut self) {
+        self.config.try_update();
+    }
+}
+
+impl HasFreq for Pulse {
+    fn set_freq(&mut self, hz: f32) {
+        self.phase.hz = hz;
+    }
+
+    fn get_freq(&self) -> f32 {
+        self.phase.hz
+    }
+}
+
+impl Voice<f32> for Pulse {
+    fn generate(&mut self) -> f32 {
+        let (phase, dt) = self.phase.advance();
+        // Keep both edges at least a sample apart so the corrections can't
+        // overlap into a spike.
+        let width = self.config.config.width.max(dt).min(1.0 - dt);
+        pulse(phase, dt, width)
+    }
+
+    fn set_context(&mut self, context: ProcessContext) {
+        self.phase.sample_rate = context.sample_rate;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run<V: Voice<f32>>(mut voice: V, samples: usize) -> Vec<f32> {
+        (0..samples).map(|_| voice.generate()).collect()
+    }
+
+    #[test]
+    fn phase_stays_bounded_over_long_runs() {
+        for &hz in [440.0, 12345.67, -97.3].iter() {
+            let mut phase = Phase::new(hz);
+            for _ in 0..10_000_000 {
+                let turned = phase.turn();
+                assert!((0.0..1.0).contains(&turned), "{}: {}", hz, turned);
+            }
+            // Still on the pitch it started at, rather than smeared by
+            // rounding in a huge accumulator.
+            let (a, b) = (phase.turn(), phase.turn());
+            let step = (b - a).rem_euclid(1.0);
+            assert!((step - (hz / phase.sample_rate).rem_euclid(1.0)).abs() < 1e-4);
+        }
+    }
+
+    #[test]
+    fn stays_in_range() {
+        for &hz in [20.0, 440.0, 5000.0, 20000.0].iter() {
+            let outputs = vec![
+                run(Saw::new(hz), 4410),
+                run(Square::new(hz), 4410),
+                run(Triangle::new(hz), 4410),
+                run(Pulse::new(hz, 0.2), 4410),
+            ];
+            for output in outputs {
+                assert!(
+                    output.iter().all(|sample| sample.abs() <= 1.0 + 1e-4),
+                    "{}",
+                    hz
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn poly_blep_meets_the_step_halfway() {
+        let dt = 0.1;
+        assert_eq!(poly_blep(0.0, dt), -1.0);
+        assert_eq!(poly_blep(0.5, dt), 0.0);
+        assert!((poly_blep(1.0 - 1e-6, dt) - 1.0).abs() < 1e-4);
+
+        // The saw resets at its first sample, which lands midway through
+        // the drop instead of at the bottom of it.
+        let saw = run(Saw::new(4410.0), 11);
+        assert_eq!(saw[0], 0.0);
+        assert!(saw[1] < saw[2] && saw[9] > saw[8]);
+        assert!((saw[10] - saw[0]).abs() < 1e-4);
+    }
+
+    #[test]
+    fn pulse_keeps_both_edges_at_extreme_widths() {
+        for &width in [0.0, 1.0].iter() {
+            let output = run(Pulse::new(4410.0, width), 100);
+            assert!(output.iter().any(|sample| *sample > 0.0), "{}", width);
+            assert!(output.iter().any(|sample| *sample < 0.0), "{}", width);
+            assert!(
+                output.iter().all(|sample| sample.abs() <= 1.0 + 1e-4),
+                "{}",
+                width
+            );
+        }
+    }
+}
@@ -0,0 +1,853 @@
+use std::{
+    any::Any,
+    collections::{BTreeMap, HashMap, HashSet},
+    fmt, fs, io,
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+use crossbeam::atomic::AtomicCell;
+use serde::Deserialize;
+use toml::Spanned;
+
+use crate::{
+    chain::{Chain, Voice},
+    combinators::{MixerConfig, TwoChannel},
+    config::{Config, JoinClient},
+    controllers::{
+        KBActionQueue, KeyboardController, KeyboardControllerClient, PolyKeyboardController,
+        StealPolicy,
+    },
+    effects::{Gate, FM},
+    envelope::{Envelope, EnvelopeConfig},
+    midi::CcMap,
+    noise::{BrownNoise, PinkNoise, SampleAndHold, WhiteNoise},
+    oscillators::{Pulse, Saw, Square, Triangle},
+    preset::{Persist, SharedPersist},
+    sampler::{Interpolation, LoopMode, Sample, Sampler},
+    sf2,
+    sfz::{Instrument, MultiSampler},
+    stereo::{DualMono, Stereo},
+    tuning::Tuning,
+    ui::components::{
+        AdditiveComponent, EnvelopeComponent, MixerComponent, PulseComponent, TwoChannelComponent,
+        UIComponent, WavetableComponent,
+    },
+    voices::{Additive, AdditiveConfig, Chained, Sine, Waveform},
+    wavetable::{Table, Wavetable},
+};
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PatchSpec {
+    output: Spanned<String>,
+    /// Where the output sits, from -1.0 (left) to 1.0 (right).
+    pan: Option<Spanned<f32>>,
+    /// Gates each channel of the panned output on its own, like a `gate`
+    /// node.
+    gate: Option<Spanned<f32>>,
+    nodes: BTreeMap<String, NodeSpec>,
+    #[serde(default)]
+    cc: Vec<CcSpec>,
+}
+
+/// Maps a MIDI control change onto a parameter, given as a node name and a
+/// path into its config like `amp.release`. `min` and `max` default to 0 and 1.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CcSpec {
+    cc: u8,
+    target: Spanned<String>,
+    min: Option<f32>,
+    max: Option<f32>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct NodeSpec {
+    kind: Spanned<String>,
+    hz: Option<Spanned<f32>>,
+    fundamental: Option<Spanned<f32>>,
+    overtones: Option<Spanned<Vec<f32>>>,
+    volumes: Option<Spanned<Vec<f32>>>,
+    modulator: Option<Spanned<String>>,
+    carrier: Option<Spanned<String>>,
+    input: Option<Spanned<String>>,
+    a: Option<Spanned<String>>,
+    b: Option<Spanned<String>>,
+    a_mix: Option<Spanned<f32>>,
+    b_mix: Option<Spanned<f32>>,
+    cutoff: Option<Spanned<f32>>,
+    width: Option<Spanned<f32>>,
+    color: Option<Spanned<String>>,
+    seed: Option<Spanned<u64>>,
+    file: Option<Spanned<String>>,
+    position: Option<Spanned<f32>>,
+    root: Option<Spanned<u8>>,
+    loop_start: Option<Spanned<usize>>,
+    loop_end: Option<Spanned<usize>>,
+    looping: Option<Spanned<String>>,
+    interpolation: Option<Spanned<String>>,
+    bank: Option<Spanned<u16>>,
+    program: Option<Spanned<u16>>,
+    transpose: Option<Spanned<f32>>,
+    // Replaced by `transpose`, and only read to say so.
+    base_hz: Option<Spanned<f32>>,
+    attack: Option<Spanned<f32>>,
+    hold: Option<Spanned<f32>>,
+    decay: Option<Spanned<f32>>,
+    sustain: Option<Spanned<f32>>,
+    release: Option<Spanned<f32>>,
+    curve: Option<Spanned<f32>>,
+    voices: Option<Spanned<usize>>,
+    steal: Option<Spanned<String>>,
+}
+
+#[derive(Debug)]
+pub enum PatchError {
+    Io(io::Error),
+    Invalid { line: usize, message: String },
+}
+
+impl fmt::Display for PatchError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PatchError::Io(err) => write!(f, "{}", err),
+            PatchError::Invalid { line, message } => write!(f, "line {}: {}", line, message),
+        }
+    }
+}
+
+impl std::error::Error for PatchError {}
+
+pub struct Patch {
+    pub voice: Box<dyn Voice<f32> + Send>,
+    /// Where the voice is panned, and the effects on the stereo signal after.
+    pub pan: f32,
+    pub stereo: Chain<Stereo>,
+    pub components: Vec<Box<dyn UIComponent + Send + 'static>>,
+    pub keyboard_clients: Vec<Arc<Mutex<KeyboardControllerClient>>>,
+    pub keyboard_queues: Vec<KBActionQueue>,
+    pub configs: Vec<(String, SharedPersist)>,
+    pub active_voices: Vec<Arc<AtomicCell<usize>>>,
+    pub cc_map: CcMap,
+}
+
+pub fn load_patch<P: AsRef<Path>>(path: P, tuning: &Tuning) -> Result<Patch, PatchError> {
+    let source = fs::read_to_string(path).map_err(PatchError::Io)?;
+    parse_patch(&source, tuning)
+}
+
+/// Builds the patch in `source`. Sampler roots are pitched in `tuning`, so
+/// a sample plays at its own speed on its root key.
+pub fn parse_patch(source: &str, tuning: &Tuning) -> Result<Patch, PatchError> {
+    let spec: PatchSpec = toml::from_str(source).map_err(|err| PatchError::Invalid {
+        line: err.line_col().map(|(line, _)| line + 1).unwrap_or(1),
+        message: err.to_string(),
+    })?;
+
+    let mut builder = Builder {
+        source,
+        tuning,
+        nodes: &spec.nodes,
+        used: HashSet::new(),
+        components: Vec::new(),
+        keyboard_clients: Vec::new(),
+        keyboard_queues: Vec::new(),
+        configs: Vec::new(),
+        active_voices: Vec::new(),
+        registered: HashMap::new(),
+        copying: false,
+        noises: 0,
+        tables: HashMap::new(),
+        samples: HashMap::new(),
+        instruments: HashMap::new(),
+    };
+    let voice = builder.voice(&spec.output)?;
+
+    let pan = match &spec.pan {
+        Some(pan) if !(-1.0..=1.0).contains(pan.get_ref()) => {
+            return builder.error(pan, "pan must be between -1 and 1".to_string());
+        }
+        Some(pan) => *pan.get_ref(),
+        None => 0.0,
+    };
+    let mut stereo = Chain::new();
+    if let Some(cutoff) = &spec.gate {
+        let gate = || Gate {
+            cutoff_config: Config::new(*cutoff.get_ref()),
+        };
+        stereo.add(Box::new(DualMono::new(gate(), gate())));
+    }
+
+    let mut cc_map = CcMap::new();
+    for cc in spec.cc.iter() {
+        if let Err(message) = cc_map.add(
+            cc.cc,
+            cc.target.get_ref(),
+            cc.min.unwrap_or(0.0),
+            cc.max.unwrap_or(1.0),
+            &builder.configs,
+        ) {
+            return builder.error(&cc.target, message);
+        }
+    }
+
+    Ok(Patch {
+        voice,
+        pan,
+        stereo,
+        components: builder.components,
+        keyboard_clients: builder.keyboard_clients,
+        keyboard_queues: builder.keyboard_queues,
+        configs: builder.configs,
+        active_voices: builder.active_voices,
+        cc_map,
+    })
+}
+
+enum Built {
+    Waveform(Box<dyn Waveform<f32> + Send>),
+    Voice(Box<dyn Voice<f32> + Send>),
+}
+
+struct Builder<'a> {
+    source: &'a str,
+    tuning: &'a Tuning,
+    nodes: &'a BTreeMap<String, NodeSpec>,
+    used: HashSet<&'a str>,
+    components: Vec<Box<dyn UIComponent + Send + 'static>>,
+    keyboard_clients: Vec<Arc<Mutex<KeyboardControllerClient>>>,
+    keyboard_queues: Vec<KBActionQueue>,
+    configs: Vec<(String, SharedPersist)>,
+    active_voices: Vec<Arc<AtomicCell<usize>>>,
+    registered: HashMap<String, Box<dyn Any>>,
+    copying: bool,
+    noises: u64,
+    tables: HashMap<String, Arc<Table>>,
+    samples: HashMap<String, Arc<Sample>>,
+    instruments: HashMap<String, Arc<Instrument>>,
+}
+
+impl<'a> Builder<'a> {
+    /// Shares a client with the UI and presets. A node built more than once,
+    /// as it is under a poly controller, joins its copies to the first client.
+    fn register<P: Persist + JoinClient + Send + 'static>(
+        &mut self,
+        name: String,
+        client: P,
+    ) -> Arc<Mutex<P>> {
+        if let Some(first) = self
+            .registered
+            .get(&name)
+            .and_then(|first| first.downcast_ref::<Arc<Mutex<P>>>())
+        {
+            first.lock().unwrap().join(client);
+            return Arc::clone(first);
+        }
+
+        let client = Arc::new(Mutex::new(client));
+        self.configs.push((name.clone(), client.clone()));
+        self.registered.insert(name, Box::new(client.clone()));
+        client
+    }
+
+    fn add_component<C: UIComponent + Send + 'static>(&mut self, component: C) {
+        if !self.copying {
+            self.components.push(Box::new(component));
+        }
+    }
+
+    fn error<T, S>(&self, at: &Spanned<S>, message: String) -> Result<T, PatchError> {
+        let line = self.source[..at.start()].matches('\n').count() + 1;
+        Err(PatchError::Invalid { line, message })
+    }
+
+    fn voice(
+        &mut self,
+        name: &'a Spanned<String>,
+    ) -> Result<Box<dyn Voice<f32> + Send>, PatchError> {
+        Ok(match self.build(name)? {
+            Built::Waveform(waveform) => Box::new(waveform),
+            Built::Voice(voice) => voice,
+        })
+    }
+
+    fn waveform(
+        &mut self,
+        name: &'a Spanned<String>,
+    ) -> Result<Box<dyn Waveform<f32> + Send>, PatchError> {
+        match self.build(name)? {
+            Built::Waveform(waveform) => Ok(waveform),
+            Built::Voice(_) => self.error(
+                name,
+                format!("node '{}' has no frequency to control", name.get_ref()),
+            ),
+        }
+    }
+
+    fn required<T>(
+        &self,
+        kind: &Spanned<String>,
+        field: &'a Option<Spanned<T>>,
+        field_name: &str,
+    ) -> Result<&'a Spanned<T>, PatchError> {
+        match field {
+            Some(value) => Ok(value),
+            None => self.error(
+                kind,
+                format!("a {} node needs '{}'", kind.get_ref(), field_name),
+            ),
+        }
+    }
+
+    /// The `overtones` and `volumes` of an additive node, or a wavetable
+    /// built like one.
+    fn partials(&self, node: &NodeSpec) -> Result<(Vec<f32>, Vec<f32>), PatchError> {
+        let overtones = node
+            .overtones
+            .as_ref()
+            .map(|o| o.get_ref().clone())
+            .unwrap_or_default();
+        let volumes = match &node.volumes {
+            Some(volumes) if volumes.get_ref().len() != overtones.len() + 1 => {
+                return self.error(
+                    volumes,
+                    format!(
+                        "expected {} volumes, one for the fundamental and each overtone",
+                        overtones.len() + 1
+                    ),
+                )
+            }
+            Some(volumes) => volumes.get_ref().clone(),
+            None => vec![0.5; overtones.len() + 1],
+        };
+        Ok((overtones, volumes))
+    }
+
+    fn interpolation(&self, node: &NodeSpec) -> Result<Interpolation, PatchError> {
+        Ok(match &node.interpolation {
+            None => Interpolation::Cubic,
+            Some(interpolation) => match interpolation.get_ref().as_str() {
+                "linear" => Interpolation::Linear,
+                "cubic" => Interpolation::Cubic,
+                "sinc" => Interpolation::Sinc,
+                other => {
+                    return self.error(
+                        interpolation,
+                        format!(
+                            "unknown interpolation '{}', expected linear, cubic or sinc",
+                            other
+                        ),
+                    )
+                }
+            },
+        })
+    }
+
+    /// Loads a sampler node's `file`, pitched at its `root` note. Loop points
+    /// default to the whole sample.
+    fn sample(&self, kind: &Spanned<String>, node: &NodeSpec) -> Result<Arc<Sample>, PatchError> {
+        let file = self.required(kind, &node.file, "file")?;
+        let root = node.root.as_ref().map_or(60, |root| *root.get_ref());
+        let root_hz = match self.tuning.hz(root) {
+            Some(hz) => hz,
+            None => {
+                let message = format!("root note {} isn't mapped by the tuning", root);
+                return match &node.root {
+                    Some(at) => self.error(at, message),
+                    None => self.error(kind, message),
+                };
+            }
+        };
+        let mut sample = Sample::load_wav(file.get_ref(), root_hz)
+            .or_else(|err| self.error(file, format!("{}: {}", file.get_ref(), err)))?;
+
+        let has_points = node.loop_start.is_some() || node.loop_end.is_some();
+        let mode = match &node.looping {
+            None if has_points => Some(LoopMode::Forward),
+            None => None,
+            Some(looping) => match looping.get_ref().as_str() {
+                "off" => None,
+                "forward" => Some(LoopMode::Forward),
+                "pingpong" => Some(LoopMode::PingPong),
+                other => {
+                    return self.error(
+                        looping,
+                        format!(
+                            "unknown looping '{}', expected off, forward or pingpong",
+                            other
+                        ),
+                    )
+                }
+            },
+        };
+        if let Some(mode) = mode {
+            let start = node.loop_start.as_ref().map_or(0, |start| *start.get_ref());
+            let end = node
+                .loop_end
+                .as_ref()
+                .map_or(sample.data.len(), |end| *end.get_ref());
+            sample.set_loop(start, end, mode);
+            if sample.looping.is_none() {
+                let at = node.loop_start.as_ref().or(node.loop_end.as_ref()).unwrap();
+                return self.error(at, "the loop is empty".to_string());
+            }
+        }
+        Ok(Arc::new(sample))
+    }
+
+    fn build(&mut self, name: &'a Spanned<String>) -> Result<Built, PatchError> {
+        let node = match self.nodes.get(name.get_ref()) {
+            Some(node) => node,
+            None => return self.error(name, format!("unknown node '{}'", name.get_ref())),
+        };
+        // Every node is owned by exactly one parent, which also rules out cycles.
+        if !self.used.insert(name.get_ref()) && !self.copying {
+            return self.error(
+                name,
+                format!("node '{}' is used more than once", name.get_ref()),
+            );
+        }
+
+        let kind = &node.kind;
+        if let Some(base_hz) = &node.base_hz {
+            // An offset in hz has no equivalent in semitones.
+            return self.error(
+                base_hz,
+                "'base_hz' has been replaced by 'transpose', in semitones".to_string(),
+            );
+        }
+        let number = |field: &Option<Spanned<f32>>, default: f32| {
+            field
+                .as_ref()
+                .map(|value| *value.get_ref())
+                .unwrap_or(default)
+        };
+        // Transpositions are written in equal-tempered semitones.
+        let semitones = |field: &Option<Spanned<f32>>| 2f32.powf(number(field, 0.0) / 12.0);
+
+        Ok(match kind.get_ref().as_str() {
+            "sine" => Built::Waveform(Box::new(Sine::new(number(&node.hz, 440.0)))),
+            "saw" => Built::Waveform(Box::new(Saw::new(number(&node.hz, 440.0)))),
+            "square" => Built::Waveform(Box::new(Square::new(number(&node.hz, 440.0)))),
+            "triangle" => Built::Waveform(Box::new(Triangle::new(number(&node.hz, 440.0)))),
+            "pulse" => {
+                let mut pulse = Pulse::new(number(&node.hz, 440.0), number(&node.width, 0.5));
+                let client =
+                    self.register(name.get_ref().clone(), pulse.config.get_client().unwrap());
+                self.add_component(PulseComponent { client });
+                Built::Waveform(Box::new(pulse))
+            }
+            "noise" => {
+                // Each noise node, and each copy of one under a poly
+                // controller, gets its own seed so they don't sound alike.
+                let seed = node.seed.as_ref().map_or(0, |seed| *seed.get_ref()) + self.noises;
+                self.noises += 1;
+                match node
+                    .color
+                    .as_ref()
+                    .map_or("white", |color| color.get_ref().as_str())
+                {
+                    "white" => Built::Waveform(Box::new(WhiteNoise::new(seed))),
+                    "pink" => Built::Waveform(Box::new(PinkNoise::new(seed))),
+                    "brown" => Built::Waveform(Box::new(BrownNoise::new(seed))),
+                    other => {
+                        return self.error(
+                            node.color.as_ref().unwrap(),
+                            format!(
+                                "unknown noise color '{}', expected white, pink or brown",
+                                other
+                            ),
+                        )
+                    }
+                }
+            }
+            "hold" => {
+                let input = self.required(kind, &node.input, "input")?;
+                let input = self.voice(input)?;
+                Built::Waveform(Box::new(SampleAndHold::new(input, number(&node.hz, 10.0))))
+            }
+            "wavetable" => {
+                let table = match self.tables.get(name.get_ref()) {
+                    Some(table) => table.clone(),
+                    None => {
+                        let table = match &node.file {
+                            Some(file) => Table::load_wav(file.get_ref()).or_else(|err| {
+                                self.error(file, format!("{}: {}", file.get_ref(), err))
+                            })?,
+                            None => {
+                                let (overtones, volumes) = self.partials(node)?;
+                                Table::from_additive(
+                                    &AdditiveConfig {
+                                        fundamental: 1.0,
+                                        overtones,
+                                    },
+                                    &MixerConfig { channels: volumes },
+                                )
+                                .or_else(|err| self.error(kind, err.to_string()))?
+                            }
+                        };
+                        let table = Arc::new(table);
+                        self.tables.insert(name.get_ref().clone(), table.clone());
+                        table
+                    }
+                };
+
+                let mut wavetable =
+                    Wavetable::new(table, number(&node.hz, 440.0), number(&node.position, 0.0));
+                let client = self.register(
+                    name.get_ref().clone(),
+                    wavetable.config.get_client().unwrap(),
+                );
+                self.add_component(WavetableComponent { client });
+                Built::Waveform(Box::new(wavetable))
+            }
+            "sampler" => {
+                let interpolation = self.interpolation(node)?;
+                let sample = match self.samples.get(name.get_ref()) {
+                    Some(sample) => sample.clone(),
+                    None => {
+                        let sample = self.sample(kind, node)?;
+                        self.samples.insert(name.get_ref().clone(), sample.clone());
+                        sample
+                    }
+                };
+                Built::Waveform(Box::new(Sampler::new(sample, interpolation)))
+            }
+            "sfz" => {
+                let interpolation = self.interpolation(node)?;
+                let instrument = match self.instruments.get(name.get_ref()) {
+                    Some(instrument) => instrument.clone(),
+                    None => {
+                        let file = self.required(kind, &node.file, "file")?;
+                        let instrument =
+                            Arc::new(Instrument::load(file.get_ref()).or_else(|err| {
+                                self.error(file, format!("{}: {}", file.get_ref(), err))
+                            })?);
+                        self.instruments
+                            .insert(name.get_ref().clone(), instrument.clone());
+                        instrument
+                    }
+                };
+                Built::Waveform(Box::new(MultiSampler::new(
+                    instrument,
+                    interpolation,
+                    self.tuning,
+                )))
+            }
+            "sf2" => {
+                let interpolation = self.interpolation(node)?;
+                let instrument = match self.instruments.get(name.get_ref()) {
+                    Some(instrument) => instrument.clone(),
+                    None => {
+                        let file = self.required(kind, &node.file, "file")?;
+                        let bank = node.bank.as_ref().map_or(0, |bank| *bank.get_ref());
+                        let program = node
+                            .program
+                            .as_ref()
+                            .map_or(0, |program| *program.get_ref());
+                        let instrument =
+                            Arc::new(sf2::load_preset(file.get_ref(), bank, program).or_else(
+                                |err| self.error(file, format!("{}: {}", file.get_ref(), err)),
+                            )?);
+                        self.instruments
+                            .insert(name.get_ref().clone(), instrument.clone());
+                        instrument
+                    }
+                };
+                Built::Waveform(Box::new(MultiSampler::new(
+                    instrument,
+                    interpolation,
+                    self.tuning,
+                )))
+            }
+            "additive" => {
+                let (overtones, volumes) = self.partials(node)?;
+                let mut additive =
+                    Additive::with_volumes(number(&node.fundamental, 440.0), overtones, volumes);
+                let mixer_client = self.register(
+                    format!("{}.mixer", name.get_ref()),
+                    additive.mixer.config.get_client().unwrap(),
+                );
+                let additive_client = self.register(
+                    name.get_ref().clone(),
+                    additive.config.get_client().unwrap(),
+                );
+                self.add_component(MixerComponent {
+                    client: mixer_client,
+                });
+                self.add_component(AdditiveComponent {
+                    client: additive_client,
+                });
+                Built::Waveform(Box::new(additive))
+            }
+            "fm" => {
+                let modulator = self.required(kind, &node.modulator, "modulator")?;
+                let carrier = self.required(kind, &node.carrier, "carrier")?;
+                let modulator = self.waveform(modulator)?;
+                let carrier = self.waveform(carrier)?;
+                Built::Waveform(Box::new(FM::new(modulator, carrier)))
+            }
+            "mix" => {
+                let a = self.required(kind, &node.a, "a")?;
+                let b = self.required(kind, &node.b, "b")?;
+                let (a_mix, b_mix) = (number(&node.a_mix, 0.5), number(&node.b_mix, 0.5));
+                for mix in [&node.a_mix, &node.b_mix].iter().filter_map(|m| m.as_ref()) {
+                    if !(0.0..=1.0).contains(mix.get_ref()) {
+                        return self.error(mix, "mix levels must be between 0 and 1".to_string());
+                    }
+                }
+
+                let mut mix = TwoChannel::with_mix(self.voice(a)?, self.voice(b)?, a_mix, b_mix);
+                let client =
+                    self.register(name.get_ref().clone(), mix.config.get_client().unwrap());
+                self.add_component(TwoChannelComponent {
+                    client,
+                    selected_channel: 0,
+                });
+                Built::Voice(Box::new(mix))
+            }
+            "gate" => {
+                let input = self.required(kind, &node.input, "input")?;
+                let gate = Gate {
+                    cutoff_config: Config::new(number(&node.cutoff, 1.0)),
+                };
+                Built::Voice(Box::new(Chained::new(self.voice(input)?, gate)))
+            }
+            "envelope" => {
+                let input = self.required(kind, &node.input, "input")?;
+                let defaults = EnvelopeConfig::default();
+                let mut envelope = Envelope::new(EnvelopeConfig {
+                    attack: number(&node.attack, defaults.attack),
+                    hold: number(&node.hold, defaults.hold),
+                    decay: number(&node.decay, defaults.decay),
+                    sustain: number(&node.sustain, defaults.sustain),
+                    release: number(&node.release, defaults.release),
+                    curve: number(&node.curve, defaults.curve),
+                });
+                let client = self.register(
+                    name.get_ref().clone(),
+                    envelope.config.get_client().unwrap(),
+                );
+                self.add_component(EnvelopeComponent::new(client));
+
+                match self.build(input)? {
+                    Built::Waveform(waveform) => {
+                        Built::Waveform(Box::new(Chained::new(waveform, envelope)))
+                    }
+                    Built::Voice(voice) => Built::Voice(Box::new(Chained::new(voice, envelope))),
+                }
+            }
+            "keyboard" => {
+                let input = self.required(kind, &node.input, "input")?;
+                let mut controller = KeyboardController::with_transpose(
+                    self.waveform(input)?,
+                    semitones(&node.transpose),
+                );
+                let client = self.register(
+                    name.get_ref().clone(),
+                    controller.config.get_client().unwrap(),
+                );
+                self.keyboard_clients.push(client);
+                self.keyboard_queues.push(controller.action_queue());
+                Built::Voice(Box::new(controller))
+            }
+            "poly" => {
+                let input = self.required(kind, &node.input, "input")?;
+                let count = match &node.voices {
+                    Some(voices) if *voices.get_ref() == 0 => {
+                        return self
+                            .error(voices, "a poly node needs at least one voice".to_string())
+                    }
+                    Some(voices) => *voices.get_ref(),
+                    None => 8,
+                };
+                let policy = match &node.steal {
+                    None => StealPolicy::Oldest,
+                    Some(steal) => match steal.get_ref().as_str() {
+                        "oldest" => StealPolicy::Oldest,
+                        "quietest" => StealPolicy::Quietest,
+                        "retrigger" => StealPolicy::SameNoteRetrigger,
+                        other => {
+                            return self.error(
+                                steal,
+                                format!(
+                                "unknown steal policy '{}', expected oldest, quietest or retrigger",
+                                other
+                            ),
+                            )
+                        }
+                    },
+                };
+
+                let mut voices = vec![self.waveform(input)?];
+                let copying = std::mem::replace(&mut self.copying, true);
+                for _ in 1..count {
+                    voices.push(self.waveform(input)?);
+                }
+                self.copying = copying;
+
+                let mut controller = PolyKeyboardController::with_transpose(
+                    voices,
+                    policy,
+                    semitones(&node.transpose),
+                );
+                let client = self.register(
+                    name.get_ref().clone(),
+                    controller.config.get_client().unwrap(),
+                );
+                self.keyboard_clients.push(client);
+                self.keyboard_queues.push(controller.action_queue());
+                self.active_voices.push(controller.active_voices());
+                Built::Voice(Box::new(controller))
+            }
+            other => return self.error(kind, format!("unknown node kind '{}'", other)),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::offline::{write_wav, WavFormat};
+
+    fn error_line(source: &str, tuning: &Tuning) -> usize {
+        match parse_patch(source, tuning) {
+            Err(PatchError::Invalid { line, .. }) => line,
+            Err(err) => panic!("expected a line number, got {}", err),
+            Ok(_) => panic!("expected an error"),
+        }
+    }
+
+    // Hands `check` a patch playing a short sample, which is removed again
+    // afterwards; parsing reads it in, so the patch doesn't need it after.
+    fn with_sampler_patch<T>(test: &str, root: u8, check: impl FnOnce(&str) -> T) -> T {
+        let path = std::env::temp_dir().join(format!("rsynth-{}-{}.wav", test, std::process::id()));
+        write_wav(&path, &[0.0f32, 0.5, -0.5, 0.0], 44100, WavFormat::Float32).unwrap();
+        let source = format!(
+            "output = \"keys\"\n\n[nodes.keys]\nkind = \"keyboard\"\ninput = \"sample\"\n\n\
+             [nodes.sample]\nkind = \"sampler\"\nfile = {:?}\nroot = {}\n",
+            path.to_str().unwrap(),
+            root
+        );
+        let checked = check(&source);
+        fs::remove_file(&path).unwrap();
+        checked
+    }
+
+    #[test]
+    fn sampler_root_uses_tuning() {
+        let mut tuning = Tuning::equal(12, 440.0);
+        tuning.mapping.last_note = 72;
+
+        let mapped = with_sampler_patch("mapped", 60, |source| parse_patch(source, &tuning));
+        assert!(mapped.is_ok());
+        let line = with_sampler_patch("unmapped", 80, |source| error_line(source, &tuning));
+        assert_eq!(line, 10);
+    }
+
+    #[test]
+    fn builds_a_poly_patch() {
+        let source = r#"
+output = "poly"
+
+[nodes.poly]
+kind = "poly"
+input = "tone"
+voices = 4
+steal = "quietest"
+
+[nodes.tone]
+kind = "additive"
+overtones = [2.0, 3.0]
+volumes = [1.0, 0.5, 0.25]
+
+[[cc]]
+cc = 1
+target = "poly.transpose"
+"#;
+        let patch = parse_patch(source, &Tuning::equal(12, 440.0)).unwrap();
+        assert_eq!(patch.keyboard_clients.len(), 1);
+        assert!(patch.configs.iter().any(|(name, _)| name == "poly"));
+    }
+
+    #[test]
+    fn errors_point_at_their_line() {
+        let tuning = Tuning::equal(12, 440.0);
+        // A syntax error, from the toml parser.
+        assert_eq!(error_line("output = \"a\"\n\n[nodes.a\n", &tuning), 3);
+        // An unknown field, which toml reports at its table.
+        assert_eq!(
+            error_line(
+                "output = \"a\"\n[nodes.a]\nkind = \"sine\"\nhertz = 3\n",
+                &tuning
+            ),
+            2
+        );
+
+        let node = |lines: &str| format!("output = \"a\"\n\n[nodes.a]\n{}\n", lines);
+        assert_eq!(error_line(&node("kind = \"kazoo\""), &tuning), 4);
+        assert_eq!(
+            error_line(&node("kind = \"keyboard\"\ninput = \"b\""), &tuning),
+            5
+        );
+        assert_eq!(error_line(&node("kind = \"keyboard\""), &tuning), 4);
+        assert_eq!(
+            error_line(
+                &node("kind = \"additive\"\novertones = [2.0]\nvolumes = [1.0]"),
+                &tuning
+            ),
+            6
+        );
+        assert_eq!(
+            error_line(
+                &node("kind = \"poly\"\ninput = \"b\"\nsteal = \"newest\"\n[nodes.b]\nkind = \"sine\""),
+                &tuning
+            ),
+            6
+        );
+    }
+
+    #[test]
+    fn pans_and_gates_the_output() {
+        let tuning = Tuning::equal(12, 440.0);
+        let source = |pan| {
+            format!(
+                "output = \"osc\"\npan = {}\ngate = 0.5\n\n[nodes.osc]\nkind = \"sine\"\n",
+                pan
+            )
+        };
+        let patch = parse_patch(&source(-0.5), &tuning).unwrap();
+        assert_eq!(patch.pan, -0.5);
+        assert_eq!(patch.stereo.chain.len(), 1);
+        assert_eq!(error_line(&source(2.0), &tuning), 2);
+    }
+
+    #[test]
+    fn nodes_have_one_parent() {
+        let source = "output = \"mix\"\n\n[nodes.mix]\nkind = \"mix\"\na = \"osc\"\n\
+                      b = \"osc\"\n\n[nodes.osc]\nkind = \"sine\"\n";
+        assert_eq!(error_line(source, &Tuning::equal(12, 440.0)), 6);
+    }
+
+    #[test]
+    fn cc_targets_are_checked() {
+        let source = "output = \"a\"\n\n[nodes.a]\nkind = \"keyboard\"\ninput = \"b\"\n\n\
+                      [nodes.b]\nkind = \"sine\"\n\n[[cc]]\ncc = 1\ntarget = \"a.volume\"\n";
+        assert_eq!(error_line(source, &Tuning::equal(12, 440.0)), 12);
+    }
+
+    #[test]
+    fn base_hz_points_at_transpose() {
+        let source = "output = \"keys\"\n\n[nodes.keys]\nkind = \"keyboard\"\n\
+                      input = \"osc\"\nbase_hz = 2.0\n\n[nodes.osc]\nkind = \"sine\"\n";
+        match parse_patch(source, &Tuning::equal(12, 440.0)) {
+            Err(PatchError::Invalid { line, message }) => {
+                assert_eq!(line, 6);
+                assert!(message.contains("transpose"));
+            }
+            _ => panic!("expected base_hz to be rejected"),
+        }
+    }
+}
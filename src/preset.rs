@@ -0,0 +1,319 @@
+use std::{
+    fmt, fs, io,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+
+use serde::{de::DeserializeOwned, Serialize};
+use toml::{value::Table, Value};
+
+use crate::config::{ComposeConfigClient, ConfigClient};
+
+/// A config client whose current value can be written to and read back from
+/// a preset.
+pub trait Persist {
+    fn save(&self) -> Result<Value, String>;
+    fn restore(&mut self, value: Value) -> Result<(), String>;
+    fn parameter(&self, path: &str) -> Option<f32>;
+    fn set_parameter(&mut self, path: &str, value: f32) -> Result<(), String>;
+}
+
+/// A config that can be stored in a preset. `restore` merges a saved value
+/// into the current one, so state a preset doesn't describe, like the notes a
+/// controller is holding, survives loading it. It rejects values the voice
+/// can't take, like a mixer with a different number of channels.
+pub trait Persistent: Clone + Serialize + DeserializeOwned {
+    fn restore(&self, saved: Self) -> Result<Self, String> {
+        Ok(saved)
+    }
+
+    /// The number at `path`, like `attack` or `channels.1`, for MIDI CCs to
+    /// set.
+    fn parameter(&mut self, _path: &str) -> Option<&mut f32> {
+        None
+    }
+}
+
+// A changed value goes through `restore`, so it's checked like a preset's.
+fn with_parameter<C: Persistent>(current: C, path: &str, value: f32) -> Result<C, String> {
+    let mut config = current.clone();
+    match config.parameter(path) {
+        Some(parameter) => *parameter = value,
+        None => return Err(format!("no parameter '{}'", path)),
+    }
+    current.restore(config)
+}
+
+/// Finds the index in paths like `channels.1`.
+pub fn indexed<'a>(items: &'a mut [f32], path: &str, name: &str) -> Option<&'a mut f32> {
+    let index = path
+        .strip_prefix(name)?
+        .strip_prefix('.')?
+        .parse::<usize>()
+        .ok()?;
+    items.get_mut(index)
+}
+
+impl<C: Persistent> Persist for ConfigClient<C> {
+    fn save(&self) -> Result<Value, String> {
+        Value::try_from(self.get()).map_err(|err| err.to_string())
+    }
+
+    fn restore(&mut self, value: Value) -> Result<(), String> {
+        let saved: C = value.try_into().map_err(|err| err.to_string())?;
+        let restored = self.get().restore(saved)?;
+        self.update(|current| *current = restored);
+        Ok(())
+    }
+
+    fn parameter(&self, path: &str) -> Option<f32> {
+        self.get().parameter(path).copied()
+    }
+
+    fn set_parameter(&mut self, path: &str, value: f32) -> Result<(), String> {
+        let config = with_parameter(self.get(), path, value)?;
+        self.update(|current| *current = config);
+        Ok(())
+    }
+}
+
+impl<C: Persistent, D, F: Fn(C, D) -> C> Persist for ComposeConfigClient<C, D, F> {
+    fn save(&self) -> Result<Value, String> {
+        Value::try_from(self.get()).map_err(|err| err.to_string())
+    }
+
+    fn restore(&mut self, value: Value) -> Result<(), String> {
+        let saved: C = value.try_into().map_err(|err| err.to_string())?;
+        self.set(self.get().restore(saved)?);
+        Ok(())
+    }
+
+    fn parameter(&self, path: &str) -> Option<f32> {
+        self.get().parameter(path).copied()
+    }
+
+    fn set_parameter(&mut self, path: &str, value: f32) -> Result<(), String> {
+        self.set(with_parameter(self.get(), path, value)?);
+        Ok(())
+    }
+}
+
+pub type SharedPersist = Arc<Mutex<dyn Persist + Send>>;
+
+#[derive(Debug)]
+pub enum PresetError {
+    Io(io::Error),
+    InvalidName(String),
+    Exists(String),
+    Parse(String),
+    Target { name: String, message: String },
+}
+
+impl fmt::Display for PresetError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PresetError::Io(err) => write!(f, "{}", err),
+            PresetError::InvalidName(name) => write!(f, "invalid preset name '{}'", name),
+            PresetError::Exists(name) => write!(f, "preset '{}' already exists", name),
+            PresetError::Parse(message) => write!(f, "{}", message),
+            PresetError::Target { name, message } => write!(f, "{}: {}", name, message),
+        }
+    }
+}
+
+impl std::error::Error for PresetError {}
+
+/// Snapshots of every registered config, stored as one TOML file per preset
+/// in `dir` and keyed by the name each client was registered under.
+pub struct PresetBank {
+    dir: PathBuf,
+    targets: Vec<(String, SharedPersist)>,
+}
+
+impl PresetBank {
+    pub fn new<P: Into<PathBuf>>(dir: P, targets: Vec<(String, SharedPersist)>) -> Self {
+        Self {
+            dir: dir.into(),
+            targets,
+        }
+    }
+
+    pub fn names(&self) -> Result<Vec<String>, PresetError> {
+        let entries = match fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(err) => return Err(PresetError::Io(err)),
+        };
+
+        let mut names = Vec::new();
+        for entry in entries {
+            let path = entry.map_err(PresetError::Io)?.path();
+            if path.extension().is_some_and(|ext| ext == "toml") {
+                if let Some(stem) = path.file_stem().and_then(|stem| stem.to_str()) {
+                    names.push(stem.to_string());
+                }
+            }
+        }
+        names.sort();
+        Ok(names)
+    }
+
+    pub fn save(&self, name: &str) -> Result<(), PresetError> {
+        let mut table = Table::new();
+        for (target, client) in self.targets.iter() {
+            let value = client
+                .lock()
+                .unwrap()
+                .save()
+                .map_err(|message| PresetError::Target {
+                    name: target.clone(),
+                    message,
+                })?;
+            table.insert(target.clone(), value);
+        }
+
+        let path = self.path(name)?;
+        let source = toml::to_string_pretty(&Value::Table(table))
+            .map_err(|err| PresetError::Parse(err.to_string()))?;
+        fs::create_dir_all(&self.dir).map_err(PresetError::Io)?;
+        fs::write(path, source).map_err(PresetError::Io)
+    }
+
+    pub fn save_as(&self, name: &str) -> Result<(), PresetError> {
+        if self.path(name)?.exists() {
+            return Err(PresetError::Exists(name.to_string()));
+        }
+        self.save(name)
+    }
+
+    /// Sends every value in the preset through its client. Entries for
+    /// clients this patch doesn't have are ignored, so presets can be shared
+    /// between patches with a common subset of nodes.
+    pub fn load(&self, name: &str) -> Result<(), PresetError> {
+        let source = fs::read_to_string(self.path(name)?).map_err(PresetError::Io)?;
+        let mut table: Table =
+            toml::from_str(&source).map_err(|err| PresetError::Parse(err.to_string()))?;
+
+        for (target, client) in self.targets.iter() {
+            if let Some(value) = table.remove(target) {
+                client
+                    .lock()
+                    .unwrap()
+                    .restore(value)
+                    .map_err(|message| PresetError::Target {
+                        name: target.clone(),
+                        message,
+                    })?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn rename(&self, from: &str, to: &str) -> Result<(), PresetError> {
+        let to_path = self.path(to)?;
+        if to_path.exists() {
+            return Err(PresetError::Exists(to.to_string()));
+        }
+        fs::rename(self.path(from)?, to_path).map_err(PresetError::Io)
+    }
+
+    pub fn delete(&self, name: &str) -> Result<(), PresetError> {
+        fs::remove_file(self.path(name)?).map_err(PresetError::Io)
+    }
+
+    fn path(&self, name: &str) -> Result<PathBuf, PresetError> {
+        let valid = !name.is_empty() && !name.starts_with('.') && !name.contains(['/', '\\']);
+        if !valid {
+            return Err(PresetError::InvalidName(name.to_string()));
+        }
+        Ok(self.dir.join(format!("{}.toml", name)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        combinators::{MixerConfig, TwoChannelConfig},
+        config::{ComposeConfig, Config, ValidatedConfig},
+    };
+
+    // A bank in a directory of its own, removed when the test is done with it.
+    struct TempBank(PresetBank);
+
+    impl std::ops::Deref for TempBank {
+        type Target = PresetBank;
+
+        fn deref(&self) -> &PresetBank {
+            &self.0
+        }
+    }
+
+    impl Drop for TempBank {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0.dir);
+        }
+    }
+
+    fn bank(test: &str, targets: Vec<(String, SharedPersist)>) -> TempBank {
+        let dir = std::env::temp_dir().join(format!("rsynth-{}-{}", test, std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        TempBank(PresetBank::new(dir, targets))
+    }
+
+    fn write(bank: &PresetBank, name: &str, source: &str) {
+        fs::write(bank.path(name).unwrap(), source).unwrap();
+    }
+
+    #[test]
+    fn restores_saved_values() {
+        let mut config = Config::new(MixerConfig {
+            channels: vec![0.5, 0.5],
+        });
+        let client: SharedPersist = Arc::new(Mutex::new(config.get_client().unwrap()));
+        let bank = bank("restore", vec![("mixer".to_string(), client)]);
+        write(&bank, "quiet", "[mixer]\nchannels = [0.25, 0.0]\n");
+
+        bank.load("quiet").unwrap();
+        config.try_update();
+        assert_eq!(config.config.channels, vec![0.25, 0.0]);
+    }
+
+    #[test]
+    fn rejects_mixer_with_other_channel_count() {
+        let mut config = ComposeConfig::new(
+            MixerConfig {
+                channels: vec![0.5, 0.5],
+            },
+            |config: MixerConfig, _: ()| config,
+        );
+        let client: SharedPersist = Arc::new(Mutex::new(config.get_client().unwrap()));
+        let bank = bank("channels", vec![("mixer".to_string(), client)]);
+        write(&bank, "wide", "[mixer]\nchannels = [0.5, 0.5, 0.5]\n");
+
+        match bank.load("wide") {
+            Err(PresetError::Target { name, .. }) => assert_eq!(name, "mixer"),
+            other => panic!("expected a target error, got {:?}", other),
+        }
+        config.try_update();
+        assert_eq!(config.config.config.channels, vec![0.5, 0.5]);
+    }
+
+    #[test]
+    fn rejects_mix_out_of_range() {
+        let mut config: ValidatedConfig<TwoChannelConfig> = ValidatedConfig::new_validated(
+            TwoChannelConfig {
+                a_mix: 0.5,
+                b_mix: 0.5,
+            },
+            |_| true,
+        );
+        let client: SharedPersist = Arc::new(Mutex::new(config.get_client().unwrap()));
+        let bank = bank("mix", vec![("mix".to_string(), client)]);
+        write(&bank, "loud", "[mix]\na_mix = 2.0\nb_mix = 0.5\n");
+
+        assert!(matches!(bank.load("loud"), Err(PresetError::Target { .. })));
+        config.try_update();
+        assert_eq!(config.config.config.a_mix, 0.5);
+    }
+}
@@ -0,0 +1,418 @@
+use std::{f32::consts::PI, fmt, path::Path, sync::Arc};
+
+use crate::{
+    chain::{ProcessContext, Voice},
+    config::ConfigReceiver,
+    offline::read_wav,
+    voices::HasFreq,
+};
+
+#[derive(Debug)]
+pub enum SampleError {
+    Wav(hound::Error),
+    Empty,
+}
+
+impl fmt::Display for SampleError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SampleError::Wav(err) => write!(f, "{}", err),
+            SampleError::Empty => write!(f, "the sample is empty"),
+        }
+    }
+}
+
+impl std::error::Error for SampleError {}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LoopMode {
+    Forward,
+    PingPong,
+}
+
+/// Loop points in sample frames, `end` being exclusive. A `sustain` loop
+/// only repeats while the note is held, then plays on to the end.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Loop {
+    pub start: usize,
+    pub end: usize,
+    pub mode: LoopMode,
+    pub sustain: bool,
+}
+
+/// A recording, mixed down to mono. The data is shared, so samples that
+/// play it with different roots or loops don't copy it.
+pub struct Sample {
+    pub data: Arc<[f32]>,
+    pub sample_rate: f32,
+    /// The pitch the sample plays back at its own speed.
+    pub root_hz: f32,
+    pub looping: Option<Loop>,
+}
+
+impl Sample {
+    pub fn new(data: Arc<[f32]>, sample_rate: f32, root_hz: f32) -> Self {
+        Self {
+            data,
+            sample_rate,
+            root_hz,
+            looping: None,
+        }
+    }
+
+    pub fn load_wav<P: AsRef<Path>>(path: P, root_hz: f32) -> Result<Self, SampleError> {
+        let (spec, samples) = read_wav(path).map_err(SampleError::Wav)?;
+
+        let channels = spec.channels as usize;
+        let data: Vec<f32> = samples
+            .chunks_exact(channels)
+            .map(|frame| frame.iter().sum::<f32>() / channels as f32)
+            .collect();
+        if data.is_empty() {
+            return Err(SampleError::Empty);
+        }
+        Ok(Self::new(data.into(), spec.sample_rate as f32, root_hz))
+    }
+
+    /// Loops between `start` and `end`, which are clamped to the sample. A
+    /// loop with nothing in it is dropped.
+    pub fn set_loop(&mut self, start: usize, end: usize, mode: LoopMode) {
+        let end = end.min(self.data.len());
+        self.looping = if start < end {
+            Some(Loop {
+                start,
+                end,
+                mode,
+                sustain: false,
+            })
+        } else {
+            None
+        };
+    }
+
+    // Reads past the end of a forward loop wrap round to its start, so
+    // interpolation across the seam doesn't click. A sustain loop may be let
+    // go of, so its reads stay put.
+    fn at(&self, index: i64) -> f32 {
+        let index = match self.looping {
+            Some(Loop {
+                start,
+                end,
+                mode: LoopMode::Forward,
+                sustain: false,
+            }) if index >= end as i64 => {
+                start as i64 + (index - start as i64) % (end - start) as i64
+            }
+            _ => index,
+        };
+        if index < 0 || index >= self.data.len() as i64 {
+            0.0
+        } else {
+            self.data[index as usize]
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Interpolation {
+    Linear,
+    /// Catmull-Rom through the four nearest samples.
+    Cubic,
+    /// A Hann-windowed sinc over `SINC_TAPS` samples either side.
+    Sinc,
+}
+
+const SINC_TAPS: i64 = 8;
+
+/// Plays a `Sample` from the start on every note, pitched by `set_freq`
+/// relative to the sample's root.
+pub struct Sampler {
+    sample: Arc<Sample>,
+    interpolation: Interpolation,
+    hz: f32,
+    sample_rate: f32,
+    position: f64,
+    forwards: bool,
+    held: bool,
+}
+
+impl Sampler {
+    pub fn new(sample: Arc<Sample>, interpolation: Interpolation) -> Self {
+        Self {
+            hz: sample.root_hz,
+            sample,
+            interpolation,
+            sample_rate: ProcessContext::default().sample_rate,
+            // Silent until the first note.
+            position: f64::INFINITY,
+            forwards: true,
+            held: false,
+        }
+    }
+
+    /// Whether the sample has played to its end. Pitched to zero or below it
+    /// would never get there, so it counts as finished.
+    pub fn finished(&self) -> bool {
+        self.position >= self.sample.data.len() as f64 || self.step() <= 0.0
+    }
+
+    // Source samples to move on per output sample.
+    fn step(&self) -> f64 {
+        (self.hz / self.sample.root_hz * self.sample.sample_rate / self.sample_rate) as f64
+    }
+
+    fn interpolate(&self) -> f32 {
+        let index = self.position.floor() as i64;
+        let t = (self.position - index as f64) as f32;
+        let at = |offset: i64| self.sample.at(index + offset);
+        match self.interpolation {
+            Interpolation::Linear => at(0) + (at(1) - at(0)) * t,
+            Interpolation::Cubic => {
+                let (a, b, c, d) = (at(-1), at(0), at(1), at(2));
+                b + 0.5
+                    * t
+                    * (c - a + t * (2.0 * a - 5.0 * b + 4.0 * c - d + t * (3.0 * (b - c) + d - a)))
+            }
+            Interpolation::Sinc => {
+                // Lowering the cutoff when playing faster than the source
+                // keeps the extra harmonics from aliasing.
+                let cutoff = (1.0 / self.step() as f32).min(1.0);
+                (1 - SINC_TAPS..=SINC_TAPS)
+                    .map(|offset| {
+                        let x = offset as f32 - t;
+                        let window = 0.5 + 0.5 * (PI * x / SINC_TAPS as f32).cos();
+                        at(offset) * cutoff * sinc(x * cutoff) * window
+                    })
+                    .sum()
+            }
+        }
+    }
+
+    fn advance(&mut self) {
+        let step = self.step();
+        let looping = self
+            .sample
+            .looping
+            .filter(|looping| self.held || !looping.sustain);
+        match looping {
+            Some(Loop {
+                start,
+                end,
+                mode: LoopMode::Forward,
+                ..
+            }) => {
+                self.position += step;
+                let (start, end) = (start as f64, end as f64);
+                if self.position >= end {
+                    self.position = start + (self.position - start) % (end - start);
+                }
+            }
+            Some(Loop {
+                start,
+                end,
+                mode: LoopMode::PingPong,
+                ..
+            }) => {
+                let (start, end) = (start as f64, (end - 1) as f64);
+                if self.forwards {
+                    self.position += step;
+                } else {
+                    self.position -= step;
+                }
+                // Bounces back off either end; the loop's first pass has to
+                // reach it from the start of the sample.
+                if end > start {
+                    while self.position > end || (!self.forwards && self.position < start) {
+                        if self.position > end {
+                            self.position = 2.0 * end - self.position;
+                            self.forwards = false;
+                        } else {
+                            self.position = 2.0 * start - self.position;
+                            self.forwards = true;
+                        }
+                    }
+                } else if self.position > end {
+                    self.position = end;
+                }
+            }
+            None if self.forwards => self.position += step,
+            // Leaving a ping-pong sustain loop on the way back plays out
+            // forwards from there.
+            None => {
+                self.forwards = true;
+                self.position += step;
+            }
+        }
+    }
+}
+
+fn sinc(x: f32) -> f32 {
+    if x.abs() < 1e-6 {
+        1.0
+    } else {
+        (PI * x).sin() / (PI * x)
+    }
+}
+
+impl ConfigReceiver for Sampler {
+    fn try_update_configs(&mut self) {}
+}
+
+impl HasFreq for Sampler {
+    fn set_freq(&mut self, hz: f32) {
+        self.hz = hz;
+    }
+
+    fn get_freq(&self) -> f32 {
+        self.hz
+    }
+}
+
+impl Voice<f32> for Sampler {
+    fn generate(&mut self) -> f32 {
+        if self.finished() {
+            return 0.0;
+        }
+        let signal = self.interpolate();
+        self.advance();
+        signal
+    }
+
+    fn set_context(&mut self, context: ProcessContext) {
+        self.sample_rate = context.sample_rate;
+    }
+
+    fn note_on(&mut self, _velocity: f32) {
+        self.position = 0.0;
+        self.forwards = true;
+        self.held = true;
+    }
+
+    fn note_off(&mut self) {
+        self.held = false;
+    }
+
+    fn is_releasing(&self) -> bool {
+        !self.held && !self.finished()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A ramp sampled at the output rate, so the root plays one frame per
+    // sample.
+    fn sampler(frames: usize, interpolation: Interpolation) -> Sampler {
+        let data: Vec<f32> = (0..frames).map(|frame| frame as f32).collect();
+        let sample_rate = ProcessContext::default().sample_rate;
+        Sampler::new(
+            Arc::new(Sample::new(data.into(), sample_rate, 100.0)),
+            interpolation,
+        )
+    }
+
+    fn looped(frames: usize, start: usize, end: usize, mode: LoopMode) -> Sampler {
+        let mut sampler = sampler(frames, Interpolation::Linear);
+        let mut sample = Sample::new(sampler.sample.data.clone(), sampler.sample_rate, 100.0);
+        sample.set_loop(start, end, mode);
+        sampler.sample = Arc::new(sample);
+        sampler
+    }
+
+    fn run(sampler: &mut Sampler, samples: usize) -> Vec<f32> {
+        (0..samples).map(|_| sampler.generate()).collect()
+    }
+
+    #[test]
+    fn forward_loops_wrap_to_their_start() {
+        let mut sampler = looped(8, 2, 5, LoopMode::Forward);
+        sampler.note_on(1.0);
+        assert_eq!(
+            run(&mut sampler, 10),
+            vec![0.0, 1.0, 2.0, 3.0, 4.0, 2.0, 3.0, 4.0, 2.0, 3.0]
+        );
+
+        // Halfway between the loop's last frame and its start.
+        sampler.note_on(1.0);
+        sampler.position = 4.5;
+        assert_eq!(sampler.generate(), 3.0);
+    }
+
+    #[test]
+    fn ping_pong_loops_bounce_between_their_ends() {
+        let mut sampler = looped(8, 2, 5, LoopMode::PingPong);
+        sampler.note_on(1.0);
+        assert_eq!(
+            run(&mut sampler, 10),
+            vec![0.0, 1.0, 2.0, 3.0, 4.0, 3.0, 2.0, 3.0, 4.0, 3.0]
+        );
+    }
+
+    #[test]
+    fn interpolates_between_frames() {
+        // On a straight line every mode lands on the line, away from the
+        // sample's ends.
+        for &interpolation in [
+            Interpolation::Linear,
+            Interpolation::Cubic,
+            Interpolation::Sinc,
+        ]
+        .iter()
+        {
+            let mut sampler = sampler(64, interpolation);
+            sampler.note_on(1.0);
+            sampler.set_freq(50.0);
+            let played = run(&mut sampler, 64);
+            let tolerance = if interpolation == Interpolation::Sinc {
+                0.1
+            } else {
+                1e-4
+            };
+            for (index, value) in played.iter().enumerate().skip(20).take(24) {
+                assert!(
+                    (value - index as f32 * 0.5).abs() < tolerance,
+                    "{:?} at {}: {}",
+                    interpolation,
+                    index,
+                    value
+                );
+            }
+        }
+
+        // Cubic passes through the frames but bends between them.
+        let mut data = vec![0.0; 8];
+        data[3] = 1.0;
+        let sample = Arc::new(Sample::new(data.into(), 44100.0, 100.0));
+        let halfway = |interpolation| {
+            let mut sampler = Sampler::new(Arc::clone(&sample), interpolation);
+            sampler.note_on(1.0);
+            sampler.position = 2.5;
+            sampler.generate()
+        };
+        assert_eq!(halfway(Interpolation::Linear), 0.5);
+        assert!((halfway(Interpolation::Cubic) - 0.5625).abs() < 1e-6);
+    }
+
+    #[test]
+    fn releases_until_the_sample_ends() {
+        let mut sampler = sampler(4, Interpolation::Linear);
+        sampler.note_on(1.0);
+        assert!(!sampler.is_releasing());
+        sampler.note_off();
+        assert!(sampler.is_releasing());
+        run(&mut sampler, 4);
+        assert!(!sampler.is_releasing());
+    }
+
+    #[test]
+    fn negative_pitches_finish() {
+        let mut sampler = sampler(4, Interpolation::Linear);
+        sampler.note_on(1.0);
+        sampler.set_freq(-100.0);
+        assert!(sampler.finished());
+        assert_eq!(sampler.generate(), 0.0);
+        sampler.note_off();
+        assert!(!sampler.is_releasing());
+    }
+}
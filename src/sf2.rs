@@ -0,0 +1,548 @@
+use std::{collections::HashMap, convert::TryInto, fmt, fs, io, path::Path, sync::Arc};
+
+use crate::{
+    envelope::EnvelopeConfig,
+    sampler::{Loop, LoopMode, Sample},
+    sfz::{Instrument, Region},
+};
+
+#[derive(Debug)]
+pub enum Sf2Error {
+    Io(io::Error),
+    Invalid(String),
+    NoPreset {
+        bank: u16,
+        program: u16,
+    },
+    /// Every zone of the preset was skipped, e.g. for using ROM samples.
+    Unplayable {
+        bank: u16,
+        program: u16,
+    },
+}
+
+impl fmt::Display for Sf2Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Sf2Error::Io(err) => write!(f, "{}", err),
+            Sf2Error::Invalid(message) => write!(f, "invalid soundfont: {}", message),
+            Sf2Error::NoPreset { bank, program } => {
+                write!(f, "no preset {} in bank {}", program, bank)
+            }
+            Sf2Error::Unplayable { bank, program } => write!(
+                f,
+                "preset {} in bank {} has no zones that can be played",
+                program, bank
+            ),
+        }
+    }
+}
+
+impl std::error::Error for Sf2Error {}
+
+fn invalid<T>(message: &str) -> Result<T, Sf2Error> {
+    Err(Sf2Error::Invalid(message.to_string()))
+}
+
+// Generator numbers from the SoundFont 2.04 spec, the ones that are used.
+const START_OFFSET: usize = 0;
+const END_OFFSET: usize = 1;
+const LOOP_START_OFFSET: usize = 2;
+const LOOP_END_OFFSET: usize = 3;
+const START_COARSE_OFFSET: usize = 4;
+const END_COARSE_OFFSET: usize = 12;
+const ATTACK: usize = 34;
+const HOLD: usize = 35;
+const DECAY: usize = 36;
+const SUSTAIN: usize = 37;
+const RELEASE: usize = 38;
+const INSTRUMENT: usize = 41;
+const KEY_RANGE: usize = 43;
+const VELOCITY_RANGE: usize = 44;
+const LOOP_START_COARSE_OFFSET: usize = 45;
+const ATTENUATION: usize = 48;
+const LOOP_END_COARSE_OFFSET: usize = 50;
+const COARSE_TUNE: usize = 51;
+const FINE_TUNE: usize = 52;
+const SAMPLE_ID: usize = 53;
+const SAMPLE_MODES: usize = 54;
+const SCALE_TUNING: usize = 56;
+const ROOT_KEY: usize = 58;
+const GENERATORS: usize = 61;
+
+// Sample header kinds.
+const RIGHT_SAMPLE: u16 = 2;
+const LEFT_SAMPLE: u16 = 4;
+
+/// Raw generator amounts, `None` where a zone leaves the default.
+type Generators = [Option<u16>; GENERATORS];
+
+fn read_u16(data: &[u8], at: usize) -> u16 {
+    u16::from_le_bytes(data[at..at + 2].try_into().unwrap())
+}
+
+fn read_u32(data: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes(data[at..at + 4].try_into().unwrap())
+}
+
+// A chunk's id and data.
+type Chunk<'a> = (&'a [u8], &'a [u8]);
+
+// RIFF chunks, each an id, a length and its data padded to an even length.
+fn chunks(mut data: &[u8]) -> Result<Vec<Chunk<'_>>, Sf2Error> {
+    let mut chunks = Vec::new();
+    while data.len() >= 8 {
+        let size = read_u32(data, 4) as usize;
+        if data.len() < 8 + size {
+            return invalid("truncated chunk");
+        }
+        chunks.push((&data[..4], &data[8..8 + size]));
+        data = &data[(8 + size + size % 2).min(data.len())..];
+    }
+    Ok(chunks)
+}
+
+// The chunks inside a `LIST` of the given type.
+fn list<'a>(chunks: &[Chunk<'a>], kind: &[u8]) -> Result<Vec<Chunk<'a>>, Sf2Error> {
+    match chunks
+        .iter()
+        .find(|(id, data)| *id == b"LIST" && data.len() >= 4 && &data[..4] == kind)
+    {
+        Some((_, data)) => self::chunks(&data[4..]),
+        None => Err(Sf2Error::Invalid(format!(
+            "missing {} list",
+            String::from_utf8_lossy(kind)
+        ))),
+    }
+}
+
+// A chunk of fixed-size records. Lists of headers end with a terminal record,
+// which marks where the last one's zones end.
+fn records<'a>(chunks: &[Chunk<'a>], id: &[u8], size: usize) -> Result<Vec<&'a [u8]>, Sf2Error> {
+    match chunks.iter().find(|(chunk, _)| *chunk == id) {
+        Some((_, data)) if data.len() % size == 0 => Ok(data.chunks(size).collect()),
+        _ => Err(Sf2Error::Invalid(format!(
+            "missing or misshapen {} chunk",
+            String::from_utf8_lossy(id)
+        ))),
+    }
+}
+
+struct SampleHeader {
+    start: u32,
+    end: u32,
+    loop_start: u32,
+    loop_end: u32,
+    sample_rate: u32,
+    pitch: u8,
+    correction: i8,
+    kind: u16,
+}
+
+/// Preset and instrument zones, both of which hold generators.
+struct Zones {
+    // The zone range of each header, its bags.
+    headers: Vec<(usize, usize)>,
+    bags: Vec<Generators>,
+}
+
+impl Zones {
+    fn parse(
+        headers: &[&[u8]],
+        bag_at: usize,
+        bags: &[&[u8]],
+        generators: &[&[u8]],
+    ) -> Result<Self, Sf2Error> {
+        let bag_index = |header: &[u8]| read_u16(header, bag_at) as usize;
+        let header_ranges = headers
+            .windows(2)
+            .map(|pair| (bag_index(pair[0]), bag_index(pair[1])))
+            .collect::<Vec<_>>();
+        let generator_index = |bag: &[u8]| read_u16(bag, 0) as usize;
+
+        let mut zones = Vec::new();
+        for pair in bags.windows(2) {
+            let (first, last) = (generator_index(pair[0]), generator_index(pair[1]));
+            if first > last || last > generators.len() {
+                return invalid("zone generators out of range");
+            }
+            let mut zone = [None; GENERATORS];
+            for generator in generators[first..last].iter() {
+                let operator = read_u16(generator, 0) as usize;
+                if operator < GENERATORS {
+                    zone[operator] = Some(read_u16(generator, 2));
+                }
+            }
+            zones.push(zone);
+        }
+        if header_ranges
+            .iter()
+            .any(|&(first, last)| first > last || last > zones.len())
+        {
+            return invalid("zones out of range");
+        }
+        Ok(Self {
+            headers: header_ranges,
+            bags: zones,
+        })
+    }
+
+    // A header's zones: its global zone if it has one, then the zones that
+    // set `link`, its instrument or sample. Others are ignored, as the spec
+    // says.
+    fn of(&self, header: usize, link: usize) -> (Option<&Generators>, Vec<&Generators>) {
+        let (first, last) = self.headers[header];
+        let zones = &self.bags[first..last];
+        match zones.split_first() {
+            Some((global, rest)) if global[link].is_none() => (
+                Some(global),
+                rest.iter().filter(|zone| zone[link].is_some()).collect(),
+            ),
+            _ => (
+                None,
+                zones.iter().filter(|zone| zone[link].is_some()).collect(),
+            ),
+        }
+    }
+}
+
+fn get(zones: &[Option<&Generators>], generator: usize) -> Option<u16> {
+    zones
+        .iter()
+        .rev()
+        .flatten()
+        .find_map(|zone| zone[generator])
+}
+
+// The amount from the most specific zone that sets it.
+fn signed(zones: &[Option<&Generators>], generator: usize, default: i16) -> i32 {
+    get(zones, generator).map_or(default, |amount| amount as i16) as i32
+}
+
+// Ranges narrow each other, from every level.
+fn range(levels: &[&[Option<&Generators>]], generator: usize) -> (u8, u8) {
+    levels
+        .iter()
+        .fold((0, 127), |(lo, hi), zones| match get(zones, generator) {
+            Some(amount) => (lo.max(amount as u8), hi.min((amount >> 8) as u8)),
+            None => (lo, hi),
+        })
+}
+
+fn seconds(timecents: i32) -> f32 {
+    2f32.powf(timecents as f32 / 1200.0)
+}
+
+/// Loads one preset of a SoundFont as an instrument. Modulators, the filter,
+/// the modulation envelope and the LFOs are left out; velocity still scales
+/// the volume envelope. Stereo samples are mixed down, each side of a pair
+/// playing at half gain.
+pub fn load_preset<P: AsRef<Path>>(
+    path: P,
+    bank: u16,
+    program: u16,
+) -> Result<Instrument, Sf2Error> {
+    let data = fs::read(path).map_err(Sf2Error::Io)?;
+    if data.len() < 12 || &data[..4] != b"RIFF" || &data[8..12] != b"sfbk" {
+        return invalid("not a RIFF sfbk file");
+    }
+    let top = chunks(&data[12..])?;
+
+    let sdta = list(&top, b"sdta")?;
+    let smpl = match sdta.iter().find(|(id, _)| *id == b"smpl") {
+        Some((_, smpl)) => *smpl,
+        None => return invalid("missing smpl chunk"),
+    };
+    let audio: Vec<f32> = smpl
+        .chunks_exact(2)
+        .map(|pair| i16::from_le_bytes([pair[0], pair[1]]) as f32 / 32768.0)
+        .collect();
+
+    let pdta = list(&top, b"pdta")?;
+    let phdr = records(&pdta, b"phdr", 38)?;
+    let pbag = records(&pdta, b"pbag", 4)?;
+    let pgen = records(&pdta, b"pgen", 4)?;
+    let inst = records(&pdta, b"inst", 22)?;
+    let ibag = records(&pdta, b"ibag", 4)?;
+    let igen = records(&pdta, b"igen", 4)?;
+    let shdr = records(&pdta, b"shdr", 46)?;
+
+    let presets = Zones::parse(&phdr, 24, &pbag, &pgen)?;
+    let instruments = Zones::parse(&inst, 20, &ibag, &igen)?;
+    let samples: Vec<SampleHeader> = shdr
+        .iter()
+        .map(|header| SampleHeader {
+            start: read_u32(header, 20),
+            end: read_u32(header, 24),
+            loop_start: read_u32(header, 28),
+            loop_end: read_u32(header, 32),
+            sample_rate: read_u32(header, 36),
+            pitch: header[40],
+            correction: header[41] as i8,
+            kind: read_u16(header, 44),
+        })
+        .collect();
+
+    let preset = phdr[..phdr.len().saturating_sub(1)]
+        .iter()
+        .position(|header| read_u16(header, 20) == program && read_u16(header, 22) == bank)
+        .ok_or(Sf2Error::NoPreset { bank, program })?;
+
+    let mut cache: HashMap<(usize, usize), Arc<[f32]>> = HashMap::new();
+    let mut regions = Vec::new();
+    let (preset_global, preset_zones) = presets.of(preset, INSTRUMENT);
+    for preset_zone in preset_zones {
+        let instrument = preset_zone[INSTRUMENT].unwrap() as usize;
+        if instrument >= instruments.headers.len() {
+            return invalid("instrument out of range");
+        }
+        let preset_levels = [preset_global, Some(preset_zone)];
+
+        let (global, zones) = instruments.of(instrument, SAMPLE_ID);
+        for zone in zones {
+            let levels = [global, Some(zone)];
+            let keys = range(&[&preset_levels, &levels], KEY_RANGE);
+            let velocities = range(&[&preset_levels, &levels], VELOCITY_RANGE);
+            if keys.0 > keys.1 || velocities.0 > velocities.1 {
+                continue;
+            }
+            // Preset generators offset the instrument's.
+            let sum = |generator: usize, default: i16| {
+                signed(&levels, generator, default) + signed(&preset_levels, generator, 0)
+            };
+
+            let index = zone[SAMPLE_ID].unwrap() as usize;
+            let header = match samples.get(index) {
+                Some(header) if index + 1 < samples.len() => header,
+                _ => return invalid("sample out of range"),
+            };
+            // ROM samples live in hardware we don't have.
+            if header.kind & 0x8000 != 0 {
+                continue;
+            }
+            if header.sample_rate == 0 {
+                return invalid("sample with a sample rate of 0");
+            }
+            // The left and right of a stereo pair each have a zone of their
+            // own, and both play.
+            let stereo_gain = if header.kind & (RIGHT_SAMPLE | LEFT_SAMPLE) != 0 {
+                0.5
+            } else {
+                1.0
+            };
+
+            let offset = |fine: usize, coarse: usize| {
+                signed(&levels, fine, 0) as i64 + 32768 * signed(&levels, coarse, 0) as i64
+            };
+            let start = header.start as i64 + offset(START_OFFSET, START_COARSE_OFFSET);
+            let end = header.end as i64 + offset(END_OFFSET, END_COARSE_OFFSET);
+            let loop_start =
+                header.loop_start as i64 + offset(LOOP_START_OFFSET, LOOP_START_COARSE_OFFSET);
+            let loop_end = header.loop_end as i64 + offset(LOOP_END_OFFSET, LOOP_END_COARSE_OFFSET);
+            let (start, end) = (
+                start.max(0) as usize,
+                (end.max(0) as usize).min(audio.len()),
+            );
+            if start >= end {
+                continue;
+            }
+
+            let key_center = match signed(&levels, ROOT_KEY, -1) {
+                -1 => match header.pitch {
+                    pitch @ 0..=127 => pitch,
+                    _ => 60,
+                },
+                key => key.clamp(0, 127) as u8,
+            };
+            let cents = 100 * sum(COARSE_TUNE, 0) + sum(FINE_TUNE, 0) + header.correction as i32;
+            let center_hz = 440.0 * 2f32.powf((key_center as f32 - 69.0) / 12.0);
+            let root_hz = center_hz / 2f32.powf(cents as f32 / 1200.0);
+
+            // 1 loops throughout, 3 only while the key is held.
+            let mode = signed(&levels, SAMPLE_MODES, 0) & 3;
+            let looping = if mode == 1 || mode == 3 {
+                let loop_start = (loop_start - start as i64).max(0) as usize;
+                let loop_end = ((loop_end - start as i64).max(0) as usize).min(end - start);
+                if loop_start < loop_end {
+                    Some(Loop {
+                        start: loop_start,
+                        end: loop_end,
+                        mode: LoopMode::Forward,
+                        sustain: mode == 3,
+                    })
+                } else {
+                    None
+                }
+            } else {
+                None
+            };
+
+            // Zones playing the same stretch of audio share it, whatever
+            // their roots and loops.
+            let data = cache
+                .entry((start, end))
+                .or_insert_with(|| Arc::from(&audio[start..end]))
+                .clone();
+            let mut sample = Sample::new(data, header.sample_rate as f32, root_hz);
+            sample.looping = looping;
+            let sample = Arc::new(sample);
+
+            // Sustain and attenuation are in centibels.
+            let envelope = EnvelopeConfig {
+                attack: seconds(sum(ATTACK, -12000)),
+                hold: seconds(sum(HOLD, -12000)),
+                decay: seconds(sum(DECAY, -12000)),
+                sustain: 10f32.powf(-(sum(SUSTAIN, 0).max(0) as f32) / 200.0),
+                release: seconds(sum(RELEASE, -12000)),
+                curve: 0.0,
+            };
+
+            regions.push(Region {
+                sample,
+                keys,
+                velocities,
+                key_center,
+                key_track: sum(SCALE_TUNING, 100) as f32,
+                gain: stereo_gain * 10f32.powf(-(sum(ATTENUATION, 0).max(0) as f32) / 200.0),
+                envelope,
+                one_shot: false,
+            });
+        }
+    }
+
+    if regions.is_empty() {
+        return Err(Sf2Error::Unplayable { bank, program });
+    }
+    Ok(Instrument { regions })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn chunk(id: &[u8], data: &[u8]) -> Vec<u8> {
+        let mut chunk = id.to_vec();
+        chunk.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        chunk.extend_from_slice(data);
+        chunk
+    }
+
+    fn record(size: usize, fields: &[(usize, u32, usize)]) -> Vec<u8> {
+        let mut record = vec![0; size];
+        for &(at, value, width) in fields {
+            record[at..at + width].copy_from_slice(&value.to_le_bytes()[..width]);
+        }
+        record
+    }
+
+    // A soundfont with preset 0:0 playing one instrument with a zone for
+    // each `(kind, sample_rate)` sample, written to a file for `load_preset`.
+    fn soundfont(test: &str, samples: &[(u16, u32)]) -> PathBuf {
+        let count = samples.len() as u32;
+        let smpl: Vec<u8> = (0..samples.len() * 16)
+            .flat_map(|_| 16384i16.to_le_bytes().to_vec())
+            .collect();
+        let phdr = [
+            record(38, &[(20, 0, 2), (22, 0, 2), (24, 0, 2)]),
+            record(38, &[(24, 1, 2)]),
+        ]
+        .concat();
+        let pbag = [record(4, &[(0, 0, 2)]), record(4, &[(0, 1, 2)])].concat();
+        let pgen = record(4, &[(0, INSTRUMENT as u32, 2), (2, 0, 2)]);
+        let inst = [record(22, &[(20, 0, 2)]), record(22, &[(20, count, 2)])].concat();
+        let ibag: Vec<u8> = (0..=count).flat_map(|i| record(4, &[(0, i, 2)])).collect();
+        let igen: Vec<u8> = (0..count)
+            .flat_map(|i| record(4, &[(0, SAMPLE_ID as u32, 2), (2, i, 2)]))
+            .collect();
+        let mut shdr: Vec<u8> = samples
+            .iter()
+            .enumerate()
+            .flat_map(|(i, &(kind, sample_rate))| {
+                let start = i as u32 * 16;
+                record(
+                    46,
+                    &[
+                        (20, start, 4),
+                        (24, start + 8, 4),
+                        (36, sample_rate, 4),
+                        (40, 60, 1),
+                        (44, kind as u32, 2),
+                    ],
+                )
+            })
+            .collect();
+        shdr.extend(record(46, &[]));
+
+        let sdta = [b"sdta".to_vec(), chunk(b"smpl", &smpl)].concat();
+        let pdta = [
+            b"pdta".to_vec(),
+            chunk(b"phdr", &phdr),
+            chunk(b"pbag", &pbag),
+            chunk(b"pgen", &pgen),
+            chunk(b"inst", &inst),
+            chunk(b"ibag", &ibag),
+            chunk(b"igen", &igen),
+            chunk(b"shdr", &shdr),
+        ]
+        .concat();
+        let body = [
+            b"sfbk".to_vec(),
+            chunk(b"LIST", &sdta),
+            chunk(b"LIST", &pdta),
+        ]
+        .concat();
+
+        let path = std::env::temp_dir().join(format!("rsynth-{}-{}.sf2", test, std::process::id()));
+        fs::write(&path, chunk(b"RIFF", &body)).unwrap();
+        path
+    }
+
+    // Loads preset 0:`program` from `soundfont`, removing the file again.
+    fn load(test: &str, samples: &[(u16, u32)], program: u16) -> Result<Instrument, Sf2Error> {
+        let path = soundfont(test, samples);
+        let loaded = load_preset(&path, 0, program);
+        fs::remove_file(&path).unwrap();
+        loaded
+    }
+
+    #[test]
+    fn halves_stereo_pairs() {
+        let instrument = load("stereo", &[(4, 44100), (2, 44100), (1, 22050)], 0).unwrap();
+        let gains: Vec<f32> = instrument
+            .regions
+            .iter()
+            .map(|region| region.gain)
+            .collect();
+        assert_eq!(gains, vec![0.5, 0.5, 1.0]);
+        assert_eq!(instrument.regions[2].sample.sample_rate, 22050.0);
+        assert_eq!(instrument.regions[0].sample.data.len(), 8);
+    }
+
+    #[test]
+    fn rejects_zero_sample_rate() {
+        assert!(matches!(
+            load("rate", &[(1, 0)], 0),
+            Err(Sf2Error::Invalid(_))
+        ));
+    }
+
+    #[test]
+    fn rejects_presets_with_nothing_to_play() {
+        let rom = [(0x8001, 44100)];
+        assert!(matches!(
+            load("rom", &rom, 0),
+            Err(Sf2Error::Unplayable {
+                bank: 0,
+                program: 0
+            })
+        ));
+        assert!(matches!(
+            load("rom", &rom, 1),
+            Err(Sf2Error::NoPreset {
+                bank: 0,
+                program: 1
+            })
+        ));
+    }
+}
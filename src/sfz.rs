@@ -0,0 +1,743 @@
+use std::{
+    collections::HashMap,
+    fmt, fs, io,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use crate::{
+    chain::{ProcessContext, Voice},
+    config::ConfigReceiver,
+    envelope::{Envelope, EnvelopeConfig},
+    sampler::{Interpolation, Loop, LoopMode, Sample, SampleError, Sampler},
+    tuning::Tuning,
+    voices::{Chained, HasFreq},
+};
+
+#[derive(Debug)]
+pub enum SfzError {
+    Io(io::Error),
+    Invalid { line: usize, message: String },
+    Unsupported { line: usize, what: String },
+    Sample { path: PathBuf, error: SampleError },
+}
+
+impl fmt::Display for SfzError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SfzError::Io(err) => write!(f, "{}", err),
+            SfzError::Invalid { line, message } => write!(f, "line {}: {}", line, message),
+            SfzError::Unsupported { line, what } => {
+                write!(f, "line {}: {} is not supported", line, what)
+            }
+            SfzError::Sample { path, error } => write!(f, "{}: {}", path.display(), error),
+        }
+    }
+}
+
+impl std::error::Error for SfzError {}
+
+/// One sample and the notes it plays.
+pub struct Region {
+    pub sample: Arc<Sample>,
+    pub keys: (u8, u8),
+    pub velocities: (u8, u8),
+    /// The key the sample's root is mapped to, before `tune` and `transpose`.
+    pub key_center: u8,
+    /// Cents per key; 0 plays every key at the root pitch.
+    pub key_track: f32,
+    pub gain: f32,
+    pub envelope: EnvelopeConfig,
+    /// Plays to the end of the sample whatever the key does.
+    pub one_shot: bool,
+}
+
+pub struct Instrument {
+    pub regions: Vec<Region>,
+}
+
+// Where an opcode was set, so later errors can point at it.
+#[derive(Clone)]
+struct Opcode {
+    value: String,
+    line: usize,
+}
+
+type Opcodes = HashMap<String, Opcode>;
+
+const OPCODES: [&str; 23] = [
+    "sample",
+    "lokey",
+    "hikey",
+    "key",
+    "pitch_keycenter",
+    "lovel",
+    "hivel",
+    "loop_mode",
+    "loopmode",
+    "loop_type",
+    "loop_start",
+    "loopstart",
+    "loop_end",
+    "loopend",
+    "tune",
+    "transpose",
+    "pitch_keytrack",
+    "volume",
+    "ampeg_attack",
+    "ampeg_hold",
+    "ampeg_decay",
+    "ampeg_sustain",
+    "ampeg_release",
+];
+
+impl Instrument {
+    /// Loads an SFZ file, with sample paths relative to it.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, SfzError> {
+        let path = path.as_ref();
+        let source = fs::read_to_string(path).map_err(SfzError::Io)?;
+        let directory = path.parent().unwrap_or_else(|| Path::new(""));
+        Self::parse(&source, directory)
+    }
+
+    pub fn parse(source: &str, directory: &Path) -> Result<Self, SfzError> {
+        let mut default_path = String::new();
+        // Opcodes set at each level, which regions inherit.
+        let (mut global, mut master, mut group) = (Opcodes::new(), Opcodes::new(), Opcodes::new());
+        let mut regions: Vec<Opcodes> = Vec::new();
+        let mut header = None;
+
+        for (line, token) in tokens(source)? {
+            match token {
+                Token::Header(name) => {
+                    match name.as_str() {
+                        "global" => {
+                            global.clear();
+                            master.clear();
+                            group.clear();
+                        }
+                        "master" => {
+                            master.clear();
+                            group.clear();
+                        }
+                        "group" => group.clear(),
+                        "region" => {
+                            let mut region = global.clone();
+                            region.extend(master.clone());
+                            region.extend(group.clone());
+                            regions.push(region);
+                        }
+                        "control" => {}
+                        other => {
+                            return Err(SfzError::Unsupported {
+                                line,
+                                what: format!("the <{}> header", other),
+                            })
+                        }
+                    }
+                    header = Some(name);
+                }
+                Token::Opcode(name, value) => {
+                    let opcode = Opcode { value, line };
+                    match header.as_deref() {
+                        Some("control") if name == "default_path" => {
+                            default_path = opcode.value.replace('\\', "/")
+                        }
+                        Some("control") => {
+                            return Err(SfzError::Unsupported {
+                                line,
+                                what: format!("<control> opcode '{}'", name),
+                            })
+                        }
+                        Some("global") => {
+                            global.insert(name, opcode);
+                        }
+                        Some("master") => {
+                            master.insert(name, opcode);
+                        }
+                        Some("group") => {
+                            group.insert(name, opcode);
+                        }
+                        Some("region") => {
+                            regions.last_mut().unwrap().insert(name, opcode);
+                        }
+                        _ => {
+                            return Err(SfzError::Invalid {
+                                line,
+                                message: format!("opcode '{}' outside a header", name),
+                            })
+                        }
+                    }
+                }
+            }
+        }
+
+        let mut samples: HashMap<PathBuf, Arc<Sample>> = HashMap::new();
+        let regions = regions
+            .iter()
+            .map(|opcodes| region(opcodes, &directory.join(&default_path), &mut samples))
+            .collect::<Result<_, _>>()?;
+        Ok(Self { regions })
+    }
+}
+
+enum Token {
+    Header(String),
+    Opcode(String, String),
+}
+
+// Comments blanked out, keeping newlines so line numbers stay right.
+fn strip_comments(source: &str) -> String {
+    let mut stripped = String::with_capacity(source.len());
+    let mut chars = source.chars().peekable();
+    while let Some(c) = chars.next() {
+        match (c, chars.peek()) {
+            ('/', Some('/')) => {
+                while chars.peek().is_some_and(|&c| c != '\n') {
+                    chars.next();
+                }
+            }
+            ('/', Some('*')) => {
+                chars.next();
+                let mut last = ' ';
+                for c in chars.by_ref() {
+                    if c == '\n' {
+                        stripped.push('\n');
+                    }
+                    if last == '*' && c == '/' {
+                        break;
+                    }
+                    last = c;
+                }
+            }
+            _ => stripped.push(c),
+        }
+    }
+    stripped
+}
+
+// Values may hold spaces, as sample paths often do, so one runs on until the
+// next header or `name=`.
+fn tokens(source: &str) -> Result<Vec<(usize, Token)>, SfzError> {
+    let mut tokens = Vec::new();
+    for (index, text) in strip_comments(source).lines().enumerate() {
+        let line = index + 1;
+        let mut rest = text.trim();
+        while !rest.is_empty() {
+            if rest.starts_with('#') {
+                return Err(SfzError::Unsupported {
+                    line,
+                    what: format!("'{}'", rest.split_whitespace().next().unwrap()),
+                });
+            }
+            if let Some(header) = rest.strip_prefix('<') {
+                let end = header.find('>').ok_or_else(|| SfzError::Invalid {
+                    line,
+                    message: "unclosed header".to_string(),
+                })?;
+                tokens.push((line, Token::Header(header[..end].trim().to_string())));
+                rest = header[end + 1..].trim_start();
+                continue;
+            }
+
+            let equals = rest.find('=').ok_or_else(|| SfzError::Invalid {
+                line,
+                message: format!("expected an opcode, found '{}'", rest),
+            })?;
+            let name = rest[..equals].trim();
+            if name.is_empty() || name.contains(char::is_whitespace) {
+                return Err(SfzError::Invalid {
+                    line,
+                    message: format!("invalid opcode name '{}'", name),
+                });
+            }
+            let value = &rest[equals + 1..];
+            let end = value_end(value);
+            tokens.push((
+                line,
+                Token::Opcode(name.to_string(), value[..end].trim().to_string()),
+            ));
+            rest = value[end..].trim_start();
+        }
+    }
+    Ok(tokens)
+}
+
+fn value_end(value: &str) -> usize {
+    let mut end = value.len();
+    for (start, _) in value.match_indices(char::is_whitespace) {
+        let next = value[start..].trim_start();
+        let word_end = next.find(|c: char| !(c.is_alphanumeric() || c == '_'));
+        if next.starts_with('<') || word_end.is_some_and(|i| i > 0 && next[i..].starts_with('=')) {
+            end = start;
+            break;
+        }
+    }
+    end
+}
+
+fn invalid<T>(opcode: &Opcode, message: String) -> Result<T, SfzError> {
+    Err(SfzError::Invalid {
+        line: opcode.line,
+        message,
+    })
+}
+
+fn number<T: std::str::FromStr>(opcodes: &Opcodes, name: &str, default: T) -> Result<T, SfzError> {
+    match opcodes.get(name) {
+        Some(opcode) => opcode
+            .value
+            .parse()
+            .or_else(|_| invalid(opcode, format!("invalid {} '{}'", name, opcode.value))),
+        None => Ok(default),
+    }
+}
+
+// A MIDI note number or a name like `c#4`, where `c4` is 60.
+fn parse_key(text: &str) -> Option<u8> {
+    if let Ok(key) = text.parse::<u8>() {
+        return Some(key).filter(|&key| key < 128);
+    }
+    let text = text.to_ascii_lowercase();
+    let mut chars = text.chars();
+    let step = match chars.next()? {
+        'c' => 0,
+        'd' => 2,
+        'e' => 4,
+        'f' => 5,
+        'g' => 7,
+        'a' => 9,
+        'b' => 11,
+        _ => return None,
+    };
+    let rest = chars.as_str();
+    let (accidental, octave) = match rest.chars().next()? {
+        '#' => (1, &rest[1..]),
+        'b' if rest.len() > 1 => (-1, &rest[1..]),
+        _ => (0, rest),
+    };
+    let key = (octave.parse::<i32>().ok()? + 1) * 12 + step + accidental;
+    if (0..128).contains(&key) {
+        Some(key as u8)
+    } else {
+        None
+    }
+}
+
+fn key(opcodes: &Opcodes, name: &str, default: u8) -> Result<u8, SfzError> {
+    match opcodes.get(name) {
+        Some(opcode) => parse_key(&opcode.value).map_or_else(
+            || invalid(opcode, format!("invalid key '{}'", opcode.value)),
+            Ok,
+        ),
+        None => Ok(default),
+    }
+}
+
+fn region(
+    opcodes: &Opcodes,
+    directory: &Path,
+    samples: &mut HashMap<PathBuf, Arc<Sample>>,
+) -> Result<Region, SfzError> {
+    for (name, opcode) in opcodes.iter() {
+        if !OPCODES.contains(&name.as_str()) {
+            return Err(SfzError::Unsupported {
+                line: opcode.line,
+                what: format!("opcode '{}'", name),
+            });
+        }
+    }
+
+    let sample = match opcodes.get("sample") {
+        Some(sample) => sample,
+        None => {
+            let line = opcodes
+                .values()
+                .map(|opcode| opcode.line)
+                .min()
+                .unwrap_or(0);
+            return Err(SfzError::Invalid {
+                line,
+                message: "a region needs a sample".to_string(),
+            });
+        }
+    };
+    let path = directory.join(sample.value.replace('\\', "/"));
+
+    let fixed = key(opcodes, "key", 60)?;
+    let has_key = opcodes.contains_key("key");
+    let lokey = key(opcodes, "lokey", if has_key { fixed } else { 0 })?;
+    let hikey = key(opcodes, "hikey", if has_key { fixed } else { 127 })?;
+    let key_center = key(opcodes, "pitch_keycenter", fixed)?;
+    let lovel: u8 = number(opcodes, "lovel", 1)?;
+    let hivel: u8 = number(opcodes, "hivel", 127)?;
+
+    // Cents away from the key center the sample sounds at.
+    let tune = number(opcodes, "tune", 0.0f32)? + 100.0 * number(opcodes, "transpose", 0.0f32)?;
+    let root_hz = standard_hz(key_center) / 2f32.powf(tune / 1200.0);
+
+    let mode_opcode = opcodes.get("loop_mode").or_else(|| opcodes.get("loopmode"));
+    let (looping, sustain, one_shot) = match mode_opcode.map(|opcode| opcode.value.as_str()) {
+        None | Some("no_loop") => (false, false, false),
+        Some("one_shot") => (false, false, true),
+        Some("loop_continuous") => (true, false, false),
+        Some("loop_sustain") => (true, true, false),
+        Some(other) => {
+            return invalid(
+                mode_opcode.unwrap(),
+                format!("unknown loop_mode '{}'", other),
+            )
+        }
+    };
+    let direction = match opcodes.get("loop_type") {
+        None => LoopMode::Forward,
+        Some(opcode) => match opcode.value.as_str() {
+            "forward" => LoopMode::Forward,
+            "alternate" => LoopMode::PingPong,
+            other => {
+                return Err(SfzError::Unsupported {
+                    line: opcode.line,
+                    what: format!("loop_type '{}'", other),
+                })
+            }
+        },
+    };
+
+    let loaded = match samples.get(&path) {
+        Some(sample) => sample.clone(),
+        None => {
+            let sample = Sample::load_wav(&path, root_hz).map_err(|error| SfzError::Sample {
+                path: path.clone(),
+                error,
+            })?;
+            let sample = Arc::new(sample);
+            samples.insert(path.clone(), sample.clone());
+            sample
+        }
+    };
+    let start = number(opcodes, "loop_start", number(opcodes, "loopstart", 0)?)?;
+    let end = match opcodes.get("loop_end").or_else(|| opcodes.get("loopend")) {
+        // SFZ loop ends are inclusive.
+        Some(_) => {
+            number(opcodes, "loop_end", number(opcodes, "loopend", 0usize)?)?.saturating_add(1)
+        }
+        None => loaded.data.len(),
+    };
+    let loop_points = Loop {
+        start,
+        end: end.min(loaded.data.len()),
+        mode: direction,
+        sustain,
+    };
+    if looping && loop_points.start >= loop_points.end {
+        let at = opcodes
+            .get("loop_start")
+            .or_else(|| opcodes.get("loopstart"))
+            .or(mode_opcode)
+            .unwrap();
+        return invalid(at, "the loop is empty".to_string());
+    }
+
+    // Regions sharing a file only share its data when they play it alike.
+    let looping = if looping { Some(loop_points) } else { None };
+    let sample = if loaded.root_hz == root_hz && loaded.looping == looping {
+        loaded
+    } else {
+        let mut sample = Sample::new(Arc::clone(&loaded.data), loaded.sample_rate, root_hz);
+        sample.looping = looping;
+        Arc::new(sample)
+    };
+
+    // SFZ's default envelope is a gate with a tiny release against clicks.
+    let envelope = EnvelopeConfig {
+        attack: number(opcodes, "ampeg_attack", 0.0)?,
+        hold: number(opcodes, "ampeg_hold", 0.0)?,
+        decay: number(opcodes, "ampeg_decay", 0.0)?,
+        sustain: number(opcodes, "ampeg_sustain", 100.0f32)? / 100.0,
+        release: number(opcodes, "ampeg_release", 0.001)?,
+        curve: 0.0,
+    };
+
+    Ok(Region {
+        sample,
+        keys: (lokey, hikey),
+        velocities: (lovel, hivel),
+        key_center,
+        key_track: number(opcodes, "pitch_keytrack", 100.0)?,
+        gain: 10f32.powf(number(opcodes, "volume", 0.0f32)? / 20.0),
+        envelope,
+        one_shot,
+    })
+}
+
+impl Region {
+    fn plays(&self, key: u8, velocity: u8) -> bool {
+        (self.keys.0..=self.keys.1).contains(&key)
+            && (self.velocities.0..=self.velocities.1).contains(&velocity)
+    }
+
+    // Scales the played frequency's distance from the key center, where it
+    // is in the tuning, by `key_track`. The sample's root is in standard
+    // pitch, so the key center plays it at its own speed.
+    fn sampler_hz(&self, hz: f32, tuned_center_hz: f32) -> f32 {
+        standard_hz(self.key_center) * (hz / tuned_center_hz).powf(self.key_track / 100.0)
+    }
+}
+
+fn standard_hz(key: u8) -> f32 {
+    440.0 * 2f32.powf((key as f32 - 69.0) / 12.0)
+}
+
+/// Plays an `Instrument`, sounding every region that covers the note's key
+/// and velocity. As the voice is only handed a frequency, the key is the one
+/// the tuning puts nearest to it.
+pub struct MultiSampler {
+    instrument: Arc<Instrument>,
+    zones: Vec<Chained<f32, Sampler, Envelope>>,
+    active: Vec<usize>,
+    // Each key's frequency in the tuning; unmapped keys keep their standard
+    // pitch as a key center but are never picked.
+    keys: Vec<(f32, bool)>,
+    hz: f32,
+    // Regions are picked on the first sample after a note on, once the
+    // controller has set the note's frequency.
+    pending: Option<f32>,
+}
+
+impl MultiSampler {
+    pub fn new(instrument: Arc<Instrument>, interpolation: Interpolation, tuning: &Tuning) -> Self {
+        let zones = instrument
+            .regions
+            .iter()
+            .map(|region| {
+                Chained::new(
+                    Sampler::new(region.sample.clone(), interpolation),
+                    Envelope::new(region.envelope),
+                )
+            })
+            .collect();
+        let keys = (0..128)
+            .map(|key| match tuning.hz(key) {
+                Some(hz) => (hz, true),
+                None => (standard_hz(key), false),
+            })
+            .collect();
+        Self {
+            active: Vec::with_capacity(instrument.regions.len()),
+            instrument,
+            zones,
+            keys,
+            hz: 440.0,
+            pending: None,
+        }
+    }
+
+    fn key(&self) -> Option<u8> {
+        let distance = |key_hz: f32| (key_hz / self.hz).ln().abs();
+        self.keys
+            .iter()
+            .enumerate()
+            .filter(|(_, (_, mapped))| *mapped)
+            .min_by(|(_, (a, _)), (_, (b, _))| {
+                distance(*a)
+                    .partial_cmp(&distance(*b))
+                    .unwrap_or(std::cmp::Ordering::Equal)
+            })
+            .map(|(key, _)| key as u8)
+    }
+
+    fn center_hz(&self, region: &Region) -> f32 {
+        self.keys[region.key_center as usize].0
+    }
+
+    fn start(&mut self, velocity: f32) {
+        self.active.clear();
+        let key = match self.key() {
+            Some(key) => key,
+            None => return,
+        };
+        let midi_velocity = (velocity * 127.0).round().clamp(1.0, 127.0) as u8;
+
+        for (index, region) in self.instrument.regions.iter().enumerate() {
+            if region.plays(key, midi_velocity) {
+                let hz = region.sampler_hz(self.hz, self.center_hz(region));
+                let zone = &mut self.zones[index];
+                zone.voice.set_freq(hz);
+                zone.note_on(velocity);
+                self.active.push(index);
+            }
+        }
+    }
+}
+
+impl ConfigReceiver for MultiSampler {
+    fn try_update_configs(&mut self) {}
+}
+
+impl HasFreq for MultiSampler {
+    fn set_freq(&mut self, hz: f32) {
+        self.hz = hz;
+        for &index in self.active.iter() {
+            let region = &self.instrument.regions[index];
+            let hz = region.sampler_hz(hz, self.center_hz(region));
+            self.zones[index].voice.set_freq(hz);
+        }
+    }
+
+    fn get_freq(&self) -> f32 {
+        self.hz
+    }
+}
+
+impl Voice<f32> for MultiSampler {
+    fn generate(&mut self) -> f32 {
+        if let Some(velocity) = self.pending.take() {
+            self.start(velocity);
+        }
+        let regions = &self.instrument.regions;
+        let zones = &mut self.zones;
+        self.active
+            .iter()
+            .map(|&index| zones[index].generate() * regions[index].gain)
+            .sum()
+    }
+
+    fn set_context(&mut self, context: ProcessContext) {
+        for zone in self.zones.iter_mut() {
+            zone.set_context(context);
+        }
+    }
+
+    fn note_on(&mut self, velocity: f32) {
+        self.pending = Some(velocity);
+    }
+
+    fn note_off(&mut self) {
+        self.pending = None;
+        for &index in self.active.iter() {
+            if !self.instrument.regions[index].one_shot {
+                self.zones[index].note_off();
+            }
+        }
+    }
+
+    fn is_releasing(&self) -> bool {
+        self.active.iter().any(|&index| {
+            let zone = &self.zones[index];
+            if self.instrument.regions[index].one_shot {
+                !zone.voice.finished()
+            } else {
+                zone.is_releasing()
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::offline::{write_wav, WavFormat};
+
+    // A directory holding a short `a.wav`.
+    fn directory(test: &str) -> PathBuf {
+        let directory =
+            std::env::temp_dir().join(format!("rsynth-{}-{}", test, std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        let data = [0.0f32, 0.5, 1.0, 0.5, 0.0, -0.5, -1.0, -0.5];
+        write_wav(directory.join("a.wav"), &data, 44100, WavFormat::Float32).unwrap();
+        directory
+    }
+
+    // Parses `source` next to the sample, then removes the directory again.
+    fn parse(test: &str, source: &str) -> Result<Instrument, SfzError> {
+        let directory = directory(test);
+        let parsed = Instrument::parse(source, &directory);
+        fs::remove_dir_all(&directory).unwrap();
+        parsed
+    }
+
+    #[test]
+    fn regions_inherit_opcodes() {
+        let instrument = parse(
+            "inherit",
+            "<global> volume=-6\n\
+             <group> lokey=c4 hikey=72\n\
+             <region> sample=a.wav\n\
+             <region> sample=a.wav lokey=40 volume=0\n\
+             <group>\n\
+             <region> sample=a.wav\n",
+        )
+        .unwrap();
+
+        let regions = &instrument.regions;
+        assert_eq!(regions[0].keys, (60, 72));
+        assert!((regions[0].gain - 0.501).abs() < 0.001);
+        assert_eq!(regions[1].keys, (40, 72));
+        assert_eq!(regions[1].gain, 1.0);
+        assert_eq!(regions[2].keys, (0, 127));
+    }
+
+    #[test]
+    fn errors_point_at_their_line() {
+        let line = |source| match parse("lines", source) {
+            Err(SfzError::Invalid { line, .. }) | Err(SfzError::Unsupported { line, .. }) => line,
+            Err(err) => panic!("expected a line number, got {}", err),
+            Ok(_) => panic!("expected an error"),
+        };
+        assert_eq!(line("sample=a.wav"), 1);
+        assert_eq!(line("<region>\nsample=a.wav\nfil_type=lpf_2p"), 3);
+        assert_eq!(
+            line("<region> sample=a.wav\n<region> lokey=h4 sample=a.wav"),
+            2
+        );
+        assert_eq!(line("<region> sample=a.wav\n\n<curve> v000=0"), 3);
+        assert!(matches!(
+            parse("control", "<control>\nset_cc1=64\n<region> sample=a.wav"),
+            Err(SfzError::Unsupported { line: 2, .. })
+        ));
+    }
+
+    #[test]
+    fn loop_end_is_clamped() {
+        let instrument = parse(
+            "loop",
+            "<region> sample=a.wav loop_mode=loop_continuous loop_start=2 \
+             loop_end=18446744073709551615",
+        )
+        .unwrap();
+        let looping = instrument.regions[0].sample.looping.unwrap();
+        assert_eq!((looping.start, looping.end), (2, 8));
+    }
+
+    #[test]
+    fn regions_share_sample_data() {
+        let instrument = parse(
+            "share",
+            "<region> sample=a.wav key=60\n<region> sample=a.wav key=62",
+        )
+        .unwrap();
+        let regions = &instrument.regions;
+        assert!(regions[0].sample.root_hz != regions[1].sample.root_hz);
+        assert!(Arc::ptr_eq(
+            &regions[0].sample.data,
+            &regions[1].sample.data
+        ));
+    }
+
+    #[test]
+    fn picks_regions_by_tuned_key() {
+        let instrument = parse("tuned", "<region> sample=a.wav key=62").unwrap();
+        let tuning = Tuning::equal(19, 440.0);
+        let mut sampler = MultiSampler::new(Arc::new(instrument), Interpolation::Linear, &tuning);
+
+        sampler.set_freq(tuning.hz(62).unwrap());
+        sampler.note_on(1.0);
+        sampler.generate();
+        assert_eq!(sampler.active, vec![0]);
+
+        sampler.set_freq(tuning.hz(63).unwrap());
+        sampler.note_on(1.0);
+        sampler.generate();
+        assert!(sampler.active.is_empty());
+    }
+}
@@ -0,0 +1,238 @@
+use std::ops::{Add, Mul};
+
+use crate::{
+    chain::{Effect, ProcessContext, Voice},
+    config::{Config, ConfigReceiver},
+    voices::HasFreq,
+};
+
+pub trait Frame: Copy + Default + Add<Output = Self> + Mul<Output = Self> + From<f32> {
+    const CHANNELS: usize;
+
+    fn write(&self, out: &mut [f32]);
+}
+
+impl Frame for f32 {
+    const CHANNELS: usize = 1;
+
+    fn write(&self, out: &mut [f32]) {
+        for slot in out {
+            *slot = *self;
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Stereo {
+    pub left: f32,
+    pub right: f32,
+}
+
+impl Stereo {
+    pub fn new(left: f32, right: f32) -> Self {
+        Self { left, right }
+    }
+}
+
+impl Add for Stereo {
+    type Output = Self;
+
+    fn add(self, other: Self) -> Self {
+        Stereo::new(self.left + other.left, self.right + other.right)
+    }
+}
+
+impl Mul for Stereo {
+    type Output = Self;
+
+    fn mul(self, other: Self) -> Self {
+        Stereo::new(self.left * other.left, self.right * other.right)
+    }
+}
+
+impl From<f32> for Stereo {
+    fn from(signal: f32) -> Self {
+        Stereo::new(signal, signal)
+    }
+}
+
+impl Frame for Stereo {
+    const CHANNELS: usize = 2;
+
+    fn write(&self, out: &mut [f32]) {
+        match out {
+            [] => {}
+            [mono] => *mono = (self.left + self.right) * 0.5,
+            [left, right, rest @ ..] => {
+                *left = self.left;
+                *right = self.right;
+                rest.iter_mut().for_each(|slot| *slot = 0.0);
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct PanConfig {
+    pub pan: f32,
+}
+
+/// Places a mono voice in the stereo field with an equal-power pan law,
+/// `pan` running from -1.0 (left) to 1.0 (right).
+pub struct Panned<V: Voice<f32>> {
+    pub voice: V,
+    pub config: Config<PanConfig>,
+}
+
+impl<V: Voice<f32>> Panned<V> {
+    pub fn new(voice: V, pan: f32) -> Self {
+        Self {
+            voice,
+            config: Config::new(PanConfig { pan }),
+        }
+    }
+
+    fn gains(&self) -> (f32, f32) {
+        let pan = self.config.config.pan.clamp(-1.0, 1.0);
+        let angle = (pan + 1.0) * std::f32::consts::FRAC_PI_4;
+        (angle.cos(), angle.sin())
+    }
+}
+
+impl<V: Voice<f32>> ConfigReceiver for Panned<V> {
+    fn try_update_configs(&mut self) {
+        self.config.try_update();
+        self.voice.try_update_configs();
+    }
+}
+
+impl<V: Voice<f32>> Voice<Stereo> for Panned<V> {
+    fn generate(&mut self) -> Stereo {
+        let (left, right) = self.gains();
+        let signal = self.voice.generate();
+        Stereo::new(signal * left, signal * right)
+    }
+
+    fn set_context(&mut self, context: ProcessContext) {
+        self.voice.set_context(context);
+    }
+
+    fn note_on(&mut self, velocity: f32) {
+        self.voice.note_on(velocity);
+    }
+
+    fn note_off(&mut self) {
+        self.voice.note_off();
+    }
+
+    fn is_releasing(&self) -> bool {
+        self.voice.is_releasing()
+    }
+}
+
+impl<V: Voice<f32> + HasFreq> HasFreq for Panned<V> {
+    fn set_freq(&mut self, hz: f32) {
+        self.voice.set_freq(hz);
+    }
+
+    fn get_freq(&self) -> f32 {
+        self.voice.get_freq()
+    }
+}
+
+/// Runs an independent mono effect on each channel.
+pub struct DualMono<E: Effect<f32>> {
+    pub left: E,
+    pub right: E,
+}
+
+impl<E: Effect<f32>> DualMono<E> {
+    pub fn new(left: E, right: E) -> Self {
+        Self { left, right }
+    }
+}
+
+impl<E: Effect<f32>> ConfigReceiver for DualMono<E> {
+    fn try_update_configs(&mut self) {
+        self.left.try_update_configs();
+        self.right.try_update_configs();
+    }
+}
+
+impl<E: Effect<f32>> Effect<Stereo> for DualMono<E> {
+    fn process(&mut self, signal: Stereo) -> Stereo {
+        Stereo::new(
+            self.left.process(signal.left),
+            self.right.process(signal.right),
+        )
+    }
+
+    fn set_context(&mut self, context: ProcessContext) {
+        self.left.set_context(context);
+        self.right.set_context(context);
+    }
+
+    fn note_on(&mut self, velocity: f32) {
+        self.left.note_on(velocity);
+        self.right.note_on(velocity);
+    }
+
+    fn note_off(&mut self) {
+        self.left.note_off();
+        self.right.note_off();
+    }
+
+    fn is_releasing(&self) -> bool {
+        self.left.is_releasing() || self.right.is_releasing()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config::Config, effects::Gate};
+
+    struct Constant(f32);
+
+    impl ConfigReceiver for Constant {
+        fn try_update_configs(&mut self) {}
+    }
+
+    impl Voice<f32> for Constant {
+        fn generate(&mut self) -> f32 {
+            self.0
+        }
+    }
+
+    #[test]
+    fn pans_with_equal_power() {
+        let left = Panned::new(Constant(1.0), -1.0).generate();
+        assert!((left.left - 1.0).abs() < 1e-6 && left.right.abs() < 1e-6);
+
+        let centre = Panned::new(Constant(1.0), 0.0).generate();
+        let power = centre.left * centre.left + centre.right * centre.right;
+        assert!((power - 1.0).abs() < 1e-6);
+
+        // Past the edge is the edge.
+        assert_eq!(
+            Panned::new(Constant(1.0), 3.0).generate(),
+            Panned::new(Constant(1.0), 1.0).generate()
+        );
+    }
+
+    #[test]
+    fn dual_mono_keeps_channels_apart() {
+        let gate = |cutoff| Gate {
+            cutoff_config: Config::new(cutoff),
+        };
+        let mut effect = DualMono::new(gate(0.5), gate(1.0));
+        assert_eq!(
+            effect.process(Stereo::new(0.75, 0.75)),
+            Stereo::new(0.0, 0.75)
+        );
+        assert_eq!(
+            effect.process(Stereo::new(0.25, -2.0)),
+            Stereo::new(0.25, 0.0)
+        );
+    }
+}
@@ -4,45 +4,355 @@ use std::{
     marker::PhantomData,
     rc::Rc,
     sync::{Arc, Mutex},
+    time::{Duration, Instant},
 };
 
-use cpal::{traits::StreamTrait, Stream};
+use cpal::{traits::StreamTrait, Stream, StreamError};
+use crossbeam::channel::{Receiver, Sender};
 
 use crate::{
-    audio::Audio,
-    chain::{Chain, Voice},
+    audio::{Audio, AudioError, StreamEvent},
+    chain::{Chain, ProcessContext, Voice},
+    config::ConfigReceiver,
+    stereo::Frame,
     voices::Sine,
 };
 
-pub struct Synth<V: Voice<f32> + Send + 'static> {
+#[derive(Clone, Debug, Default)]
+pub struct StreamStatus {
+    pub xruns: usize,
+    pub errors: usize,
+    pub restarts: usize,
+    pub last_error: Option<String>,
+    /// Set from the UI to pause the stream; the synth follows on its next poll.
+    pub paused: bool,
+}
+
+const CROSSFADE_SECONDS: f32 = 0.05;
+/// How long `swap_voice` waits for the engine before swapping without a
+/// crossfade.
+const SWAP_TIMEOUT: Duration = Duration::from_millis(500);
+
+struct Engine<S, V> {
+    voice: V,
+    incoming: Option<(V, usize)>,
+    fade_frames: usize,
+    swaps: Receiver<V>,
+    retired: Sender<V>,
+    _phantom: PhantomData<S>,
+}
+
+impl<S: Frame, V: Voice<S>> Engine<S, V> {
+    fn finish_swap(&mut self) {
+        if let Some((voice, _)) = self.incoming.take() {
+            let old = std::mem::replace(&mut self.voice, voice);
+            // Dropping a voice may free memory, so hand it back to the control
+            // thread. Swaps are only taken while the slot is empty, so it fits.
+            let _ = self.retired.try_send(old);
+        }
+    }
+
+    // Swaps without a crossfade, dropping any swap in flight.
+    fn replace(&mut self, voice: V) {
+        self.incoming = None;
+        self.swaps.try_iter().for_each(drop);
+        self.voice = voice;
+    }
+}
+
+impl<S: Frame, V: Voice<S>> ConfigReceiver for Engine<S, V> {
+    fn try_update_configs(&mut self) {
+        if self.incoming.is_none() && self.retired.is_empty() {
+            self.incoming = self.swaps.try_recv().ok().map(|voice| (voice, 0));
+        }
+
+        self.voice.try_update_configs();
+        if let Some((voice, _)) = &mut self.incoming {
+            voice.try_update_configs();
+        }
+    }
+}
+
+impl<S: Frame, V: Voice<S>> Voice<S> for Engine<S, V> {
+    fn generate(&mut self) -> S {
+        let old = self.voice.generate();
+        let (new, position) = match &mut self.incoming {
+            Some((voice, position)) => (voice.generate(), position),
+            None => return old,
+        };
+
+        *position += 1;
+        let mix = *position as f32 / self.fade_frames as f32;
+        if *position >= self.fade_frames {
+            self.finish_swap();
+        }
+        old * (1.0 - mix).into() + new * mix.into()
+    }
+
+    fn generate_block(&mut self, block: &mut [S]) {
+        if self.incoming.is_none() {
+            self.voice.generate_block(block);
+        } else {
+            block.iter_mut().for_each(|slot| *slot = self.generate());
+        }
+    }
+
+    fn set_context(&mut self, context: ProcessContext) {
+        self.fade_frames = ((context.sample_rate * CROSSFADE_SECONDS) as usize).max(1);
+        self.voice.set_context(context);
+        if let Some((voice, _)) = &mut self.incoming {
+            voice.set_context(context);
+        }
+    }
+}
+
+pub struct Synth<S: Frame + Send + 'static, V: Voice<S> + Send + 'static> {
     audio: Audio,
     stream: Option<Stream>,
-    _phantom: PhantomData<V>,
+    engine: Option<Arc<Mutex<Engine<S, V>>>>,
+    swaps: Sender<V>,
+    retired: Receiver<V>,
+    events: Receiver<StreamEvent>,
+    event_sender: Sender<StreamEvent>,
+    device_lost: bool,
+    paused: bool,
+    pub status: Arc<Mutex<StreamStatus>>,
 }
 
-impl<V: Voice<f32> + Send> Synth<V> {
-    pub fn new() -> Self {
+impl<S: Frame + Send, V: Voice<S> + Send> Synth<S, V> {
+    pub fn new(audio: Audio) -> Self {
+        let (event_sender, events) = crossbeam::channel::bounded(64);
+        let (swaps, _) = crossbeam::channel::bounded(0);
+        let (_, retired) = crossbeam::channel::bounded(0);
         Self {
-            audio: Audio::new(),
+            audio,
             stream: None,
+            engine: None,
+            swaps,
+            retired,
+            events,
+            event_sender,
+            device_lost: false,
+            paused: false,
+            status: Arc::new(Mutex::new(StreamStatus::default())),
+        }
+    }
+
+    /// Starts playing `voice`, crossfading to it if something is already
+    /// playing.
+    pub fn play(&mut self, mut voice: V) -> Result<(), AudioError> {
+        if self.engine.is_some() {
+            return self.swap_voice(voice);
+        }
+
+        let (swaps, swap_receiver) = crossbeam::channel::bounded(1);
+        let (retire_sender, retired) = crossbeam::channel::bounded(1);
+        voice.set_context(self.audio.context());
+        self.swaps = swaps;
+        self.retired = retired;
+        self.engine = Some(Arc::new(Mutex::new(Engine {
+            voice,
+            incoming: None,
+            fade_frames: 1,
+            swaps: swap_receiver,
+            retired: retire_sender,
             _phantom: PhantomData,
+        })));
+        self.paused = false;
+        self.start_stream()
+    }
+
+    pub fn pause(&mut self) -> Result<(), AudioError> {
+        if let Some(stream) = &self.stream {
+            stream.pause().map_err(AudioError::PauseStream)?;
+        }
+        self.paused = true;
+        Ok(())
+    }
+
+    pub fn resume(&mut self) -> Result<(), AudioError> {
+        if let Some(stream) = &self.stream {
+            stream.play().map_err(AudioError::PlayStream)?;
+        }
+        self.paused = false;
+        Ok(())
+    }
+
+    /// Crossfades from the playing voice to `voice`, or starts playing it if
+    /// nothing is loaded. The old voice is dropped on the next `poll_events`.
+    pub fn swap_voice(&mut self, mut voice: V) -> Result<(), AudioError> {
+        let engine = match &self.engine {
+            Some(engine) => engine,
+            None => return self.play(voice),
+        };
+        voice.set_context(self.audio.context());
+
+        if self.paused || self.stream.is_none() {
+            // Nothing is pulling samples, so there is nothing to crossfade.
+            engine.lock().unwrap().replace(voice);
+            return Ok(());
+        }
+
+        // Only one swap can be in flight; wait for the engine to pick up the previous one.
+        // If it doesn't, the stream has stalled and won't crossfade anyway.
+        let deadline = Instant::now() + SWAP_TIMEOUT;
+        while let Err(err) = self.swaps.try_send(voice) {
+            voice = err.into_inner();
+            self.retired.try_iter().for_each(drop);
+            if Instant::now() >= deadline {
+                engine.lock().unwrap().replace(voice);
+                return Ok(());
+            }
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        Ok(())
+    }
+
+    pub fn poll_events(&mut self) {
+        self.retired.try_iter().for_each(drop);
+
+        let status = Arc::clone(&self.status);
+        let mut status = status.lock().unwrap();
+        if status.paused != self.paused {
+            let toggled = if status.paused {
+                self.pause()
+            } else {
+                self.resume()
+            };
+            if let Err(err) = toggled {
+                status.last_error = Some(err.to_string());
+                status.paused = self.paused;
+            }
+        }
+
+        for event in self.events.try_iter() {
+            match event {
+                StreamEvent::Xrun => status.xruns += 1,
+                StreamEvent::Error(err) => {
+                    status.errors += 1;
+                    status.last_error = Some(err.to_string());
+                    if let StreamError::DeviceNotAvailable = err {
+                        self.device_lost = true;
+                    }
+                }
+            }
+        }
+
+        if self.device_lost {
+            self.stream = None;
+            match self.restart() {
+                Ok(()) => {
+                    self.device_lost = false;
+                    status.restarts += 1;
+                }
+                Err(err) => status.last_error = Some(err.to_string()),
+            }
         }
     }
 
-    pub fn play(&mut self, mut voice: V) {
-        if self.stream.is_none() {
-            let stream = self.audio.stream_with(move |data: &mut [f32]| {
-                voice.try_update_configs();
-                put_samples(&mut voice, data);
-            });
-            stream.play().unwrap();
-            self.stream = Some(stream);
+    fn restart(&mut self) -> Result<(), AudioError> {
+        self.audio = self.audio.reopen()?;
+        self.start_stream()?;
+        if self.paused {
+            self.pause()?;
         }
+        Ok(())
+    }
+
+    fn start_stream(&mut self) -> Result<(), AudioError> {
+        let engine = match &self.engine {
+            Some(engine) => Arc::clone(engine),
+            None => return Ok(()),
+        };
+        let mut context = self.audio.context();
+        let mut block = vec![S::default(); context.block_size];
+        engine.lock().unwrap().set_context(context);
+
+        let stream = self.audio.stream_with(
+            move |data: &mut [f32]| {
+                let mut engine = match engine.try_lock() {
+                    Ok(engine) => engine,
+                    Err(_) => {
+                        data.iter_mut().for_each(|slot| *slot = 0.0);
+                        return;
+                    }
+                };
+
+                let block_size = data.len() / context.channels;
+                if block_size != context.block_size {
+                    context.block_size = block_size;
+                    block.resize(block_size, S::default());
+                    engine.set_context(context);
+                }
+
+                engine.try_update_configs();
+                put_samples(&mut *engine, data, &mut block, context.channels);
+            },
+            self.event_sender.clone(),
+        )?;
+        stream.play().map_err(AudioError::PlayStream)?;
+        self.stream = Some(stream);
+
+        Ok(())
     }
 }
 
-fn put_samples<V: Voice<f32>>(voice: &mut V, data: &mut [f32]) {
-    for slot in data {
-        *slot = voice.generate();
+fn put_samples<S: Frame, V: Voice<S>>(
+    voice: &mut V,
+    data: &mut [f32],
+    block: &mut [S],
+    channels: usize,
+) {
+    voice.generate_block(block);
+    for (frame, sample) in data.chunks_mut(channels).zip(block.iter()) {
+        sample.write(frame);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Constant(f32);
+
+    impl ConfigReceiver for Constant {
+        fn try_update_configs(&mut self) {}
+    }
+
+    impl Voice<f32> for Constant {
+        fn generate(&mut self) -> f32 {
+            self.0
+        }
+    }
+
+    #[test]
+    fn swaps_wait_for_the_retired_voice_to_be_collected() {
+        let (swaps, swap_receiver) = crossbeam::channel::bounded(1);
+        let (retire_sender, retired) = crossbeam::channel::bounded(1);
+        let mut engine = Engine {
+            voice: Constant(0.0),
+            incoming: None,
+            fade_frames: 4,
+            swaps: swap_receiver,
+            retired: retire_sender,
+            _phantom: PhantomData,
+        };
+
+        swaps.try_send(Constant(1.0)).ok().unwrap();
+        engine.try_update_configs();
+        let mut block = [0.0; 8];
+        engine.generate_block(&mut block);
+        assert_eq!(block, [0.25, 0.5, 0.75, 1.0, 1.0, 1.0, 1.0, 1.0]);
+
+        // The first voice hasn't been collected, so the next swap waits.
+        swaps.try_send(Constant(2.0)).ok().unwrap();
+        engine.try_update_configs();
+        assert!(engine.incoming.is_none());
+        assert_eq!(retired.try_recv().unwrap().0, 0.0);
+
+        engine.try_update_configs();
+        engine.generate_block(&mut block);
+        assert_eq!(block[3], 2.0);
+        assert_eq!(retired.try_recv().unwrap().0, 1.0);
     }
 }
@@ -0,0 +1,383 @@
+use std::{fmt, fs, io, path::Path};
+
+#[derive(Debug)]
+pub enum TuningError {
+    Io(io::Error),
+    Invalid { line: usize, message: String },
+    Missing(String),
+}
+
+impl fmt::Display for TuningError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TuningError::Io(err) => write!(f, "{}", err),
+            TuningError::Invalid { line, message } => write!(f, "line {}: {}", line, message),
+            TuningError::Missing(what) => write!(f, "missing {}", what),
+        }
+    }
+}
+
+impl std::error::Error for TuningError {}
+
+/// Which scale degree each MIDI note plays, in the terms of a Scala `.kbm`
+/// file.
+#[derive(Clone, Debug)]
+pub struct KeyMapping {
+    pub first_note: u8,
+    pub last_note: u8,
+    /// The note that plays the scale's 1/1.
+    pub middle_note: u8,
+    pub reference_note: u8,
+    pub reference_hz: f64,
+    /// The degree the mapping repeats at, 0 for the scale's own period.
+    pub octave_degree: usize,
+    /// The degree for each key of the mapping, `None` for unmapped keys. An
+    /// empty list maps consecutive notes to consecutive degrees.
+    pub keys: Vec<Option<usize>>,
+}
+
+impl KeyMapping {
+    /// Middle C plays the 1/1 and A4 is tuned to `a4` Hz.
+    pub fn standard(a4: f64) -> Self {
+        Self {
+            first_note: 0,
+            last_note: 127,
+            middle_note: 60,
+            reference_note: 69,
+            reference_hz: a4,
+            octave_degree: 0,
+            keys: Vec::new(),
+        }
+    }
+
+    pub fn maps(&self, note: u8) -> bool {
+        if note < self.first_note || note > self.last_note {
+            return false;
+        }
+        if self.keys.is_empty() {
+            return true;
+        }
+        let offset = note as i32 - self.middle_note as i32;
+        self.keys[offset.rem_euclid(self.keys.len() as i32) as usize].is_some()
+    }
+}
+
+/// Maps MIDI note numbers to frequencies.
+#[derive(Clone, Debug)]
+pub struct Tuning {
+    /// The ratio of every degree above the 1/1; the last one is the period.
+    pitches: Vec<f64>,
+    pub mapping: KeyMapping,
+}
+
+const JUST_RATIOS: [(u32, u32); 12] = [
+    (16, 15),
+    (9, 8),
+    (6, 5),
+    (5, 4),
+    (4, 3),
+    (45, 32),
+    (3, 2),
+    (8, 5),
+    (5, 3),
+    (9, 5),
+    (15, 8),
+    (2, 1),
+];
+
+impl Tuning {
+    /// `divisions` equal steps to the octave.
+    pub fn equal(divisions: usize, a4: f64) -> Self {
+        let pitches = (1..=divisions)
+            .map(|step| 2f64.powf(step as f64 / divisions as f64))
+            .collect();
+        Self::new(pitches, KeyMapping::standard(a4))
+    }
+
+    /// Five-limit just intonation on C.
+    pub fn just(a4: f64) -> Self {
+        let pitches = JUST_RATIOS
+            .iter()
+            .map(|(num, den)| *num as f64 / *den as f64)
+            .collect();
+        Self::new(pitches, KeyMapping::standard(a4))
+    }
+
+    pub fn new(pitches: Vec<f64>, mapping: KeyMapping) -> Self {
+        Self { pitches, mapping }
+    }
+
+    pub fn load_scala<P: AsRef<Path>>(path: P, a4: f64) -> Result<Self, TuningError> {
+        let source = fs::read_to_string(path).map_err(TuningError::Io)?;
+        Ok(Self::new(parse_scl(&source)?, KeyMapping::standard(a4)))
+    }
+
+    pub fn load_keyboard_mapping<P: AsRef<Path>>(&mut self, path: P) -> Result<(), TuningError> {
+        let source = fs::read_to_string(path).map_err(TuningError::Io)?;
+        self.mapping = parse_kbm(&source)?;
+        Ok(())
+    }
+
+    /// Moves the reference pitch so A4, MIDI note 69, sounds at `a4` Hz,
+    /// whichever note the mapping's reference is.
+    pub fn set_a4(&mut self, a4: f64) -> Result<(), TuningError> {
+        let ratios = self
+            .hz(69)
+            .and(self.ratio(69).zip(self.ratio(self.mapping.reference_note)));
+        match ratios {
+            Some((a4_ratio, reference_ratio)) => {
+                self.mapping.reference_hz = a4 * reference_ratio / a4_ratio;
+                Ok(())
+            }
+            None => Err(TuningError::Missing("a mapping for A4".to_string())),
+        }
+    }
+
+    fn degree_ratio(&self, degree: usize) -> f64 {
+        let size = self.pitches.len();
+        let period = self.pitches[size - 1];
+        let octaves = (degree / size) as i32;
+        match degree % size {
+            0 => period.powi(octaves),
+            step => self.pitches[step - 1] * period.powi(octaves),
+        }
+    }
+
+    // The note's ratio above the middle note.
+    fn ratio(&self, note: u8) -> Option<f64> {
+        let mapping = &self.mapping;
+        let offset = note as i32 - mapping.middle_note as i32;
+        if mapping.keys.is_empty() {
+            let size = self.pitches.len() as i32;
+            let period = self.pitches[size as usize - 1];
+            return Some(
+                self.degree_ratio(offset.rem_euclid(size) as usize)
+                    * period.powi(offset.div_euclid(size)),
+            );
+        }
+
+        let size = mapping.keys.len() as i32;
+        let degree = mapping.keys[offset.rem_euclid(size) as usize]?;
+        let period = match mapping.octave_degree {
+            0 => self.degree_ratio(self.pitches.len()),
+            degree => self.degree_ratio(degree),
+        };
+        Some(self.degree_ratio(degree) * period.powi(offset.div_euclid(size)))
+    }
+
+    /// How many notes it takes to reach the next period, e.g. an octave.
+    pub fn period_keys(&self) -> usize {
+        match self.mapping.keys.len() {
+            0 => self.pitches.len(),
+            size => size,
+        }
+    }
+
+    /// The frequency of a MIDI note, `None` if the mapping leaves it out.
+    pub fn hz(&self, note: u8) -> Option<f32> {
+        let mapping = &self.mapping;
+        if note < mapping.first_note || note > mapping.last_note || self.pitches.is_empty() {
+            return None;
+        }
+        let reference = self.ratio(mapping.reference_note)?;
+        Some((mapping.reference_hz * self.ratio(note)? / reference) as f32)
+    }
+}
+
+impl Default for Tuning {
+    fn default() -> Self {
+        Self::equal(12, 440.0)
+    }
+}
+
+// Scala files are line based, with `!` starting a comment line. Returns the
+// remaining lines with their line numbers.
+fn scala_lines(source: &str) -> impl Iterator<Item = (usize, &str)> {
+    source
+        .lines()
+        .enumerate()
+        .map(|(index, line)| (index + 1, line.trim()))
+        .filter(|(_, line)| !line.starts_with('!'))
+}
+
+fn invalid<T>(line: usize, message: String) -> Result<T, TuningError> {
+    Err(TuningError::Invalid { line, message })
+}
+
+fn parse_number<'a, T: std::str::FromStr>(
+    lines: &mut impl Iterator<Item = (usize, &'a str)>,
+    what: &str,
+) -> Result<T, TuningError> {
+    parse_numbered(lines, what).map(|(_, number)| number)
+}
+
+// The number and the line it's on.
+fn parse_numbered<'a, T: std::str::FromStr>(
+    lines: &mut impl Iterator<Item = (usize, &'a str)>,
+    what: &str,
+) -> Result<(usize, T), TuningError> {
+    match lines.next() {
+        Some((line, text)) => {
+            let word = text.split_whitespace().next().unwrap_or("");
+            word.parse()
+                .map(|number| (line, number))
+                .or_else(|_| invalid(line, format!("invalid {} '{}'", what, text)))
+        }
+        None => Err(TuningError::Missing(what.to_string())),
+    }
+}
+
+/// Parses a Scala scale: a description line, the number of notes, then one
+/// pitch per line in cents (containing a `.`) or as a ratio like `3/2`. A
+/// scale of no notes has only the 1/1, so every key plays it.
+pub fn parse_scl(source: &str) -> Result<Vec<f64>, TuningError> {
+    let mut lines = scala_lines(source).skip(1);
+    let count: usize = parse_number(&mut lines, "note count")?;
+    if count == 0 {
+        return Ok(vec![1.0]);
+    }
+
+    let mut pitches = Vec::with_capacity(count);
+    for (line, text) in lines.take(count) {
+        let word = text.split_whitespace().next().unwrap_or("");
+        let ratio = if word.contains('.') {
+            word.parse::<f64>()
+                .ok()
+                .map(|cents| 2f64.powf(cents / 1200.0))
+        } else {
+            let mut parts = word.splitn(2, '/');
+            let num = parts.next().and_then(|num| num.parse::<f64>().ok());
+            let den = parts
+                .next()
+                .map_or(Some(1.0), |den| den.parse::<f64>().ok());
+            num.zip(den).map(|(num, den)| num / den)
+        };
+        match ratio {
+            Some(ratio) if ratio > 0.0 && ratio.is_finite() => pitches.push(ratio),
+            _ => return invalid(line, format!("invalid pitch '{}'", text)),
+        }
+    }
+
+    if pitches.len() < count {
+        return Err(TuningError::Missing(format!(
+            "notes, expected {} but found {}",
+            count,
+            pitches.len()
+        )));
+    }
+    Ok(pitches)
+}
+
+/// Parses a Scala keyboard mapping.
+pub fn parse_kbm(source: &str) -> Result<KeyMapping, TuningError> {
+    let mut lines = scala_lines(source).filter(|(_, line)| !line.is_empty());
+    let size: usize = parse_number(&mut lines, "map size")?;
+    let first_note = parse_number(&mut lines, "first note")?;
+    let last_note = parse_number(&mut lines, "last note")?;
+    let middle_note = parse_number(&mut lines, "middle note")?;
+    let (reference_line, reference_note) = parse_numbered(&mut lines, "reference note")?;
+    let reference_hz = parse_number(&mut lines, "reference frequency")?;
+    let octave_degree = parse_number(&mut lines, "octave degree")?;
+
+    let mut keys = Vec::with_capacity(size);
+    for (line, text) in lines.take(size) {
+        let word = text.split_whitespace().next().unwrap_or("");
+        keys.push(match word {
+            "x" => None,
+            degree => Some(
+                degree
+                    .parse()
+                    .or_else(|_| invalid(line, format!("invalid degree '{}'", text)))?,
+            ),
+        });
+    }
+    // Missing entries at the end count as unmapped.
+    keys.resize(size, None);
+
+    if size > 0 && keys.iter().all(Option::is_none) {
+        return Err(TuningError::Missing("a mapped key".to_string()));
+    }
+
+    let mapping = KeyMapping {
+        first_note,
+        last_note,
+        middle_note,
+        reference_note,
+        reference_hz,
+        octave_degree,
+        keys,
+    };
+    // Every note is tuned relative to the reference, so without it none are.
+    if !mapping.maps(reference_note) {
+        return invalid(
+            reference_line,
+            format!("reference note {} is not mapped", reference_note),
+        );
+    }
+    Ok(mapping)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_cents_and_ratios() {
+        let pitches = parse_scl("! comment\nhalf\n 2\n!\n700.0 fifth\n2/1\n").unwrap();
+        assert!((pitches[0] - 1.5).abs() < 0.01);
+        assert_eq!(pitches[1], 2.0);
+    }
+
+    #[test]
+    fn empty_scale_plays_its_1_1() {
+        let tuning = Tuning::new(
+            parse_scl("unison\n0\n").unwrap(),
+            KeyMapping::standard(440.0),
+        );
+        assert_eq!(tuning.hz(60), Some(440.0));
+        assert_eq!(tuning.hz(61), Some(440.0));
+    }
+
+    #[test]
+    fn scale_errors_point_at_their_line() {
+        assert!(matches!(
+            parse_scl("bad\n2\n3/2\nfifth\n"),
+            Err(TuningError::Invalid { line: 4, .. })
+        ));
+        assert!(matches!(
+            parse_scl("short\n3\n3/2\n2/1\n"),
+            Err(TuningError::Missing(_))
+        ));
+    }
+
+    // Twelve keys with the black ones unmapped, C4 at 261.63 Hz.
+    fn white_keys(reference: u8) -> String {
+        format!(
+            "12\n0\n127\n60\n{}\n261.63\n7\n0\nx\n1\nx\n2\n3\nx\n4\nx\n5\nx\n6\n",
+            reference
+        )
+    }
+
+    #[test]
+    fn keyboard_mapping_needs_its_reference() {
+        assert!(parse_kbm(&white_keys(60)).is_ok());
+        assert!(matches!(
+            parse_kbm(&white_keys(61)),
+            Err(TuningError::Invalid { line: 5, .. })
+        ));
+    }
+
+    #[test]
+    fn a4_moves_the_reference() {
+        let mut tuning = Tuning::equal(12, 440.0);
+        tuning.mapping = parse_kbm(&white_keys(60)).unwrap();
+        // The white keys play a whole-tone scale from C4.
+        let a4 = tuning.hz(69).unwrap();
+        tuning.set_a4(432.0).unwrap();
+        assert!((tuning.hz(69).unwrap() - 432.0).abs() < 0.001);
+        assert!((tuning.hz(60).unwrap() / tuning.hz(69).unwrap() - 261.63 / a4).abs() < 0.001);
+
+        tuning.mapping.last_note = 68;
+        assert!(tuning.set_a4(440.0).is_err());
+    }
+}
@@ -3,23 +3,32 @@ use std::{
     sync::{Arc, Mutex},
 };
 
+use crossbeam::atomic::AtomicCell;
 use crossterm::event::KeyCode;
 use tui::{
     buffer::Buffer,
     layout::{Constraint, Direction, Layout, Rect},
     style::{Color, Style},
+    text::{Span, Spans},
     widgets::{BarChart, Block, Borders, Clear, Paragraph, Widget},
 };
 
 use crate::{
     chain::Voice,
     combinators::{MixerClient, TwoChannelClient, TwoChannelConfig},
-    config::{ComposeConfigClient, ConfigClient},
+    config::ConfigClient,
     controllers::{KBConfigAction, KeyboardControllerClient},
-    voices::{AdditiveAction, AdditiveConfig, HasFreq},
+    envelope::{EnvelopeClient, EnvelopeConfig},
+    graph::{Edge, GraphClient, GraphError, MakeNode, NodeId},
+    midi::{TransportAction, TransportClient},
+    oscillators::PulseClient,
+    preset::{PresetBank, PresetError},
+    tuning::Tuning,
+    voices::{AdditiveClient, HasFreq},
+    wavetable::WavetableClient,
 };
 
-use super::input::InputEvent;
+use super::input::{typed_char, InputEvent};
 
 pub trait UIComponent: RefWidget {
     fn dispatch(&mut self, event: InputEvent);
@@ -155,45 +164,183 @@ impl<F: FnOnce(Rect, &mut Buffer)> Widget for WrapperWidget<F> {
 }
 
 pub struct KeyboardInputComponent {
-    pub controller_clients: Vec<KeyboardControllerClient>,
+    pub controller_clients: Vec<Arc<Mutex<KeyboardControllerClient>>>,
+    pub active_voices: Vec<Arc<AtomicCell<usize>>>,
+    tuning: Tuning,
+    held: Vec<char>,
+    octave: i32,
+    transpose: i32,
+    velocity: f32,
+    // Why the last octave or transpose key did nothing.
+    notice: Option<&'static str>,
 }
 
-impl UIComponent for KeyboardInputComponent {
-    fn dispatch(&mut self, event: InputEvent) {
-        if let Some(action) = parse_keyboard_action(event) {
-            for client in self.controller_clients.iter_mut() {
-                client.update(|_| action);
+/// The note `z` plays; `q` is an octave above it.
+const LOWEST_NOTE: u8 = 48;
+const LOWER_ROW: &str = "zsxdcvgbhnjm,l.;/";
+const UPPER_ROW: &str = "q2w3er5t6y7ui9o0p[=]";
+const VELOCITIES: [f32; 4] = [0.25, 0.5, 0.75, 1.0];
+
+impl KeyboardInputComponent {
+    pub fn new(
+        controller_clients: Vec<Arc<Mutex<KeyboardControllerClient>>>,
+        active_voices: Vec<Arc<AtomicCell<usize>>>,
+        tuning: Tuning,
+    ) -> Self {
+        Self {
+            controller_clients,
+            active_voices,
+            tuning,
+            held: Vec::new(),
+            octave: 0,
+            transpose: 0,
+            velocity: 1.0,
+            notice: None,
+        }
+    }
+
+    pub fn active_voices(&self) -> usize {
+        self.active_voices.iter().map(|count| count.load()).sum()
+    }
+
+    fn send(&self, action: KBConfigAction) {
+        for client in self.controller_clients.iter() {
+            client.lock().unwrap().update(|_| action);
+        }
+    }
+
+    /// Whether the key plays a note, and so has its release reported.
+    pub fn plays(code: KeyCode) -> bool {
+        matches!(code, KeyCode::Char(char) if key_note(char).is_some())
+    }
+
+    fn key_hz(&self, char: char) -> Option<f32> {
+        key_note(char).and_then(|note| self.tuning.hz(note))
+    }
+
+    // Octave and transpose move every note by the same ratio, measured in
+    // the tuning's own steps.
+    fn shift(&mut self, octaves: i32, steps: i32) {
+        let middle = match self.tuning.hz(60) {
+            Some(hz) => hz,
+            None => {
+                self.notice = Some("middle C isn't mapped, so can't shift");
+                return;
+            }
+        };
+        let period = self.tuning.period_keys() as i32;
+        let offset = (self.octave + octaves) * period + self.transpose + steps;
+        let shifted = (60 + offset >= 0 && 60 + offset <= 127)
+            .then(|| self.tuning.hz((60 + offset) as u8))
+            .flatten();
+
+        match shifted {
+            Some(hz) => {
+                self.octave += octaves;
+                self.transpose += steps;
+                self.notice = None;
+                self.send(KBConfigAction::ChangeBase(hz / middle));
             }
+            None => self.notice = Some("that shift lands on an unmapped note"),
         }
     }
 }
 
-fn parse_keyboard_action(event: InputEvent) -> Option<KBConfigAction> {
-    if let InputEvent::Unmapped(KeyCode::Char(char)) = event {
-        match char {
-            'a' => Some(KBConfigAction::Play(300.0)),
-            's' => Some(KBConfigAction::Play(320.0)),
-            'd' => Some(KBConfigAction::Play(340.0)),
-            'f' => Some(KBConfigAction::Play(360.0)),
-            'g' => Some(KBConfigAction::Play(380.0)),
-            'q' => Some(KBConfigAction::Play(400.0)),
-            'w' => Some(KBConfigAction::Play(420.0)),
-            'e' => Some(KBConfigAction::Play(440.0)),
-            'r' => Some(KBConfigAction::Play(460.0)),
-            't' => Some(KBConfigAction::Play(480.0)),
-            _ => None,
+impl UIComponent for KeyboardInputComponent {
+    fn dispatch(&mut self, event: InputEvent) {
+        match event {
+            InputEvent::Release(KeyCode::Char(char)) => {
+                if let Some(index) = self.held.iter().position(|held| *held == char) {
+                    self.held.remove(index);
+                    if let Some(hz) = self.key_hz(char) {
+                        self.send(KBConfigAction::Stop(hz));
+                    }
+                }
+            }
+            InputEvent::Unmapped(KeyCode::Char(' ')) => {
+                self.held.clear();
+                self.send(KBConfigAction::StopAll);
+            }
+            InputEvent::Unmapped(KeyCode::Up) => self.shift(1, 0),
+            InputEvent::Unmapped(KeyCode::Down) => self.shift(-1, 0),
+            InputEvent::Unmapped(KeyCode::Right) => self.shift(0, 1),
+            InputEvent::Unmapped(KeyCode::Left) => self.shift(0, -1),
+            InputEvent::Unmapped(KeyCode::F(key)) => {
+                if let Some(velocity) = (key as usize)
+                    .checked_sub(1)
+                    .and_then(|index| VELOCITIES.get(index))
+                {
+                    self.velocity = *velocity;
+                }
+            }
+            event => {
+                // Repeats of a held key arrive as presses too; only the first plays.
+                if let Some(char) = typed_char(event).filter(|char| !self.held.contains(char)) {
+                    if let Some(hz) = self.key_hz(char) {
+                        self.held.push(char);
+                        self.send(KBConfigAction::Velocity(self.velocity));
+                        self.send(KBConfigAction::Play(hz));
+                    }
+                }
+            }
         }
-    } else {
-        None
     }
 }
 
+// Two chromatic rows like a tracker: z-row from `LOWEST_NOTE`, q-row an
+// octave up, with the row above each holding the black keys.
+fn key_note(char: char) -> Option<u8> {
+    LOWER_ROW
+        .find(char)
+        .or_else(|| UPPER_ROW.find(char).map(|index| index + 12))
+        .map(|index| LOWEST_NOTE + index as u8)
+}
+
 impl RefWidget for KeyboardInputComponent {
-    fn render(&self, area: Rect, buf: &mut Buffer) {}
+    fn render(&self, area: Rect, buf: &mut Buffer) {
+        let held: Vec<u8> = self
+            .held
+            .iter()
+            .filter_map(|char| key_note(*char))
+            .collect();
+        let key = |note: u8, black_row: bool| {
+            let black = [1, 3, 6, 8, 10].contains(&(note % 12));
+            let color = if held.contains(&note) {
+                Color::Cyan
+            } else if black && black_row {
+                Color::Black
+            } else {
+                Color::White
+            };
+            Span::styled("  ", Style::default().bg(color))
+        };
+        let notes = LOWEST_NOTE..LOWEST_NOTE + UPPER_ROW.len() as u8 + 12;
+        let lines = vec![
+            Spans::from(
+                notes
+                    .clone()
+                    .map(|note| key(note, true))
+                    .collect::<Vec<_>>(),
+            ),
+            Spans::from(notes.map(|note| key(note, false)).collect::<Vec<_>>()),
+        ];
+        let mut title = format!(
+            "Keyboard  octave {:+}  transpose {:+}  velocity {:.2}",
+            self.octave, self.transpose, self.velocity
+        );
+        if let Some(notice) = self.notice {
+            title = format!("{}  ({})", title, notice);
+        }
+
+        Clear.render(area, buf);
+        Paragraph::new(lines)
+            .block(Block::default().borders(Borders::ALL).title(title))
+            .render(area, buf);
+    }
 }
 
 pub struct MixerComponent {
-    pub client: MixerClient,
+    pub client: Arc<Mutex<MixerClient>>,
 }
 
 impl RefWidget for MixerComponent {
@@ -205,6 +352,8 @@ impl RefWidget for MixerComponent {
 
         let data = self
             .client
+            .lock()
+            .unwrap()
             .get()
             .channels
             .iter()
@@ -252,7 +401,9 @@ impl<C: UIComponent> NavigationContainer<C> {
     fn handle_movement(&mut self, event: InputEvent) {
         match event {
             InputEvent::Enter => {
-                self.focused = Some(self.selected);
+                if !self.components.is_empty() {
+                    self.focused = Some(self.selected);
+                }
                 return;
             }
             _ => {}
@@ -266,7 +417,7 @@ impl<C: UIComponent> NavigationContainer<C> {
                     }
                 }
                 InputEvent::Right => {
-                    if self.selected < self.components.len() - 1 {
+                    if self.selected + 1 < self.components.len() {
                         self.selected += 1
                     }
                 }
@@ -279,7 +430,7 @@ impl<C: UIComponent> NavigationContainer<C> {
                     }
                 }
                 InputEvent::Down => {
-                    if self.selected < self.components.len() - 1 {
+                    if self.selected + 1 < self.components.len() {
                         self.selected += 1
                     }
                 }
@@ -309,6 +460,10 @@ impl<C: UIComponent> NavigationContainer<C> {
 
 impl<C: UIComponent> RefWidget for NavigationContainer<C> {
     fn render(&self, area: Rect, buf: &mut Buffer) {
+        if self.components.is_empty() {
+            return;
+        }
+
         let constraints: Vec<Constraint> =
             [Constraint::Percentage(100 / (self.components.len() as u16))]
                 .iter()
@@ -340,19 +495,464 @@ impl<C: UIComponent> UIComponent for NavigationContainer<C> {
 }
 
 pub struct AdditiveComponent {
-    pub client: ComposeConfigClient<
-        AdditiveConfig,
-        AdditiveAction,
-        fn(AdditiveConfig, AdditiveAction) -> AdditiveConfig,
-    >,
+    pub client: Arc<Mutex<AdditiveClient>>,
 }
 
 impl RefWidget for AdditiveComponent {
     fn render(&self, area: Rect, buf: &mut Buffer) {
-        Paragraph::new(format!("{:?}", self.client.get())).render(area, buf);
+        Paragraph::new(format!("{:?}", self.client.lock().unwrap().get())).render(area, buf);
     }
 }
 
 impl UIComponent for AdditiveComponent {
     fn dispatch(&mut self, event: InputEvent) {}
 }
+
+pub struct GraphComponent {
+    pub client: GraphClient,
+    pub palette: Vec<(&'static str, MakeNode)>,
+    palette_index: usize,
+    added: u64,
+    selected: usize,
+    source: Option<NodeId>,
+    message: Option<String>,
+}
+
+impl GraphComponent {
+    pub fn new(client: GraphClient, palette: Vec<(&'static str, MakeNode)>) -> Self {
+        Self {
+            client,
+            palette,
+            palette_index: 0,
+            added: 0,
+            selected: 0,
+            source: None,
+            message: None,
+        }
+    }
+
+    fn selected_id(&self) -> Option<NodeId> {
+        self.client.nodes().get(self.selected).map(|info| info.id)
+    }
+
+    fn connect_to_selected(&mut self, edge: Edge) -> Result<(), GraphError> {
+        if let (Some(from), Some(to)) = (self.source.take(), self.selected_id()) {
+            self.client.connect(from, to, edge)?;
+        }
+        Ok(())
+    }
+}
+
+impl UIComponent for GraphComponent {
+    fn dispatch(&mut self, event: InputEvent) {
+        let node_count = self.client.nodes().len();
+        let result = match event {
+            InputEvent::Up => {
+                self.selected = self.selected.saturating_sub(1);
+                Ok(())
+            }
+            InputEvent::Down => {
+                self.selected = (self.selected + 1).min(node_count - 1);
+                Ok(())
+            }
+            InputEvent::Unmapped(KeyCode::Char('p')) if !self.palette.is_empty() => {
+                self.palette_index = (self.palette_index + 1) % self.palette.len();
+                Ok(())
+            }
+            InputEvent::Unmapped(KeyCode::Char('a')) => {
+                match self.palette.get(self.palette_index) {
+                    Some((_, make)) => self.client.add(make(self.added)).map(|_| {
+                        self.added += 1;
+                        self.selected = node_count;
+                    }),
+                    None => Ok(()),
+                }
+            }
+            InputEvent::Unmapped(KeyCode::Char('x')) => match self.selected_id() {
+                Some(id) => self.client.remove(id).map(|_| {
+                    self.selected = self.selected.saturating_sub(1);
+                }),
+                None => Ok(()),
+            },
+            InputEvent::Unmapped(KeyCode::Char('c')) if self.source.is_none() => {
+                self.source = self.selected_id();
+                Ok(())
+            }
+            InputEvent::Unmapped(KeyCode::Char('c')) => self.connect_to_selected(Edge::Audio(1.0)),
+            InputEvent::Unmapped(KeyCode::Char('m')) => {
+                self.connect_to_selected(Edge::Modulation(100.0))
+            }
+            InputEvent::Unmapped(KeyCode::Char('u')) => {
+                match (self.source.take(), self.selected_id()) {
+                    (Some(from), Some(to)) => self.client.disconnect(from, to),
+                    _ => Ok(()),
+                }
+            }
+            InputEvent::Unmapped(KeyCode::Char('o')) => match self.selected_id() {
+                Some(id) => {
+                    let output = self.client.output();
+                    self.client.connect(id, output, Edge::Audio(1.0))
+                }
+                None => Ok(()),
+            },
+            _ => Ok(()),
+        };
+
+        self.message = result.err().map(|err| err.to_string());
+    }
+}
+
+impl RefWidget for GraphComponent {
+    fn render(&self, area: Rect, buf: &mut Buffer) {
+        let mut lines = Vec::new();
+        for (index, info) in self.client.nodes().iter().enumerate() {
+            let cursor = if index == self.selected { ">" } else { " " };
+            let source = if Some(info.id) == self.source {
+                "*"
+            } else {
+                " "
+            };
+            lines.push(format!("{}{}{} {:?}", cursor, source, info.id.0, info.kind));
+        }
+        for (from, to, edge) in self.client.edges() {
+            lines.push(format!("  {} -> {} {:?}", from.0, to.0, edge));
+        }
+        if let Some((name, _)) = self.palette.get(self.palette_index) {
+            lines.push(format!("add: {}", name));
+        }
+        if let Some(message) = &self.message {
+            lines.push(message.clone());
+        }
+
+        Clear.render(area, buf);
+        Paragraph::new(lines.join("\n"))
+            .block(Block::default().borders(Borders::ALL).title("Graph"))
+            .render(area, buf);
+    }
+}
+
+pub struct EnvelopeComponent {
+    pub client: Arc<Mutex<EnvelopeClient>>,
+    selected: usize,
+}
+
+const ENVELOPE_PARAMS: [&str; 6] = ["attack", "hold", "decay", "sustain", "release", "curve"];
+
+impl EnvelopeComponent {
+    pub fn new(client: Arc<Mutex<EnvelopeClient>>) -> Self {
+        Self {
+            client,
+            selected: 0,
+        }
+    }
+}
+
+fn envelope_param(config: &mut EnvelopeConfig, index: usize) -> (&mut f32, f32, f32, f32) {
+    // (value, step, min, max)
+    match index {
+        0 => (&mut config.attack, 0.01, 0.0, 10.0),
+        1 => (&mut config.hold, 0.01, 0.0, 10.0),
+        2 => (&mut config.decay, 0.05, 0.0, 10.0),
+        3 => (&mut config.sustain, 0.05, 0.0, 1.0),
+        4 => (&mut config.release, 0.05, 0.0, 10.0),
+        _ => (&mut config.curve, 0.5, -10.0, 10.0),
+    }
+}
+
+impl UIComponent for EnvelopeComponent {
+    fn dispatch(&mut self, event: InputEvent) {
+        let direction = match event {
+            InputEvent::Left => {
+                self.selected = self.selected.saturating_sub(1);
+                return;
+            }
+            InputEvent::Right => {
+                self.selected = (self.selected + 1).min(ENVELOPE_PARAMS.len() - 1);
+                return;
+            }
+            InputEvent::Up => 1.0,
+            InputEvent::Down => -1.0,
+            _ => return,
+        };
+
+        let selected = self.selected;
+        self.client.lock().unwrap().update(|config| {
+            let (value, step, min, max) = envelope_param(config, selected);
+            *value = (*value + step * direction).max(min).min(max);
+        });
+    }
+}
+
+impl RefWidget for EnvelopeComponent {
+    fn render(&self, area: Rect, buf: &mut Buffer) {
+        let mut config = self.client.lock().unwrap().get();
+        let lines: Vec<String> = ENVELOPE_PARAMS
+            .iter()
+            .enumerate()
+            .map(|(index, name)| {
+                let cursor = if index == self.selected { ">" } else { " " };
+                let (value, ..) = envelope_param(&mut config, index);
+                format!("{} {:<8}{:.2}", cursor, name, value)
+            })
+            .collect();
+
+        Clear.render(area, buf);
+        Paragraph::new(lines.join("\n"))
+            .block(Block::default().borders(Borders::ALL).title("Envelope"))
+            .render(area, buf);
+    }
+}
+
+enum PresetPrompt {
+    SaveAs,
+    Rename(String),
+}
+
+pub struct PresetComponent {
+    bank: PresetBank,
+    names: Vec<String>,
+    selected: usize,
+    prompt: Option<(PresetPrompt, String)>,
+    message: Option<String>,
+}
+
+impl PresetComponent {
+    pub fn new(bank: PresetBank) -> Self {
+        let mut component = Self {
+            bank,
+            names: Vec::new(),
+            selected: 0,
+            prompt: None,
+            message: None,
+        };
+        component.message = component.refresh().err().map(|err| err.to_string());
+        component
+    }
+
+    fn refresh(&mut self) -> Result<(), PresetError> {
+        self.names = self.bank.names()?;
+        self.selected = self.selected.min(self.names.len().saturating_sub(1));
+        Ok(())
+    }
+
+    fn select(&mut self, name: &str) {
+        if let Some(index) = self.names.iter().position(|n| n == name) {
+            self.selected = index;
+        }
+    }
+
+    fn selected_name(&self) -> Option<String> {
+        self.names.get(self.selected).cloned()
+    }
+
+    fn edit_prompt(&mut self, event: InputEvent) -> Result<(), PresetError> {
+        let (prompt, text) = match self.prompt.as_mut() {
+            Some(prompt) => prompt,
+            None => return Ok(()),
+        };
+
+        match event {
+            InputEvent::Enter => {
+                let name = text.trim().to_string();
+                match prompt {
+                    PresetPrompt::SaveAs => self.bank.save_as(&name)?,
+                    PresetPrompt::Rename(from) => self.bank.rename(from, &name)?,
+                }
+                self.prompt = None;
+                self.refresh()?;
+                self.select(&name);
+            }
+            InputEvent::Unmapped(KeyCode::Esc) => self.prompt = None,
+            InputEvent::Unmapped(KeyCode::Backspace) => {
+                text.pop();
+            }
+            event => text.extend(typed_char(event)),
+        }
+        Ok(())
+    }
+}
+
+impl UIComponent for PresetComponent {
+    fn dispatch(&mut self, event: InputEvent) {
+        let result = if self.prompt.is_some() {
+            self.edit_prompt(event)
+        } else {
+            match event {
+                InputEvent::Up => {
+                    self.selected = self.selected.saturating_sub(1);
+                    Ok(())
+                }
+                InputEvent::Down => {
+                    self.selected = (self.selected + 1).min(self.names.len().saturating_sub(1));
+                    Ok(())
+                }
+                InputEvent::Enter => match self.selected_name() {
+                    Some(name) => self.bank.load(&name),
+                    None => Ok(()),
+                },
+                InputEvent::Unmapped(KeyCode::Char('s')) => {
+                    self.prompt = Some((PresetPrompt::SaveAs, String::new()));
+                    Ok(())
+                }
+                InputEvent::Unmapped(KeyCode::Char('w')) => match self.selected_name() {
+                    Some(name) => self.bank.save(&name),
+                    None => Ok(()),
+                },
+                InputEvent::Replace => {
+                    if let Some(name) = self.selected_name() {
+                        self.prompt = Some((PresetPrompt::Rename(name.clone()), name));
+                    }
+                    Ok(())
+                }
+                InputEvent::Unmapped(KeyCode::Char('x')) => match self.selected_name() {
+                    Some(name) => self.bank.delete(&name).and_then(|_| self.refresh()),
+                    None => Ok(()),
+                },
+                _ => Ok(()),
+            }
+        };
+
+        self.message = result.err().map(|err| err.to_string());
+    }
+}
+
+impl RefWidget for PresetComponent {
+    fn render(&self, area: Rect, buf: &mut Buffer) {
+        let mut lines = Vec::new();
+        for (index, name) in self.names.iter().enumerate() {
+            let cursor = if index == self.selected { ">" } else { " " };
+            lines.push(format!("{} {}", cursor, name));
+        }
+        match &self.prompt {
+            Some((PresetPrompt::SaveAs, text)) => lines.push(format!("save as: {}_", text)),
+            Some((PresetPrompt::Rename(from), text)) => {
+                lines.push(format!("rename {}: {}_", from, text))
+            }
+            None => {}
+        }
+        if let Some(message) = &self.message {
+            lines.push(message.clone());
+        }
+
+        Clear.render(area, buf);
+        Paragraph::new(lines.join("\n"))
+            .block(Block::default().borders(Borders::ALL).title("Presets"))
+            .render(area, buf);
+    }
+}
+
+/// Play/pause (`p`), loop (`o`) and rewind (`b`) for a MIDI file.
+pub struct TransportComponent {
+    client: Arc<Mutex<TransportClient>>,
+    position: Arc<AtomicCell<f32>>,
+    length: f32,
+}
+
+impl TransportComponent {
+    pub fn new(
+        client: Arc<Mutex<TransportClient>>,
+        position: Arc<AtomicCell<f32>>,
+        length: f32,
+    ) -> Self {
+        Self {
+            client,
+            position,
+            length,
+        }
+    }
+}
+
+fn format_time(seconds: f32) -> String {
+    format!("{}:{:04.1}", (seconds / 60.0) as u32, seconds % 60.0)
+}
+
+impl UIComponent for TransportComponent {
+    fn dispatch(&mut self, event: InputEvent) {
+        let action = match event {
+            InputEvent::Unmapped(KeyCode::Char('p')) => TransportAction::TogglePlay,
+            InputEvent::Unmapped(KeyCode::Char('o')) => TransportAction::ToggleLoop,
+            InputEvent::Back => TransportAction::Rewind,
+            _ => return,
+        };
+        self.client.lock().unwrap().update(|_| action);
+    }
+}
+
+impl RefWidget for TransportComponent {
+    fn render(&self, area: Rect, buf: &mut Buffer) {
+        let config = self.client.lock().unwrap().get();
+        let lines = [
+            if config.playing { "playing" } else { "paused" }.to_string(),
+            format!(
+                "{} / {}",
+                format_time(self.position.load()),
+                format_time(self.length)
+            ),
+            format!("loop: {}", if config.looping { "on" } else { "off" }),
+        ];
+
+        Clear.render(area, buf);
+        Paragraph::new(lines.join("\n"))
+            .block(Block::default().borders(Borders::ALL).title("Transport"))
+            .render(area, buf);
+    }
+}
+
+/// Up/Down change the pulse width.
+pub struct PulseComponent {
+    pub client: Arc<Mutex<PulseClient>>,
+}
+
+impl UIComponent for PulseComponent {
+    fn dispatch(&mut self, event: InputEvent) {
+        let step = match event {
+            InputEvent::Up => 0.05,
+            InputEvent::Down => -0.05,
+            _ => return,
+        };
+        self.client
+            .lock()
+            .unwrap()
+            .update(|config| config.width = (config.width + step).clamp(0.05, 0.95));
+    }
+}
+
+impl RefWidget for PulseComponent {
+    fn render(&self, area: Rect, buf: &mut Buffer) {
+        let width = self.client.lock().unwrap().get().width;
+
+        Clear.render(area, buf);
+        Paragraph::new(format!("width {:.2}", width))
+            .block(Block::default().borders(Borders::ALL).title("Pulse"))
+            .render(area, buf);
+    }
+}
+
+pub struct WavetableComponent {
+    pub client: Arc<Mutex<WavetableClient>>,
+}
+
+impl UIComponent for WavetableComponent {
+    fn dispatch(&mut self, event: InputEvent) {
+        let step = match event {
+            InputEvent::Up => 0.05,
+            InputEvent::Down => -0.05,
+            _ => return,
+        };
+        self.client
+            .lock()
+            .unwrap()
+            .update(|config| config.position = (config.position + step).clamp(0.0, 1.0));
+    }
+}
+
+impl RefWidget for WavetableComponent {
+    fn render(&self, area: Rect, buf: &mut Buffer) {
+        let position = self.client.lock().unwrap().get().position;
+
+        Clear.render(area, buf);
+        Paragraph::new(format!("position {:.2}", position))
+            .block(Block::default().borders(Borders::ALL).title("Wavetable"))
+            .render(area, buf);
+    }
+}
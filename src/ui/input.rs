@@ -1,6 +1,16 @@
-use std::time::Duration;
+use std::{
+    collections::HashMap,
+    io::stdout,
+    time::{Duration, Instant},
+};
 
-use crossterm::event::{poll, read, Event, KeyCode, KeyEvent, KeyModifiers};
+use crossterm::{
+    event::{
+        Event, KeyCode, KeyEvent, KeyEventKind, KeyboardEnhancementFlags,
+        PushKeyboardEnhancementFlags,
+    },
+    execute,
+};
 
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub enum InputEvent {
@@ -13,6 +23,21 @@ pub enum InputEvent {
     Replace,
     SwitchMode,
     Unmapped(KeyCode),
+    Release(KeyCode),
+}
+
+/// Recovers the character behind an event, for components that take text.
+pub fn typed_char(event: InputEvent) -> Option<char> {
+    match event {
+        InputEvent::Left => Some('h'),
+        InputEvent::Right => Some('l'),
+        InputEvent::Down => Some('j'),
+        InputEvent::Up => Some('k'),
+        InputEvent::Replace => Some('r'),
+        InputEvent::Back => Some('b'),
+        InputEvent::Unmapped(KeyCode::Char(char)) => Some(char),
+        _ => None,
+    }
 }
 
 pub fn parse_input_event(event: crossterm::event::Event) -> Option<InputEvent> {
@@ -29,7 +54,139 @@ pub fn parse_input_event(event: crossterm::event::Event) -> Option<InputEvent> {
     };
 
     match event {
+        Event::Key(KeyEvent {
+            code,
+            kind: KeyEventKind::Release,
+            ..
+        }) => Some(InputEvent::Release(code)),
         Event::Key(key_event) => parse_from_key_code(key_event.code),
         _ => None,
     }
 }
+
+/// Asks the terminal to report key releases. Terminals that can't just
+/// ignore the request, so whether it worked only shows once a release
+/// arrives.
+pub fn request_key_releases() {
+    let flags = KeyboardEnhancementFlags::DISAMBIGUATE_ESCAPE_CODES
+        | KeyboardEnhancementFlags::REPORT_EVENT_TYPES;
+    let _ = execute!(stdout(), PushKeyboardEnhancementFlags(flags));
+}
+
+// Terminals start auto-repeating a held key after roughly half a second and
+// then repeat every few tens of milliseconds.
+const FIRST_REPEAT_TIMEOUT: Duration = Duration::from_millis(600);
+const REPEAT_TIMEOUT: Duration = Duration::from_millis(150);
+
+/// Turns terminal key events into input events, including releases of the
+/// keys `plays` picks out. Once the terminal has reported a release itself
+/// they are passed through; until then a key counts as released once its
+/// auto-repeat stops arriving.
+pub struct KeyTracker {
+    plays: fn(KeyCode) -> bool,
+    native_releases: bool,
+    deadlines: HashMap<KeyCode, Instant>,
+}
+
+impl KeyTracker {
+    pub fn new(plays: fn(KeyCode) -> bool) -> Self {
+        Self {
+            plays,
+            native_releases: false,
+            deadlines: HashMap::new(),
+        }
+    }
+
+    pub fn track(&mut self, event: Event, now: Instant) -> Option<InputEvent> {
+        if let Event::Key(key_event) = &event {
+            if key_event.kind == KeyEventKind::Release {
+                self.native_releases = true;
+                self.deadlines.clear();
+                if !(self.plays)(key_event.code) {
+                    return None;
+                }
+            } else if !self.native_releases && (self.plays)(key_event.code) {
+                let timeout = if self.deadlines.contains_key(&key_event.code) {
+                    REPEAT_TIMEOUT
+                } else {
+                    FIRST_REPEAT_TIMEOUT
+                };
+                self.deadlines.insert(key_event.code, now + timeout);
+            }
+        }
+
+        parse_input_event(event)
+    }
+
+    pub fn expire(&mut self, now: Instant) -> Vec<InputEvent> {
+        let expired: Vec<KeyCode> = self
+            .deadlines
+            .iter()
+            .filter(|(_, deadline)| **deadline <= now)
+            .map(|(code, _)| *code)
+            .collect();
+
+        expired
+            .into_iter()
+            .map(|code| {
+                self.deadlines.remove(&code);
+                InputEvent::Release(code)
+            })
+            .collect()
+    }
+
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.deadlines.values().min().copied()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crossterm::event::KeyModifiers;
+
+    fn key(char: char, kind: KeyEventKind) -> Event {
+        Event::Key(KeyEvent::new_with_kind(
+            KeyCode::Char(char),
+            KeyModifiers::NONE,
+            kind,
+        ))
+    }
+
+    fn plays(code: KeyCode) -> bool {
+        code == KeyCode::Char('z')
+    }
+
+    #[test]
+    fn releases_played_keys_when_repeats_stop() {
+        let mut keys = KeyTracker::new(plays);
+        let start = Instant::now();
+        keys.track(key('z', KeyEventKind::Press), start);
+        keys.track(key('j', KeyEventKind::Press), start);
+
+        assert!(keys.expire(start + REPEAT_TIMEOUT).is_empty());
+        keys.track(key('z', KeyEventKind::Press), start + REPEAT_TIMEOUT);
+        let later = start + REPEAT_TIMEOUT + REPEAT_TIMEOUT / 2;
+        assert!(keys.expire(later).is_empty());
+        assert_eq!(
+            keys.expire(later + REPEAT_TIMEOUT),
+            vec![InputEvent::Release(KeyCode::Char('z'))]
+        );
+        assert_eq!(keys.next_deadline(), None);
+    }
+
+    #[test]
+    fn passes_native_releases_through() {
+        let mut keys = KeyTracker::new(plays);
+        let start = Instant::now();
+        keys.track(key('z', KeyEventKind::Press), start);
+        assert_eq!(
+            keys.track(key('z', KeyEventKind::Release), start),
+            Some(InputEvent::Release(KeyCode::Char('z')))
+        );
+        assert_eq!(keys.track(key('j', KeyEventKind::Release), start), None);
+
+        keys.track(key('z', KeyEventKind::Press), start);
+        assert_eq!(keys.next_deadline(), None);
+    }
+}
@@ -1,9 +1,13 @@
 use std::{
     fmt::{write, Formatter},
     io::Result,
-    sync::mpsc::{Receiver, SendError, Sender},
+    sync::{
+        mpsc::{Receiver, SendError, Sender},
+        Arc, Mutex,
+    },
 };
 
+use crossterm::event::KeyCode;
 use tui::{
     buffer::Buffer,
     layout::{Constraint, Direction, Layout, Rect},
@@ -14,6 +18,7 @@ use tui::{
 use crate::{
     chain::Chain,
     combinators::{TwoChannelClient, TwoChannelConfig},
+    synth::StreamStatus,
 };
 
 use super::{
@@ -37,6 +42,7 @@ impl std::fmt::Display for Mode {
 pub struct UIModel<C: UIComponent> {
     pub keyboard_input: KeyboardInputComponent,
     pub component: C,
+    pub status: Arc<Mutex<StreamStatus>>,
     mode: Mode,
     location: UILocation,
 }
@@ -72,11 +78,16 @@ impl UILocation {
 }
 
 impl<C: UIComponent> UIModel<C> {
-    pub fn new(keyboard_input: KeyboardInputComponent, component: C) -> Self {
+    pub fn new(
+        keyboard_input: KeyboardInputComponent,
+        component: C,
+        status: Arc<Mutex<StreamStatus>>,
+    ) -> Self {
         Self {
             mode: Mode::Keyboard,
             keyboard_input,
             component,
+            status,
             location: UILocation::default(),
         }
     }
@@ -88,11 +99,43 @@ impl<C: UIComponent> UIModel<C> {
 
 impl<C: UIComponent> RefWidget for UIModel<C> {
     fn render(&self, area: Rect, buf: &mut Buffer) {
-        self.component.render(area, buf);
-        Paragraph::new(self.mode.to_string()).render(area, buf);
+        let rows = Layout::default()
+            .direction(Direction::Vertical)
+            .constraints(
+                [
+                    Constraint::Min(0),
+                    Constraint::Length(4),
+                    Constraint::Length(1),
+                ]
+                .as_ref(),
+            )
+            .split(area);
+
+        self.component.render(rows[0], buf);
+        Paragraph::new(self.mode.to_string()).render(rows[0], buf);
+        self.keyboard_input.render(rows[1], buf);
+        Paragraph::new(status_line(
+            &self.status.lock().unwrap(),
+            self.keyboard_input.active_voices(),
+        ))
+        .render(rows[2], buf);
     }
 }
 
+fn status_line(status: &StreamStatus, active_voices: usize) -> String {
+    let mut line = format!(
+        "voices: {}  xruns: {}  errors: {}  restarts: {}",
+        active_voices, status.xruns, status.errors, status.restarts
+    );
+    if status.paused {
+        line.push_str("  paused");
+    }
+    if let Some(err) = &status.last_error {
+        line.push_str(&format!("  last error: {}", err));
+    }
+    line
+}
+
 impl<C: UIComponent> UIComponent for UIModel<C> {
     fn dispatch(&mut self, event: InputEvent) {
         if let InputEvent::SwitchMode = event {
@@ -104,7 +147,19 @@ impl<C: UIComponent> UIComponent for UIModel<C> {
             return;
         }
 
+        // Releases always go to the keyboard so notes can't get stuck when the
+        // mode changes while a key is down.
+        if let InputEvent::Release(_) = event {
+            self.play_key(event);
+            return;
+        }
+
         if self.mode == Mode::Keyboard {
+            if let InputEvent::Unmapped(KeyCode::Esc) = event {
+                let mut status = self.status.lock().unwrap();
+                status.paused = !status.paused;
+                return;
+            }
             self.play_key(event)
         } else {
             self.component.dispatch(event)
@@ -1,11 +1,14 @@
-use std::{marker::PhantomData, ops::Add, sync::Arc, time::Instant};
+use std::{f32::consts::TAU, marker::PhantomData, ops::Add, sync::Arc, time::Instant};
 
 use crossbeam::atomic::AtomicCell;
+use serde::{Deserialize, Serialize};
 
 use crate::{
-    chain::{Chain, Effect, Voice},
+    chain::{Chain, Effect, ProcessContext, Voice},
     combinators::Mixer,
-    config::{ComposeConfig, Config, ConfigReceiver, HasConfig},
+    config::{ComposeConfig, ComposeConfigClient, Config, ConfigReceiver, HasConfig},
+    oscillators::Phase,
+    preset::{indexed, Persistent},
 };
 
 pub trait HasFreq {
@@ -13,6 +16,16 @@ pub trait HasFreq {
     fn get_freq(&self) -> f32;
 }
 
+impl<H: HasFreq + ?Sized> HasFreq for Box<H> {
+    fn set_freq(&mut self, hz: f32) {
+        (**self).set_freq(hz)
+    }
+
+    fn get_freq(&self) -> f32 {
+        (**self).get_freq()
+    }
+}
+
 pub trait Waveform<Signal>: HasFreq + Voice<Signal> {}
 impl<S, T: HasFreq + Voice<S>> Waveform<S> for T {}
 
@@ -33,8 +46,7 @@ impl HasFreq for SineConfig {
 
 pub struct Sine<Signal> {
     pub config: Config<SineConfig>,
-    hz: f32,
-    clock: f32,
+    phase: Phase,
     _phantom: PhantomData<Signal>,
 }
 
@@ -42,50 +54,41 @@ impl<Signal> Sine<Signal> {
     pub fn new(hz: f32) -> Self {
         Sine {
             config: Config::new(SineConfig { hz }),
-            hz,
-            clock: 0.0,
+            phase: Phase::new(hz),
             _phantom: PhantomData {},
         }
     }
-
-    fn try_update_hz(&mut self) {
-        let new_hz = self.config.config.hz;
-
-        if new_hz != self.hz {
-            let signal = self.get_output();
-            let limit = 0.0001;
-            if signal.abs() < limit {
-                self.hz = new_hz;
-            }
-        }
-    }
-
-    fn get_output(&self) -> f32 {
-        ((self.clock * self.hz) - 1.0).sin()
-    }
 }
 
 impl<S> ConfigReceiver for Sine<S> {
     fn try_update_configs(&mut self) {
+        let hz = self.config.config.hz;
         self.config.try_update();
+        // Only a new config overrides the frequency, so one set directly,
+        // e.g. by a keyboard or FM, sticks.
+        if self.config.config.hz != hz {
+            self.phase.hz = self.config.config.hz;
+        }
     }
 }
 
 impl Voice<f32> for Sine<f32> {
     fn generate(&mut self) -> f32 {
-        self.try_update_hz();
-        self.clock += 1.0 / 44100.0;
-        self.get_output()
+        (self.phase.turn() * TAU).sin()
+    }
+
+    fn set_context(&mut self, context: ProcessContext) {
+        self.phase.sample_rate = context.sample_rate;
     }
 }
 
 impl<S> HasFreq for Sine<S> {
     fn set_freq(&mut self, hz: f32) {
-        self.hz = hz;
+        self.phase.hz = hz;
     }
 
     fn get_freq(&self) -> f32 {
-        self.hz
+        self.phase.hz
     }
 }
 
@@ -105,10 +108,44 @@ impl<S, V: Voice<S>, E: Effect<S>> Chained<S, V, E> {
     }
 }
 
-impl<V: Voice<Signal>, E: Effect<Signal>, Signal> Voice<Signal> for Chained<Signal, V, E> {
+impl<V: Voice<Signal>, E: Effect<Signal>, Signal: Copy> Voice<Signal> for Chained<Signal, V, E> {
     fn generate(&mut self) -> Signal {
         self.effect.process(self.voice.generate())
     }
+
+    fn generate_block(&mut self, block: &mut [Signal]) {
+        self.voice.generate_block(block);
+        self.effect.process_block(block);
+    }
+
+    fn set_context(&mut self, context: ProcessContext) {
+        self.voice.set_context(context);
+        self.effect.set_context(context);
+    }
+
+    fn note_on(&mut self, velocity: f32) {
+        self.voice.note_on(velocity);
+        self.effect.note_on(velocity);
+    }
+
+    fn note_off(&mut self) {
+        self.voice.note_off();
+        self.effect.note_off();
+    }
+
+    fn is_releasing(&self) -> bool {
+        self.voice.is_releasing() || self.effect.is_releasing()
+    }
+}
+
+impl<S, V: Voice<S> + HasFreq, E: Effect<S>> HasFreq for Chained<S, V, E> {
+    fn set_freq(&mut self, hz: f32) {
+        self.voice.set_freq(hz);
+    }
+
+    fn get_freq(&self) -> f32 {
+        self.voice.get_freq()
+    }
 }
 
 impl<S, V: Voice<S> + ConfigReceiver, E: Effect<S> + ConfigReceiver> ConfigReceiver
@@ -120,6 +157,12 @@ impl<S, V: Voice<S> + ConfigReceiver, E: Effect<S> + ConfigReceiver> ConfigRecei
     }
 }
 
+pub type AdditiveClient = ComposeConfigClient<
+    AdditiveConfig,
+    AdditiveAction,
+    fn(AdditiveConfig, AdditiveAction) -> AdditiveConfig,
+>;
+
 pub struct Additive<V: Waveform<f32>> {
     pub config: ComposeConfig<
         AdditiveConfig,
@@ -131,6 +174,11 @@ pub struct Additive<V: Waveform<f32>> {
 
 impl Additive<Sine<f32>> {
     pub fn new(fundamental: f32, overtones: Vec<f32>) -> Self {
+        let volumes = vec![0.5; overtones.len() + 1];
+        Self::with_volumes(fundamental, overtones, volumes)
+    }
+
+    pub fn with_volumes(fundamental: f32, overtones: Vec<f32>, volumes: Vec<f32>) -> Self {
         let voices = (0..overtones.len() + 1)
             .map(|_i| Sine::new(fundamental))
             .collect();
@@ -143,15 +191,35 @@ impl Additive<Sine<f32>> {
                 },
                 reduce_additive_action,
             ),
-            mixer: Mixer::new(voices),
+            mixer: Mixer::with_channels(voices, volumes),
         }
     }
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct AdditiveConfig {
-    fundamental: f32,
-    overtones: Vec<f32>,
+    pub fundamental: f32,
+    pub overtones: Vec<f32>,
+}
+
+impl Persistent for AdditiveConfig {
+    fn restore(&self, saved: Self) -> Result<Self, String> {
+        if saved.overtones.len() != self.overtones.len() {
+            return Err(format!(
+                "expected {} overtones, found {}",
+                self.overtones.len(),
+                saved.overtones.len()
+            ));
+        }
+        Ok(saved)
+    }
+
+    fn parameter(&mut self, path: &str) -> Option<&mut f32> {
+        match path {
+            "fundamental" => Some(&mut self.fundamental),
+            _ => indexed(&mut self.overtones, path, "overtones"),
+        }
+    }
 }
 
 #[derive(Clone, Copy, PartialEq)]
@@ -163,6 +231,7 @@ fn reduce_additive_action(config: AdditiveConfig, action: AdditiveAction) -> Add
 
 impl<V: Waveform<f32>> ConfigReceiver for Additive<V> {
     fn try_update_configs(&mut self) {
+        self.config.try_update();
         self.mixer.try_update_configs()
     }
 }
@@ -177,8 +246,8 @@ impl<V: Waveform<f32>> HasFreq for Additive<V> {
     }
 }
 
-impl<V: Waveform<f32>> Voice<f32> for Additive<V> {
-    fn generate(&mut self) -> f32 {
+impl<V: Waveform<f32>> Additive<V> {
+    fn update_freqs(&mut self) {
         for (sine, multiple) in self
             .mixer
             .voices
@@ -187,7 +256,61 @@ impl<V: Waveform<f32>> Voice<f32> for Additive<V> {
         {
             sine.set_freq(self.config.get().fundamental * (*multiple))
         }
+    }
+}
 
+impl<V: Waveform<f32>> Voice<f32> for Additive<V> {
+    fn generate(&mut self) -> f32 {
+        self.update_freqs();
         self.mixer.generate()
     }
+
+    fn generate_block(&mut self, block: &mut [f32]) {
+        self.update_freqs();
+        self.mixer.generate_block(block);
+    }
+
+    fn set_context(&mut self, context: ProcessContext) {
+        self.mixer.set_context(context);
+    }
+
+    fn note_on(&mut self, velocity: f32) {
+        self.mixer.note_on(velocity);
+    }
+
+    fn note_off(&mut self) {
+        self.mixer.note_off();
+    }
+
+    fn is_releasing(&self) -> bool {
+        self.mixer.is_releasing()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sine_changes_frequency_on_the_next_sample() {
+        let mut sine = Sine::<f32>::new(1.0);
+        sine.set_context(ProcessContext {
+            sample_rate: 8.0,
+            ..ProcessContext::default()
+        });
+        assert_eq!(sine.generate(), 0.0);
+
+        // Twice as fast from here, so a quarter cycle further along each
+        // sample, with no jump where it changed.
+        sine.set_freq(2.0);
+        let samples: Vec<f32> = (0..3).map(|_| sine.generate()).collect();
+        let expected = [
+            (TAU / 8.0).sin(),
+            (TAU * 3.0 / 8.0).sin(),
+            (TAU * 5.0 / 8.0).sin(),
+        ];
+        for (sample, expected) in samples.iter().zip(expected.iter()) {
+            assert!((sample - expected).abs() < 1e-6);
+        }
+    }
 }
@@ -0,0 +1,370 @@
+use std::{fmt, path::Path, sync::Arc};
+
+use num::complex::Complex32;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    chain::{ProcessContext, Voice},
+    combinators::MixerConfig,
+    config::{Config, ConfigClient, ConfigReceiver},
+    offline::read_wav,
+    oscillators::Phase,
+    preset::Persistent,
+    voices::{AdditiveConfig, HasFreq},
+};
+
+/// Samples per frame, as most wavetable synths write them.
+pub const FRAME_SIZE: usize = 2048;
+/// One level per octave, from every harmonic a frame can hold down to just
+/// the fundamental.
+const LEVELS: usize = 11;
+
+#[derive(Debug)]
+pub enum WavetableError {
+    Wav(hound::Error),
+    Empty,
+}
+
+impl fmt::Display for WavetableError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WavetableError::Wav(err) => write!(f, "{}", err),
+            WavetableError::Empty => write!(f, "no complete {}-sample frame", FRAME_SIZE),
+        }
+    }
+}
+
+impl std::error::Error for WavetableError {}
+
+/// Single-cycle frames, each kept at every level of band limiting so no
+/// harmonic plays above Nyquist. Shared between the copies of a voice.
+pub struct Table {
+    // Indexed by frame, then level.
+    frames: Vec<Vec<Vec<f32>>>,
+}
+
+impl Table {
+    pub fn from_frames(frames: Vec<Vec<f32>>) -> Result<Self, WavetableError> {
+        let frames: Vec<_> = frames
+            .into_iter()
+            .filter(|frame| frame.len() == FRAME_SIZE)
+            .map(|frame| band_limit(&frame))
+            .collect();
+        if frames.is_empty() {
+            return Err(WavetableError::Empty);
+        }
+        Ok(Self { frames })
+    }
+
+    /// Reads consecutive frames from the first channel of a WAV file; a
+    /// partial frame at the end is dropped.
+    pub fn load_wav<P: AsRef<Path>>(path: P) -> Result<Self, WavetableError> {
+        let (spec, samples) = read_wav(path).map_err(WavetableError::Wav)?;
+
+        let channel: Vec<f32> = samples
+            .into_iter()
+            .step_by(spec.channels as usize)
+            .collect();
+        Self::from_frames(
+            channel
+                .chunks(FRAME_SIZE)
+                .map(|frame| frame.to_vec())
+                .collect(),
+        )
+    }
+
+    /// One frame with the additive voice's partials, each rounded to the
+    /// nearest harmonic since a single cycle can't hold anything else.
+    pub fn from_additive(
+        additive: &AdditiveConfig,
+        mixer: &MixerConfig,
+    ) -> Result<Self, WavetableError> {
+        let mut frame = vec![0.0; FRAME_SIZE];
+        let multiples = std::iter::once(1.0).chain(additive.overtones.iter().copied());
+        for (multiple, volume) in multiples.zip(mixer.channels.iter()) {
+            let harmonic = multiple.round() as usize;
+            if harmonic == 0 || harmonic >= FRAME_SIZE / 2 {
+                continue;
+            }
+            for (i, sample) in frame.iter_mut().enumerate() {
+                let phase = (i * harmonic % FRAME_SIZE) as f32 / FRAME_SIZE as f32;
+                *sample += volume * (phase * std::f32::consts::TAU).sin();
+            }
+        }
+
+        let peak = frame
+            .iter()
+            .fold(0f32, |peak, sample| peak.max(sample.abs()));
+        if peak > 0.0 {
+            frame.iter_mut().for_each(|sample| *sample /= peak);
+        }
+        Self::from_frames(vec![frame])
+    }
+}
+
+// Level n keeps harmonics up to `(FRAME_SIZE / 2) >> n`, and drops DC.
+fn band_limit(frame: &[f32]) -> Vec<Vec<f32>> {
+    let mut spectrum: Vec<Complex32> = frame.iter().map(|&x| Complex32::new(x, 0.0)).collect();
+    fft(&mut spectrum, false);
+
+    (0..LEVELS)
+        .map(|level| {
+            let highest = (FRAME_SIZE / 2) >> level;
+            let mut bins: Vec<Complex32> = spectrum
+                .iter()
+                .enumerate()
+                .map(|(bin, &value)| {
+                    let harmonic = bin.min(FRAME_SIZE - bin);
+                    if harmonic == 0 || harmonic > highest {
+                        Complex32::new(0.0, 0.0)
+                    } else {
+                        value
+                    }
+                })
+                .collect();
+            fft(&mut bins, true);
+            bins.iter().map(|bin| bin.re / FRAME_SIZE as f32).collect()
+        })
+        .collect()
+}
+
+// An in-place radix-2 FFT, unscaled either way.
+fn fft(buffer: &mut [Complex32], inverse: bool) {
+    let size = buffer.len();
+    let mut j = 0;
+    for i in 1..size {
+        let mut bit = size >> 1;
+        while j & bit != 0 {
+            j ^= bit;
+            bit >>= 1;
+        }
+        j |= bit;
+        if i < j {
+            buffer.swap(i, j);
+        }
+    }
+
+    let sign = if inverse { 1.0 } else { -1.0 };
+    let mut length = 2;
+    while length <= size {
+        let step = Complex32::from_polar(1.0, sign * std::f32::consts::TAU / length as f32);
+        for start in (0..size).step_by(length) {
+            let mut twiddle = Complex32::new(1.0, 0.0);
+            for k in 0..length / 2 {
+                let even = buffer[start + k];
+                let odd = buffer[start + k + length / 2] * twiddle;
+                buffer[start + k] = even + odd;
+                buffer[start + k + length / 2] = even - odd;
+                twiddle *= step;
+            }
+        }
+        length <<= 1;
+    }
+}
+
+/// `position` morphs through the table's frames, from 0 for the first to 1
+/// for the last.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct WavetableConfig {
+    pub position: f32,
+}
+
+impl Persistent for WavetableConfig {
+    fn parameter(&mut self, path: &str) -> Option<&mut f32> {
+        match path {
+            "position" => Some(&mut self.position),
+            _ => None,
+        }
+    }
+}
+
+pub type WavetableClient = ConfigClient<WavetableConfig>;
+
+pub struct Wavetable {
+    pub config: Config<WavetableConfig>,
+    table: Arc<Table>,
+    phase: Phase,
+}
+
+impl Wavetable {
+    pub fn new(table: Arc<Table>, hz: f32, position: f32) -> Self {
+        Self {
+            config: Config::new(WavetableConfig { position }),
+            table,
+            phase: Phase::new(hz),
+        }
+    }
+
+    // The most harmonics that fit under Nyquist at the current frequency.
+    fn level(&self) -> usize {
+        let room = 0.5 * self.phase.sample_rate / self.phase.hz.abs();
+        let mut level = 0;
+        while level + 1 < LEVELS && ((FRAME_SIZE / 2) >> level) as f32 > room {
+            level += 1;
+        }
+        level
+    }
+}
+
+fn lookup(table: &[f32], phase: f32) -> f32 {
+    let index = phase * FRAME_SIZE as f32;
+    let i = (index as usize).min(FRAME_SIZE - 1);
+    let fraction = index - i as f32;
+    let (a, b) = (table[i], table[(i + 1) % FRAME_SIZE]);
+    a + (b - a) * fraction
+}
+
+impl ConfigReceiver for Wavetable {
+    fn try_update_configs(&mut self) {
+        self.config.try_update();
+    }
+}
+
+impl HasFreq for Wavetable {
+    fn set_freq(&mut self, hz: f32) {
+        self.phase.hz = hz;
+    }
+
+    fn get_freq(&self) -> f32 {
+        self.phase.hz
+    }
+}
+
+impl Voice<f32> for Wavetable {
+    fn generate(&mut self) -> f32 {
+        let level = self.level();
+        let phase = self.phase.turn();
+
+        let last = self.table.frames.len() - 1;
+        let position = self.config.config.position.clamp(0.0, 1.0) * last as f32;
+        let first = (position as usize).min(last);
+        let fraction = position - first as f32;
+
+        let a = lookup(&self.table.frames[first][level], phase);
+        if first == last {
+            return a;
+        }
+        let b = lookup(&self.table.frames[first + 1][level], phase);
+        a + (b - a) * fraction
+    }
+
+    fn set_context(&mut self, context: ProcessContext) {
+        self.phase.sample_rate = context.sample_rate;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        offline::{write_wav, WavFormat},
+        stereo::Stereo,
+    };
+
+    fn harmonic(harmonic: usize, volume: f32) -> Vec<f32> {
+        (0..FRAME_SIZE)
+            .map(|i| {
+                let phase = (i * harmonic % FRAME_SIZE) as f32 / FRAME_SIZE as f32;
+                volume * (phase * std::f32::consts::TAU).sin()
+            })
+            .collect()
+    }
+
+    fn assert_close(a: &[f32], b: &[f32]) {
+        for (a, b) in a.iter().zip(b.iter()) {
+            assert!((a - b).abs() < 1e-3, "{} != {}", a, b);
+        }
+    }
+
+    #[test]
+    fn picks_the_level_that_fits_under_nyquist() {
+        let table = Arc::new(Table::from_frames(vec![harmonic(1, 1.0)]).unwrap());
+        let level_at = |hz| Wavetable::new(Arc::clone(&table), hz, 0.0).level();
+
+        // At 44.1kHz a 20Hz note has room for every harmonic in the frame,
+        // and 440Hz for 50, which level 5 brings down to 32.
+        assert_eq!(level_at(20.0), 0);
+        assert_eq!(level_at(440.0), 5);
+        assert_eq!(level_at(-440.0), 5);
+        assert_eq!(level_at(20000.0), LEVELS - 1);
+    }
+
+    #[test]
+    fn levels_drop_harmonics_above_their_limit() {
+        let mut frame = harmonic(1, 1.0);
+        for (sample, high) in frame.iter_mut().zip(harmonic(100, 0.5)) {
+            *sample += high + 0.25;
+        }
+        let table = Table::from_frames(vec![frame.clone()]).unwrap();
+
+        // Level 3 keeps harmonics up to 128 and level 4 up to 64; both lose DC.
+        let without_dc: Vec<f32> = frame.iter().map(|sample| sample - 0.25).collect();
+        assert_close(&table.frames[0][3], &without_dc);
+        assert_close(&table.frames[0][4], &harmonic(1, 1.0));
+    }
+
+    #[test]
+    fn morphs_between_frames() {
+        let table =
+            Arc::new(Table::from_frames(vec![harmonic(1, 1.0), harmonic(1, -1.0)]).unwrap());
+        let quarter_cycle = |position| {
+            let mut wavetable = Wavetable::new(Arc::clone(&table), 1.0, position);
+            wavetable.set_context(ProcessContext {
+                sample_rate: 4.0,
+                ..ProcessContext::default()
+            });
+            wavetable.generate();
+            wavetable.generate()
+        };
+
+        assert!((quarter_cycle(0.0) - 1.0).abs() < 1e-3);
+        assert!(quarter_cycle(0.5).abs() < 1e-3);
+        assert!((quarter_cycle(1.0) + 1.0).abs() < 1e-3);
+        assert!((quarter_cycle(3.0) + 1.0).abs() < 1e-3);
+    }
+
+    #[test]
+    fn loads_whole_frames_from_the_first_channel() {
+        let path =
+            std::env::temp_dir().join(format!("rsynth-wavetable-{}.wav", std::process::id()));
+        let (first, second) = (harmonic(1, 0.5), harmonic(2, 0.5));
+        let frames: Vec<Stereo> = first
+            .iter()
+            .chain(second.iter())
+            .chain(first[..FRAME_SIZE / 2].iter())
+            .map(|&left| Stereo { left, right: 0.0 })
+            .collect();
+        write_wav(&path, &frames, 44100, WavFormat::Float32).unwrap();
+        let table = Table::load_wav(&path);
+        std::fs::remove_file(&path).unwrap();
+
+        let table = table.unwrap();
+        assert_eq!(table.frames.len(), 2);
+        assert_close(&table.frames[0][0], &first);
+        assert_close(&table.frames[1][0], &second);
+    }
+
+    #[test]
+    fn rounds_additive_partials_to_harmonics() {
+        let additive = AdditiveConfig {
+            fundamental: 440.0,
+            overtones: vec![2.1, 0.2, 3.0],
+        };
+        let mixer = MixerConfig {
+            channels: vec![1.0, 0.5, 1.0],
+        };
+        let table = Table::from_additive(&additive, &mixer).unwrap();
+
+        // The partial that rounds to 0 is skipped, and the one past the
+        // mixer's channels is silent.
+        let mut expected = harmonic(1, 1.0);
+        for (sample, second) in expected.iter_mut().zip(harmonic(2, 0.5)) {
+            *sample += second;
+        }
+        let peak = expected
+            .iter()
+            .fold(0f32, |peak, sample| peak.max(sample.abs()));
+        expected.iter_mut().for_each(|sample| *sample /= peak);
+        assert_close(&table.frames[0][0], &expected);
+    }
+}